@@ -0,0 +1,53 @@
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+use simple_error::SimpleError;
+use dns::{handle_query, parse_upstream, ForwardConfig, ForwardStrategy, Resolver, ResolverConfig, RootHints};
+
+/// Entrypoint of the server, binding to a UDP socket.
+/// A root hints file can be given as argument, replacing the built-in one.
+/// `--forward <address>` (repeatable) forwards the queries to upstream resolvers, tried
+/// following `--strategy sequential|round-robin|fastest`. Upstreams given as
+/// `tls://<address>#<name or sha256/pin>` are reached over DNS over TLS, and the ones given
+/// as `https://<address>/<path>#<name or sha256/pin>` over DNS over HTTPS.
+fn main() -> Result<(), SimpleError> {
+    let mut config = ResolverConfig::default();
+    let mut upstreams = Vec::new();
+    let mut strategy = ForwardStrategy::Sequential;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--forward" => upstreams.push(parse_upstream(&args.next().ok_or("Missing upstream address")?)?),
+            "--strategy" => strategy = args.next().ok_or("Missing forwarding strategy")?.parse()?,
+            path => config.root_hints = RootHints::from_file(path)?,
+        }
+    }
+    if !upstreams.is_empty() {
+        config.forward = Some(ForwardConfig::new(upstreams, strategy));
+    }
+
+    // Bind an UDP socket on port 2053
+    let socket = UdpSocket::bind("0.0.0.0:2053")
+        .expect("Error creating socket on port 2053");
+
+    // Refresh the root servers from the hints, unless forwarding. On failure the hints are
+    // used as they are until priming is tried again.
+    let forwarding = config.forward.is_some();
+    let resolver = Arc::new(Resolver::new(config));
+    if forwarding {
+        println!("Forwarding queries to the upstream resolvers");
+    } else if let Err(e) = resolver.prime() {
+        println!("Priming query failed, using root hints: {}", e);
+    }
+
+    // Popular records are refreshed in the background, before they expire
+    let prefetcher = resolver.clone();
+    thread::spawn(move || prefetcher.run_prefetcher());
+
+    loop {
+        // A query that cannot be handled must not stop the server
+        if let Err(e) = handle_query(&socket, &resolver) {
+            println!("Error handling query: {}", e);
+        }
+    }
+}
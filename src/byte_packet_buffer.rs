@@ -3,17 +3,43 @@
 use std::str;
 use simple_error::SimpleError;
 
+/// Size of a DNS message sent over UDP without EDNS
+pub const UDP_MESSAGE_SIZE: usize = 512;
+/// Size of a DNS message sent over UDP with EDNS, small enough to avoid IP fragmentation
+pub const EDNS_MESSAGE_SIZE: usize = 1232;
+/// Largest DNS message, as allowed by the two bytes length prefix of TCP
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
 /// Struct that represents a raw DNS packet
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
 }
 
+impl Default for BytePacketBuffer {
+    fn default() -> Self {
+        BytePacketBuffer::new()
+    }
+}
+
 impl BytePacketBuffer {
     /// Create a new buffer that holds the package content received
     pub fn new() -> BytePacketBuffer {
-        BytePacketBuffer { 
-            buf: [0; 512],
+        BytePacketBuffer::with_size(UDP_MESSAGE_SIZE)
+    }
+
+    /// Create a new buffer able to hold a message of `size` bytes
+    pub fn with_size(size: usize) -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: vec![0; size],
+            pos: 0,
+        }
+    }
+
+    /// Create a buffer holding a copy of a raw message
+    pub fn from_bytes(bytes: &[u8]) -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: bytes.to_vec(),
             pos: 0,
         }
     }
@@ -37,7 +63,7 @@ impl BytePacketBuffer {
 
     /// Read one byte and make one step forward
     pub fn read(&mut self) -> Result<u8, SimpleError> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             bail!("End of buffer")
         }
         let single_byte = self.buf[self.pos];
@@ -47,7 +73,7 @@ impl BytePacketBuffer {
 
     /// Get the byte at the current position
     pub fn get(&self, pos: usize) -> Result<u8, SimpleError> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             bail!("End of buffer")
         }
         Ok(self.buf[pos])
@@ -55,7 +81,7 @@ impl BytePacketBuffer {
 
     /// Get a range of byte starting at index start and of length len
     pub fn get_range(&self, start: usize, len: usize) -> Result<&[u8], SimpleError> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             bail!("End of buffer");
         }
         Ok(&self.buf[start..start + len])
@@ -63,7 +89,7 @@ impl BytePacketBuffer {
 
     /// Read two bytes and make two steps forward
     pub fn read_u16(&mut self) -> Result<u16, SimpleError> {
-        if self.pos >= 511 {
+        if self.pos + 2 > self.buf.len() {
             bail!("End of buffer");
         }
         let two_bytes = ((self.read()? as u16) << 8) ^ (self.read()? as u16);
@@ -72,7 +98,7 @@ impl BytePacketBuffer {
 
     /// Read four bytes and make four steps forward
     pub fn read_u32(&mut self) -> Result<u32, SimpleError> {
-        if self.pos >= 509 {
+        if self.pos + 4 > self.buf.len() {
             bail!("End of buffer");
         }
         let four_bytes = ((self.read_u16()? as u32) << 16) ^ (self.read_u16()? as u32);
@@ -139,7 +165,9 @@ impl BytePacketBuffer {
 
                 let buf_slice = self.get_range(shared_pos, len as usize)?;
                 // Transform &[u8] to &str and add it to outstr
-                outstr.push_str(&str::from_utf8(buf_slice).expect("bytes are not valid UTF-8").to_lowercase());
+                let label = str::from_utf8(buf_slice)
+                    .map_err(|e| SimpleError::with("Label is not valid UTF-8", e))?;
+                outstr.push_str(label);
                 delimiter = ".";
                 shared_pos += len as usize
             }
@@ -156,7 +184,7 @@ impl BytePacketBuffer {
 
     /// Write the next byte of the buffer
     pub fn write(&mut self, val: u8) -> Result<(), SimpleError> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             bail!("End of buffer")
         }
         self.buf[self.pos] = val;
@@ -180,9 +208,9 @@ impl BytePacketBuffer {
         Ok(())
     }
 
-    /// Write the query name in labeled form (domain)
+    /// Write the query name in labeled form (domain). The root domain is the empty name.
     pub fn write_qname(&mut self, qname: &str) -> Result<(), SimpleError> {
-        for label in qname.split(".") {
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
             let length = label.len();
             if length > 0x3f {
                 bail!("Single label exceeds 63 characters of length")
@@ -199,6 +227,9 @@ impl BytePacketBuffer {
 
     /// Write 1 byte at position pos
     fn set(&mut self, pos: usize, val: u8) -> Result<(), SimpleError> {
+        if pos >= self.buf.len() {
+            bail!("End of buffer")
+        }
         self.buf[pos] = val;
 
         Ok(())
@@ -0,0 +1,569 @@
+//! In-memory cache of the RRsets learned while resolving, honouring their TTL.
+//! Non-existent names and types are cached as well (RFC 2308).
+//! Cached data is ranked by credibility, following RFC 2181 §5.4.1, and keeps its
+//! DNSSEC signatures and validation status. Expired data is kept for a while, to be
+//! served when it cannot be refreshed (RFC 8767).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::{DnsRecord, RRset, RecordType, ResultCode, Security};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+/// Key of a cached RRset. The name is stored lowercased.
+pub struct CacheKey {
+    pub name: String,
+    pub rtype: RecordType,
+    pub class: u16,
+}
+
+impl CacheKey {
+    /// Key of an RRset of class IN, the only class the resolver looks up
+    pub fn new(name: &str, rtype: RecordType) -> CacheKey {
+        CacheKey::with_class(name, rtype, 1)
+    }
+
+    pub fn with_class(name: &str, rtype: RecordType, class: u16) -> CacheKey {
+        CacheKey {
+            name: name.to_ascii_lowercase(),
+            rtype,
+            class,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// Credibility of cached data, from the least to the most trustworthy (RFC 2181 §5.4.1)
+pub enum Trust {
+    /// Additional section, and authority section of a non-authoritative answer (glue and referrals).
+    /// Only used to resolve, never returned as an answer.
+    Additional,
+    /// Answer section of a non-authoritative answer
+    NonAuthAnswer,
+    /// Authority section of an authoritative answer
+    AuthAuthority,
+    /// Answer section of an authoritative answer
+    AuthAnswer,
+}
+
+/// RRset stored in the cache, along with the time it was stored and its credibility
+struct CacheEntry {
+    rrset: RRset,
+    /// RRSIG records covering the RRset
+    rrsigs: Vec<DnsRecord>,
+    inserted: Instant,
+    trust: Trust,
+    /// Validation status, if the RRset was validated
+    security: Option<Security>,
+    /// Number of times the RRset was returned as a fresh answer
+    hits: u32,
+}
+
+/// RRset returned by the cache, with its signatures and validation status
+pub struct CachedRRset {
+    pub rrset: RRset,
+    pub rrsigs: Vec<DnsRecord>,
+    pub security: Option<Security>,
+    /// TTL of the RRset when it was stored
+    pub original_ttl: u32,
+    /// Number of times the RRset was returned as a fresh answer, this time included
+    pub hits: u32,
+}
+
+/// Negative answer returned by the cache
+pub struct CachedNegative {
+    /// NXDOMAIN or NOERROR (NODATA)
+    pub rescode: ResultCode,
+    pub soa: DnsRecord,
+    /// NSEC, NSEC3 and RRSIG records proving the denial
+    pub proof: Vec<DnsRecord>,
+    pub security: Option<Security>,
+}
+
+impl CacheEntry {
+    /// TTL of the RRset, decremented by the time spent in the cache
+    fn remaining_ttl(&self) -> u32 {
+        let elapsed = self.inserted.elapsed().as_secs().min(u32::MAX as u64) as u32;
+        self.rrset.ttl().saturating_sub(elapsed)
+    }
+
+    /// Time at which the TTL runs out
+    fn expires_at(&self) -> Instant {
+        self.inserted + Duration::from_secs(self.rrset.ttl() as u64)
+    }
+}
+
+/// Negative answer stored in the cache, along with the SOA record that came with it
+struct NegativeEntry {
+    soa: DnsRecord,
+    proof: Vec<DnsRecord>,
+    ttl: u32,
+    inserted: Instant,
+    security: Option<Security>,
+}
+
+impl NegativeEntry {
+    /// TTL of the negative answer, decremented by the time spent in the cache
+    fn remaining_ttl(&self) -> u32 {
+        let elapsed = self.inserted.elapsed().as_secs().min(u32::MAX as u64) as u32;
+        self.ttl.saturating_sub(elapsed)
+    }
+
+    /// Time at which the TTL runs out
+    fn expires_at(&self) -> Instant {
+        self.inserted + Duration::from_secs(self.ttl as u64)
+    }
+}
+
+/// Cache of RRsets keyed by (name, type, class)
+pub struct Cache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Names known not to exist (NXDOMAIN), keyed by lowercased name
+    nxdomain: HashMap<String, NegativeEntry>,
+    /// Names known to exist without records of the given type (NODATA)
+    nodata: HashMap<CacheKey, NegativeEntry>,
+    /// Maximum number of RRsets kept
+    capacity: usize,
+    /// TTLs above this value are capped
+    max_ttl: u32,
+    /// TTLs of negative answers above this value are capped
+    max_negative_ttl: u32,
+    /// Time expired entries are kept, to be served as stale data
+    stale_window: Duration,
+}
+
+impl Cache {
+    pub fn new(capacity: usize, max_ttl: u32, max_negative_ttl: u32, stale_window: Duration) -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            nxdomain: HashMap::new(),
+            nodata: HashMap::new(),
+            capacity,
+            max_ttl,
+            max_negative_ttl,
+            stale_window,
+        }
+    }
+
+    /// Number of RRsets in the cache, stale ones included
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Store an RRset, replacing the one with the same key unless the cached one is more
+    /// credible and not expired yet. RRsets with a TTL of 0 are not cached.
+    pub fn insert(&mut self, rrset: RRset, trust: Trust) {
+        self.insert_signed(rrset, Vec::new(), trust, None);
+    }
+
+    /// Store an RRset along with its RRSIG records and its validation status
+    pub fn insert_signed(&mut self, mut rrset: RRset, rrsigs: Vec<DnsRecord>, trust: Trust, security: Option<Security>) {
+        if rrset.ttl() == 0 || rrset.records().is_empty() || self.capacity == 0 {
+            return;
+        }
+        rrset.set_ttl(rrset.ttl().min(self.max_ttl));
+
+        let key = CacheKey::with_class(&rrset.name, rrset.rtype, rrset.class());
+        match self.entries.get(&key) {
+            Some(entry) if entry.trust > trust && entry.remaining_ttl() > 0 => return,
+            Some(_) => {}
+            None if self.entries.len() >= self.capacity => self.make_room(),
+            None => {}
+        }
+        self.entries.insert(key, CacheEntry { rrset, rrsigs, inserted: Instant::now(), trust, security, hits: 0 });
+    }
+
+    /// Group records into RRsets and store them
+    pub fn insert_records(&mut self, records: &[DnsRecord], trust: Trust) {
+        for rrset in RRset::group(records) {
+            self.insert(rrset, trust);
+        }
+    }
+
+    /// Return a cached RRset, with its TTL decremented by the time spent in the cache
+    pub fn get(&self, name: &str, rtype: RecordType) -> Option<RRset> {
+        self.get_trusted(name, rtype, Trust::Additional, false).map(|cached| cached.rrset)
+    }
+
+    /// Return a cached RRset credible enough to answer a client, with its signatures.
+    /// TTLs are decremented by the time spent in the cache. The answer is counted as a hit,
+    /// to tell the popular RRsets.
+    pub fn get_answer(&mut self, name: &str, rtype: RecordType) -> Option<CachedRRset> {
+        if let Some(entry) = self.entries.get_mut(&CacheKey::new(name, rtype)) {
+            if entry.trust >= Trust::NonAuthAnswer && entry.remaining_ttl() > 0 {
+                entry.hits = entry.hits.saturating_add(1);
+            }
+        }
+        self.get_trusted(name, rtype, Trust::NonAuthAnswer, false)
+    }
+
+    /// Same as `get_answer`, returning as well an RRset expired within the stale window,
+    /// with a TTL of 0. Stale answers are not counted as hits.
+    pub fn get_stale_answer(&self, name: &str, rtype: RecordType) -> Option<CachedRRset> {
+        self.get_trusted(name, rtype, Trust::NonAuthAnswer, true)
+    }
+
+    /// Return a cached RRset at least as credible as `trust`, expired within the stale window if `stale`
+    fn get_trusted(&self, name: &str, rtype: RecordType, trust: Trust, stale: bool) -> Option<CachedRRset> {
+        let entry = self.entries.get(&CacheKey::new(name, rtype))?;
+        if entry.trust < trust {
+            return None;
+        }
+        let remaining = entry.remaining_ttl();
+        if remaining == 0 && !(stale && self.is_stale(entry.expires_at())) {
+            return None;
+        }
+        let mut rrset = entry.rrset.clone();
+        rrset.set_ttl(remaining);
+        let mut rrsigs = entry.rrsigs.clone();
+        rrsigs.iter_mut().for_each(|rrsig| rrsig.set_ttl(remaining));
+        Some(CachedRRset { rrset, rrsigs, security: entry.security, original_ttl: entry.rrset.ttl(), hits: entry.hits })
+    }
+
+    /// Store a negative answer: `rescode` NXDOMAIN means that `name` does not exist at all,
+    /// NOERROR that it has no record of type `rtype`. The TTL is the minimum of the SOA TTL
+    /// and of the SOA MINIMUM field (RFC 2308 §5). The NSEC, NSEC3 and RRSIG records proving
+    /// the denial are kept along with the validation status.
+    pub fn insert_negative(&mut self, name: &str, rtype: RecordType, rescode: ResultCode, soa: DnsRecord, proof: Vec<DnsRecord>, security: Option<Security>) {
+        let ttl = match &soa {
+            DnsRecord::SOA { minimum, ttl, .. } => (*ttl).min(*minimum).min(self.max_negative_ttl),
+            _ => return,
+        };
+        if ttl == 0 || self.capacity == 0 {
+            return;
+        }
+        if self.nxdomain.len() + self.nodata.len() >= self.capacity {
+            self.remove_expired();
+            if self.nxdomain.len() + self.nodata.len() >= self.capacity {
+                return;
+            }
+        }
+
+        let entry = NegativeEntry { soa, proof, ttl, inserted: Instant::now(), security };
+        match rescode {
+            ResultCode::NXDOMAIN => {
+                self.nxdomain.insert(name.to_ascii_lowercase(), entry);
+            }
+            ResultCode::NOERROR => {
+                self.nodata.insert(CacheKey::new(name, rtype), entry);
+            }
+            _ => {}
+        }
+    }
+
+    /// Return a cached negative answer for (`name`, `rtype`): the response code to use
+    /// (NXDOMAIN or NOERROR), the SOA record and the proof, their TTL decremented by the time
+    /// spent in the cache
+    pub fn get_negative(&self, name: &str, rtype: RecordType) -> Option<CachedNegative> {
+        self.negative(name, rtype, false)
+    }
+
+    /// Same as `get_negative`, returning as well a negative answer expired within the stale
+    /// window, with a TTL of 0
+    pub fn get_stale_negative(&self, name: &str, rtype: RecordType) -> Option<CachedNegative> {
+        self.negative(name, rtype, true)
+    }
+
+    /// Return a cached negative answer, expired within the stale window if `stale`
+    fn negative(&self, name: &str, rtype: RecordType, stale: bool) -> Option<CachedNegative> {
+        let usable = |entry: &NegativeEntry| entry.remaining_ttl() > 0 || (stale && self.is_stale(entry.expires_at()));
+        let (rescode, entry) = match self.nxdomain.get(&name.to_ascii_lowercase()) {
+            Some(entry) if usable(entry) => (ResultCode::NXDOMAIN, entry),
+            _ => (ResultCode::NOERROR, self.nodata.get(&CacheKey::new(name, rtype))?),
+        };
+        if !usable(entry) {
+            return None;
+        }
+        let remaining = entry.remaining_ttl();
+        let mut soa = entry.soa.clone();
+        soa.set_ttl(remaining);
+        let mut proof = entry.proof.clone();
+        proof.iter_mut().for_each(|record| record.set_ttl(remaining));
+        Some(CachedNegative { rescode, soa, proof, security: entry.security })
+    }
+
+    /// Find the closest enclosing zone of `qname` whose name servers and at least one of their
+    /// addresses are cached. Returns the zone along with the addresses of its name servers.
+    pub fn closest_delegation(&self, qname: &str) -> Option<(String, Vec<IpAddr>)> {
+        let mut zone = qname.to_ascii_lowercase();
+        loop {
+            if let Some(ns) = self.get(&zone, RecordType::NS) {
+                let addrs: Vec<IpAddr> = ns.records()
+                    .iter()
+                    .filter_map(|record| match record {
+                        DnsRecord::NS { host, .. } => Some(host),
+                        _ => None,
+                    })
+                    .flat_map(|host| self.addresses(host))
+                    .collect();
+                if !addrs.is_empty() {
+                    return Some((zone, addrs));
+                }
+            }
+
+            // Move up one label, the root being the empty name
+            if zone.is_empty() {
+                return None;
+            }
+            zone = match zone.split_once('.') {
+                Some((_, parent)) => parent.to_string(),
+                None => String::new(),
+            };
+        }
+    }
+
+    /// Cached A and AAAA addresses of a host
+    pub fn addresses(&self, host: &str) -> Vec<IpAddr> {
+        [RecordType::A, RecordType::AAAA]
+            .iter()
+            .filter_map(|rtype| self.get(host, *rtype))
+            .flat_map(RRset::into_records)
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+                DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether data expiring at `expires_at` is still within the stale window
+    fn is_stale(&self, expires_at: Instant) -> bool {
+        expires_at + self.stale_window > Instant::now()
+    }
+
+    /// Drop the entries expired for longer than the stale window
+    pub fn remove_expired(&mut self) {
+        let now = Instant::now();
+        let window = self.stale_window;
+        self.entries.retain(|_, entry| entry.expires_at() + window > now);
+        self.nxdomain.retain(|_, entry| entry.expires_at() + window > now);
+        self.nodata.retain(|_, entry| entry.expires_at() + window > now);
+    }
+
+    /// Free a slot: drop the entries past the stale window, or else the one which expired
+    /// first, stale ones going before the others
+    fn make_room(&mut self) {
+        self.remove_expired();
+        if self.entries.len() < self.capacity {
+            return;
+        }
+        let oldest = self.entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.expires_at())
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn rrset(ttl: u32) -> RRset {
+        let record = DnsRecord::A { domain: "example.com".to_string(), addr: Ipv4Addr::new(192, 0, 2, 1), ttl };
+        RRset::group(&[record]).remove(0)
+    }
+
+    /// Cache holding an A RRset of `ttl` seconds for example.com, stored `age` seconds ago
+    fn cache_with(ttl: u32, age: u64) -> Cache {
+        let mut cache = Cache::new(10, 86400, 3600, Duration::from_secs(60));
+        cache.insert(rrset(ttl), Trust::AuthAnswer);
+        let entry = cache.entries.get_mut(&CacheKey::new("example.com", RecordType::A)).unwrap();
+        entry.inserted -= Duration::from_secs(age);
+        cache
+    }
+
+    #[test]
+    fn ttl_is_decremented() {
+        let mut cache = cache_with(300, 100);
+        assert_eq!(cache.get_answer("EXAMPLE.com", RecordType::A).unwrap().rrset.ttl(), 200);
+    }
+
+    #[test]
+    fn expired_answers_are_served_stale_within_the_window() {
+        let mut cache = cache_with(300, 330);
+        assert!(cache.get_answer("example.com", RecordType::A).is_none());
+        let stale = cache.get_stale_answer("example.com", RecordType::A).unwrap();
+        assert_eq!(stale.rrset.ttl(), 0);
+        assert_eq!(stale.original_ttl, 300);
+
+        cache.remove_expired();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn answers_past_the_stale_window_are_dropped() {
+        let mut cache = cache_with(300, 370);
+        assert!(cache.get_stale_answer("example.com", RecordType::A).is_none());
+
+        cache.remove_expired();
+        assert!(cache.is_empty());
+    }
+
+    fn ns(domain: &str, host: &str) -> RRset {
+        RRset::group(&[DnsRecord::NS { domain: domain.to_string(), host: host.to_string(), ttl: 3600 }]).remove(0)
+    }
+
+    fn address(domain: &str, addr: Ipv4Addr) -> RRset {
+        RRset::group(&[DnsRecord::A { domain: domain.to_string(), addr, ttl: 3600 }]).remove(0)
+    }
+
+    #[test]
+    fn less_credible_data_does_not_replace_cached_data() {
+        let mut cache = Cache::new(10, 86400, 3600, Duration::from_secs(60));
+        cache.insert(address("example.com", Ipv4Addr::new(192, 0, 2, 1)), Trust::AuthAnswer);
+        cache.insert(address("example.com", Ipv4Addr::new(192, 0, 2, 2)), Trust::Additional);
+        assert_eq!(cache.addresses("example.com"), [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+
+        cache.insert(address("example.com", Ipv4Addr::new(192, 0, 2, 3)), Trust::AuthAnswer);
+        assert_eq!(cache.addresses("example.com"), [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 3))]);
+    }
+
+    #[test]
+    fn glue_is_not_an_answer() {
+        let mut cache = Cache::new(10, 86400, 3600, Duration::from_secs(60));
+        cache.insert(address("ns1.example.com", Ipv4Addr::new(192, 0, 2, 1)), Trust::Additional);
+        assert!(cache.get("ns1.example.com", RecordType::A).is_some());
+        assert!(cache.get_answer("ns1.example.com", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn ttls_are_capped_and_zero_ttls_not_cached() {
+        let mut cache = Cache::new(10, 600, 3600, Duration::from_secs(60));
+        cache.insert(rrset(3600), Trust::AuthAnswer);
+        assert_eq!(cache.get("example.com", RecordType::A).unwrap().ttl(), 600);
+
+        let mut cache = Cache::new(10, 600, 3600, Duration::from_secs(60));
+        cache.insert(rrset(0), Trust::AuthAnswer);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn closest_delegation_with_addresses() {
+        let mut cache = Cache::new(10, 86400, 3600, Duration::from_secs(60));
+        cache.insert(ns("com", "a.gtld-servers.net"), Trust::Additional);
+        cache.insert(address("a.gtld-servers.net", Ipv4Addr::new(192, 5, 6, 30)), Trust::Additional);
+        // The name servers of example.com have no known address
+        cache.insert(ns("example.com", "ns1.example.com"), Trust::Additional);
+
+        let (zone, addrs) = cache.closest_delegation("www.Example.com").unwrap();
+        assert_eq!(zone, "com");
+        assert_eq!(addrs, [IpAddr::V4(Ipv4Addr::new(192, 5, 6, 30))]);
+        assert!(cache.closest_delegation("www.example.org").is_none());
+    }
+
+    #[test]
+    fn full_cache_drops_the_entry_expiring_first() {
+        let mut cache = Cache::new(2, 86400, 3600, Duration::from_secs(60));
+        cache.insert(address("a.example.com", Ipv4Addr::new(192, 0, 2, 1)), Trust::AuthAnswer);
+        let mut short = address("b.example.com", Ipv4Addr::new(192, 0, 2, 2));
+        short.set_ttl(60);
+        cache.insert(short, Trust::AuthAnswer);
+        cache.insert(address("c.example.com", Ipv4Addr::new(192, 0, 2, 3)), Trust::AuthAnswer);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("b.example.com", RecordType::A).is_none());
+        assert!(cache.get("a.example.com", RecordType::A).is_some());
+    }
+
+    #[test]
+    fn rrsets_are_cached_under_their_class() {
+        let mut cache = Cache::new(10, 86400, 3600, Duration::from_secs(60));
+        let mut chaos = RRset::with_class("example.com".to_string(), RecordType::A, 3, 300);
+        chaos.push(rrset(300).into_records().remove(0));
+        cache.insert(chaos, Trust::AuthAnswer);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("example.com", RecordType::A).is_none());
+
+        cache.insert(rrset(300), Trust::AuthAnswer);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("example.com", RecordType::A).is_some());
+    }
+
+    #[test]
+    fn only_fresh_answers_are_counted() {
+        let mut cache = cache_with(300, 0);
+        assert_eq!(cache.get_answer("example.com", RecordType::A).unwrap().hits, 1);
+        assert_eq!(cache.get_answer("example.com", RecordType::A).unwrap().hits, 2);
+        // Neither stale lookups nor lookups of glue are counted
+        assert_eq!(cache.get_stale_answer("example.com", RecordType::A).unwrap().hits, 2);
+        assert!(cache.get("example.com", RecordType::A).is_some());
+        assert_eq!(cache.get_answer("example.com", RecordType::A).unwrap().hits, 3);
+
+        let mut cache = cache_with(300, 330);
+        assert!(cache.get_answer("example.com", RecordType::A).is_none());
+        assert_eq!(cache.get_stale_answer("example.com", RecordType::A).unwrap().hits, 0);
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum,
+            ttl,
+        }
+    }
+
+    #[test]
+    fn nxdomain_covers_every_type() {
+        let mut cache = Cache::new(10, 86400, 3600, Duration::from_secs(60));
+        cache.insert_negative("nothing.example.com", RecordType::A, ResultCode::NXDOMAIN, soa(3600, 300), Vec::new(), None);
+        for rtype in [RecordType::A, RecordType::MX] {
+            let negative = cache.get_negative("Nothing.example.com", rtype).unwrap();
+            assert_eq!(negative.rescode, ResultCode::NXDOMAIN);
+            assert_eq!(negative.soa.ttl(), 300);
+        }
+        assert!(cache.get_negative("example.com", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn nodata_covers_its_type_only() {
+        let mut cache = Cache::new(10, 86400, 3600, Duration::from_secs(60));
+        cache.insert_negative("www.example.com", RecordType::AAAA, ResultCode::NOERROR, soa(60, 300), Vec::new(), None);
+        let negative = cache.get_negative("www.example.com", RecordType::AAAA).unwrap();
+        assert_eq!(negative.rescode, ResultCode::NOERROR);
+        assert_eq!(negative.soa.ttl(), 60);
+        assert!(cache.get_negative("www.example.com", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn negative_ttl_is_capped() {
+        let mut cache = Cache::new(10, 86400, 900, Duration::from_secs(60));
+        cache.insert_negative("www.example.com", RecordType::A, ResultCode::NOERROR, soa(86400, 86400), Vec::new(), None);
+        assert_eq!(cache.get_negative("www.example.com", RecordType::A).unwrap().soa.ttl(), 900);
+
+        // Nothing is cached without a SOA record, or with a TTL of 0
+        let a = rrset(300).into_records().remove(0);
+        cache.insert_negative("a.example.com", RecordType::A, ResultCode::NXDOMAIN, a, Vec::new(), None);
+        cache.insert_negative("b.example.com", RecordType::A, ResultCode::NXDOMAIN, soa(3600, 0), Vec::new(), None);
+        assert!(cache.get_negative("a.example.com", RecordType::A).is_none());
+        assert!(cache.get_negative("b.example.com", RecordType::A).is_none());
+    }
+
+    #[test]
+    fn expired_negative_answers_are_served_stale_within_the_window() {
+        let mut cache = Cache::new(10, 86400, 3600, Duration::from_secs(60));
+        cache.insert_negative("nothing.example.com", RecordType::A, ResultCode::NXDOMAIN, soa(300, 300), Vec::new(), None);
+        cache.nxdomain.get_mut("nothing.example.com").unwrap().inserted -= Duration::from_secs(330);
+        assert!(cache.get_negative("nothing.example.com", RecordType::A).is_none());
+        assert_eq!(cache.get_stale_negative("nothing.example.com", RecordType::A).unwrap().soa.ttl(), 0);
+
+        cache.nxdomain.get_mut("nothing.example.com").unwrap().inserted -= Duration::from_secs(60);
+        assert!(cache.get_stale_negative("nothing.example.com", RecordType::A).is_none());
+        cache.remove_expired();
+        assert!(cache.nxdomain.is_empty());
+    }
+}
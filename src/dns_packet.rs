@@ -2,15 +2,27 @@
 mod dns_header;
 mod dns_questions;
 mod dns_record;
+mod dns_rrset;
+mod dns_view;
 
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 
 pub use dns_header::*;
 pub use dns_questions::*;
-use dns_record::*;
+pub use dns_record::*;
+pub use dns_rrset::*;
+pub use dns_view::*;
 use crate::BytePacketBuffer;
+use crate::random::choose;
 use simple_error::SimpleError;
 
+/// DO bit of the flags of the OPT pseudo-record
+pub const EDNS_DO_FLAG: u32 = 0x8000;
+/// Code of the Extended DNS Error option (RFC 8914)
+pub const EDNS_EDE_OPTION: u16 = 15;
+/// Extended DNS Error info-code of answers served from expired cache data (RFC 8914 §4.4)
+pub const EDE_STALE_ANSWER: u16 = 3;
+
 #[derive(Clone, Debug)]
 pub struct DnsPacket {
     pub header: DnsHeader,
@@ -20,6 +32,12 @@ pub struct DnsPacket {
     pub resources: Vec<DnsRecord>
 }
 
+impl Default for DnsPacket {
+    fn default() -> Self {
+        DnsPacket::new()
+    }
+}
+
 impl DnsPacket {
     pub fn new() -> DnsPacket {
         DnsPacket {
@@ -82,15 +100,92 @@ impl DnsPacket {
         Ok(())
     }
     
+    /// RRsets of the answer section
+    pub fn answer_rrsets(&self) -> Vec<RRset> {
+        RRset::group(&self.answers)
+    }
+
+    /// RRsets of the authority section
+    pub fn authority_rrsets(&self) -> Vec<RRset> {
+        RRset::group(&self.authorities)
+    }
+
+    /// RRsets of the additional section
+    pub fn resource_rrsets(&self) -> Vec<RRset> {
+        RRset::group(&self.resources)
+    }
+
+    /// Replace the answer section with the records of the given RRsets
+    pub fn set_answer_rrsets(&mut self, sets: Vec<RRset>) {
+        self.answers = RRset::flatten(sets);
+    }
+
+    /// Replace the authority section with the records of the given RRsets
+    pub fn set_authority_rrsets(&mut self, sets: Vec<RRset>) {
+        self.authorities = RRset::flatten(sets);
+    }
+
+    /// Replace the additional section with the records of the given RRsets
+    pub fn set_resource_rrsets(&mut self, sets: Vec<RRset>) {
+        self.resources = RRset::flatten(sets);
+    }
+
+    /// OPT pseudo-record of the additional section (RFC 6891), if any
+    pub fn edns(&self) -> Option<&DnsRecord> {
+        self.resources.iter().find(|record| record.record_type() == RecordType::OPT)
+    }
+
+    /// Replace the OPT pseudo-record, advertising `udp_size` and setting the DO bit
+    /// (RFC 3225) if `dnssec_ok`
+    pub fn set_edns(&mut self, udp_size: u16, dnssec_ok: bool, options: Vec<EdnsOption>) {
+        self.resources.retain(|record| record.record_type() != RecordType::OPT);
+        self.resources.push(DnsRecord::OPT {
+            domain: String::new(),
+            packet_len: udp_size,
+            flags: if dnssec_ok { EDNS_DO_FLAG } else { 0 },
+            options,
+        });
+    }
+
+    /// Whether the sender set the DO bit, asking for DNSSEC records
+    pub fn dnssec_ok(&self) -> bool {
+        matches!(self.edns(), Some(DnsRecord::OPT { flags, .. }) if flags & EDNS_DO_FLAG != 0)
+    }
+
+    /// Info-codes of the Extended DNS Errors of the OPT pseudo-record (RFC 8914)
+    pub fn extended_errors(&self) -> Vec<u16> {
+        match self.edns() {
+            Some(DnsRecord::OPT { options, .. }) => options
+                .iter()
+                .filter(|option| option.code == EDNS_EDE_OPTION && option.data.len() >= 2)
+                .map(|option| u16::from_be_bytes([option.data[0], option.data[1]]))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
     /// Pick a random A record from the answer, in case there are multiple IPs
     pub fn get_random_a(&self) -> Option<Ipv4Addr> {
-        self.answers
+        let addrs: Vec<Ipv4Addr> = self.answers
             .iter()
             .filter_map(|record| match record {
                 DnsRecord::A { addr, .. } => Some(*addr),
                 _ => None,
             })
-            .next()
+            .collect();
+        pick(&addrs)
+    }
+
+    /// Every address of the A and AAAA records of the answer
+    pub fn get_all_addresses(&self) -> Vec<IpAddr> {
+        self.answers
+            .iter()
+            .filter_map(|record| match record {
+                DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                _ => None,
+            })
+            .collect()
     }
 
     /// Helper function which returns an iterator over all name servers in
@@ -106,40 +201,155 @@ impl DnsPacket {
                 _ => None,
             })
             // Discard servers which aren't authoritative to our query
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+            .filter(move |(domain, _)| is_subdomain(qname, domain))
     }
 
     /// We'll use the fact that name servers often bundle the corresponding
     /// A records when replying to an NS query.
-    /// Function that returns the actual IP for a NS record if possible.
+    /// Function that returns the actual IP for a NS record if possible, picked at random.
     pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
         // Get an iterator over the nameservers in the authorities section
-        self.get_ns(qname)
-            // Look for a matching A record in the additional section.
-            // Since we just want the first valid record, we can just
-            // build a stream of matching records.
+        let addrs: Vec<Ipv4Addr> = self.get_ns(qname)
+            // Look for the matching A records in the additional section.
             .flat_map(|(_, host)| {
                 self.resources
                     .iter()
                     // Filter for A records where the domain match the host
                     // of the NS record that we are currently processing
                     .filter_map(move |record| match record {
-                        DnsRecord::A { domain, addr, .. } if domain == host => Some(addr),
+                        DnsRecord::A { domain, addr, .. } if domain.eq_ignore_ascii_case(host) => Some(addr),
                         _ => None,
                     })
             })
-            .map(|addr| *addr)
-            // Finally, pick the first valid entry
-            .next()
+            .copied()
+            .collect();
+        // Finally, pick one of the valid entries
+        pick(&addrs)
+    }
+
+    /// Every address (A or AAAA glue) found in the additional section for the name servers
+    /// of the authorities section
+    pub fn get_all_resolved_ns(&self, qname: &str) -> Vec<IpAddr> {
+        self.get_ns(qname)
+            .flat_map(|(_, host)| {
+                self.resources
+                    .iter()
+                    .filter_map(move |record| match record {
+                        DnsRecord::A { domain, addr, .. } if domain.eq_ignore_ascii_case(host) => Some(IpAddr::V4(*addr)),
+                        DnsRecord::AAAA { domain, addr, .. } if domain.eq_ignore_ascii_case(host) => Some(IpAddr::V6(*addr)),
+                        _ => None,
+                    })
+            })
+            .collect()
     }
 
     /// In case there is no A records in the additional section, we'll have to perform another
-    /// lookup in the midst. This method returns the host name of an appropriate name server.
+    /// lookup in the midst. This method returns the host name of a name server picked at random.
     pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
-        // Get an iterator over the nameservers in the authorities section
+        pick(&self.get_all_unresolved_ns(qname))
+    }
+
+    /// Host names of every name server of the authorities section
+    pub fn get_all_unresolved_ns<'a>(&'a self, qname: &'a str) -> Vec<&'a str> {
         self.get_ns(qname)
             .map(|(_, host)| host)
-            // Finally, pick the first valid entry
-            .next()
+            .collect()
+    }
+
+    /// Drop the records the servers of `zone` have no authority over, so that out-of-zone data
+    /// (such as glue for hosts of another zone) is never used nor cached: every record has to be
+    /// owned by `zone` or one of its descendants, OPT pseudo-records aside. Returns the number
+    /// of records dropped.
+    pub fn retain_in_bailiwick(&mut self, zone: &str) -> usize {
+        let before = self.answers.len() + self.authorities.len() + self.resources.len();
+        for section in [&mut self.answers, &mut self.authorities, &mut self.resources] {
+            section.retain(|record| record.record_type() == RecordType::OPT || is_subdomain(record.domain(), zone));
+        }
+        before - (self.answers.len() + self.authorities.len() + self.resources.len())
+    }
+
+    /// Zone the authorities section delegates `qname` to: the deepest owner of its NS records
+    pub fn get_referral_zone<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+        self.get_ns(qname)
+            .map(|(domain, _)| domain)
+            .max_by_key(|domain| domain.len())
+    }
+
+    /// Check whether the answer holds records of type `qtype` for `qname`, or any record for
+    /// `qname` when `qtype` is ANY
+    pub fn has_answer(&self, qname: &str, qtype: RecordType) -> bool {
+        self.answers.iter().any(|record| {
+            (qtype == RecordType::ANY || record.record_type() == qtype) && record.domain().eq_ignore_ascii_case(qname)
+        })
     }
-}
\ No newline at end of file
+
+    /// Look in the answer for an alias of `qname`: a CNAME owned by `qname`, or a DNAME owned by
+    /// one of its ancestors. Returns the records making up this step of the chain along with the
+    /// alias target. A CNAME is synthesized from the DNAME when the server did not include one.
+    pub fn get_alias(&self, qname: &str) -> Option<(Vec<DnsRecord>, String)> {
+        let mut records = Vec::new();
+
+        let dname = self.answers.iter().find_map(|record| match record {
+            DnsRecord::DNAME { domain, host, ttl } if is_proper_subdomain(qname, domain) => Some((record, host, *ttl, domain)),
+            _ => None,
+        });
+        if let Some((record, ..)) = dname {
+            records.push(record.clone());
+        }
+
+        let cname = self.answers.iter().find_map(|record| match record {
+            DnsRecord::CNAME { domain, host, .. } if domain.eq_ignore_ascii_case(qname) => Some((record, host)),
+            _ => None,
+        });
+        match (cname, dname) {
+            (Some((record, host)), _) => {
+                records.push(record.clone());
+                Some((records, host.clone()))
+            }
+            (None, Some((_, host, ttl, domain))) => {
+                let target = dname_substitution(qname, domain, host);
+                records.push(DnsRecord::CNAME {
+                    domain: qname.to_string(),
+                    host: target.clone(),
+                    ttl,
+                });
+                Some((records, target))
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+/// Pick an item at random, or the first one if no random number can be drawn
+fn pick<T: Copy>(items: &[T]) -> Option<T> {
+    match choose(items) {
+        Ok(item) => item.copied(),
+        Err(_) => items.first().copied(),
+    }
+}
+
+/// Replace the `owner` suffix of `name` by `target`, as a DNAME owned by `owner` does
+/// (RFC 6672 §2.2). `name` must be a proper subdomain of `owner`.
+pub(crate) fn dname_substitution(name: &str, owner: &str, target: &str) -> String {
+    let prefix = name[..name.len() - owner.len()].trim_end_matches('.');
+    if target.is_empty() {
+        prefix.to_string()
+    } else {
+        format!("{}.{}", prefix, target)
+    }
+}
+
+/// Check whether `name` is `ancestor` or one of its descendants, ignoring case
+pub(crate) fn is_subdomain(name: &str, ancestor: &str) -> bool {
+    name.eq_ignore_ascii_case(ancestor) || is_proper_subdomain(name, ancestor)
+}
+
+/// Check whether `name` is strictly below `ancestor`, ignoring case
+pub(crate) fn is_proper_subdomain(name: &str, ancestor: &str) -> bool {
+    if ancestor.is_empty() {
+        return !name.is_empty();
+    }
+    name.len() > ancestor.len() + 1
+        && name.as_bytes()[name.len() - ancestor.len() - 1] == b'.'
+        && name.as_bytes()[name.len() - ancestor.len()..].eq_ignore_ascii_case(ancestor.as_bytes())
+}
@@ -27,6 +27,12 @@ pub struct DnsHeader {
     pub resource_entries: u16,      // 16 bits
 }
 
+impl Default for DnsHeader {
+    fn default() -> Self {
+        DnsHeader::new()
+    }
+}
+
 impl DnsHeader {
     pub fn new() -> DnsHeader {
         DnsHeader {
@@ -55,35 +61,39 @@ impl DnsHeader {
         self.id = buffer.read_u16()?;
         
         let first_flags = buffer.read()?;
+        let second_flags = buffer.read()?;
+        self.read_flags(first_flags, second_flags);
+
+        self.questions = buffer.read_u16()?;
+        self.answers = buffer.read_u16()?;
+        self.authoritative_entries = buffer.read_u16()?;
+        self.resource_entries = buffer.read_u16()?;
+
+        Ok(())
+    }
+    /// Decode the two bytes of flags following the id
+    pub(crate) fn read_flags(&mut self, first_flags: u8, second_flags: u8) {
         self.response = (first_flags & 128) == 128;
         self.opcode = (first_flags & 120) >> 3;
         self.authoritative_answer = (first_flags & 4) == 4;
         self.truncated_message = (first_flags & 2) == 2;
         self.recursion_desired = (first_flags & 1) == 1;
-        
-        let second_flags = buffer.read()?;
+
         self.recursion_available = (second_flags & 128) == 128;
         self.z = (second_flags & 64) > 0;
         self.checking_disabled = (second_flags & 16) > 0;
         self.authed_data = (second_flags & 32) > 0;
         self.rescode = ResultCode::from_num(second_flags & 0x0F);
-
-        self.questions = buffer.read_u16()?;
-        self.answers = buffer.read_u16()?;
-        self.authoritative_entries = buffer.read_u16()?;
-        self.resource_entries = buffer.read_u16()?;
-
-        Ok(())
     }
     /// Transform DNS header object into bytes and write it into a BytePacketBuffer
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<(), SimpleError> {
         buffer.write_u16(self.id)?;
         let first_flags: u8 = (self.response as u8) << 7
-            | ((self.opcode as u8) << 3)
+            | (self.opcode << 3)
             | ((self.authoritative_answer as u8) << 2)
             | ((self.truncated_message as u8) << 1)
             | (self.recursion_desired as u8);
-        let second_flags: u8 = (self.rescode as u8)
+        let second_flags: u8 = self.rescode as u8
             | ((self.checking_disabled as u8) << 4)
             | ((self.authed_data as u8) << 5)
             | ((self.z as u8) << 6)
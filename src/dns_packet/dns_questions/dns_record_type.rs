@@ -1,13 +1,22 @@
 //! Represent the RecordType
-#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Copy, PartialOrd, Ord)]
 /// Enum to represent record types
 pub enum RecordType {
     UNKNOWN(u16),
     A, //1
     NS, //2
     CNAME, //5
+    SOA, //6
     MX, //15
     AAAA, //28
+    DNAME, //39
+    OPT, //41
+    DS, //43
+    RRSIG, //46
+    NSEC, //47
+    DNSKEY, //48
+    NSEC3, //50
+    ANY, //255
 }
 
 impl RecordType {
@@ -18,8 +27,17 @@ impl RecordType {
             RecordType::UNKNOWN(num) => num,
             RecordType::NS => 2,
             RecordType::CNAME => 5,
+            RecordType::SOA => 6,
             RecordType::MX => 15,
             RecordType::AAAA => 28,
+            RecordType::DNAME => 39,
+            RecordType::OPT => 41,
+            RecordType::DS => 43,
+            RecordType::RRSIG => 46,
+            RecordType::NSEC => 47,
+            RecordType::DNSKEY => 48,
+            RecordType::NSEC3 => 50,
+            RecordType::ANY => 255,
         }
     }
     /// Convert bytes into a RecordType
@@ -28,8 +46,17 @@ impl RecordType {
             1 => RecordType::A,
             2 => RecordType::NS,
             5 => RecordType::CNAME,
+            6 => RecordType::SOA,
             15 => RecordType::MX,
             28 => RecordType::AAAA,
+            39 => RecordType::DNAME,
+            41 => RecordType::OPT,
+            43 => RecordType::DS,
+            46 => RecordType::RRSIG,
+            47 => RecordType::NSEC,
+            48 => RecordType::DNSKEY,
+            50 => RecordType::NSEC3,
+            255 => RecordType::ANY,
             _ => RecordType::UNKNOWN(num),
 
         }
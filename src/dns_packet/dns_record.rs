@@ -1,8 +1,10 @@
 //! Represent the DNS record
+use std::cmp::Ordering;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 use crate::BytePacketBuffer;
 use super::dns_questions::RecordType;
+use super::EDNS_EDE_OPTION;
 use simple_error::SimpleError;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -13,6 +15,7 @@ pub enum DnsRecord {
         domain: String,
         qtype: u16,
         data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
@@ -30,6 +33,17 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        m_name: String,
+        r_name: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
     MX {
         domain: String,
         host: String,
@@ -40,7 +54,81 @@ pub enum DnsRecord {
         domain: String,
         addr: Ipv6Addr,
         ttl: u32,
-    } // 28
+    }, // 28
+    DNAME {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 39
+    OPT {
+        domain: String,
+        /// Largest UDP payload the sender can receive, carried in the class field
+        packet_len: u16,
+        /// Extended RCODE, version and flags, carried in the TTL field
+        flags: u32,
+        options: Vec<EdnsOption>,
+    }, // 41
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    }, // 43
+    RRSIG {
+        domain: String,
+        type_covered: RecordType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer_name: String,
+        signature: Vec<u8>,
+        ttl: u32,
+    }, // 46
+    NSEC {
+        domain: String,
+        next_domain: String,
+        types: Vec<RecordType>,
+        ttl: u32,
+    }, // 47
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    }, // 48
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        types: Vec<RecordType>,
+        ttl: u32,
+    }, // 50
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// Option carried in the RDATA of an OPT record (RFC 6891 §6.1.2)
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl EdnsOption {
+    /// Extended DNS Error option (RFC 8914 §2) carrying `info_code` and an explanation, which may be empty
+    pub fn extended_error(info_code: u16, text: &str) -> EdnsOption {
+        let mut data = info_code.to_be_bytes().to_vec();
+        data.extend_from_slice(text.as_bytes());
+        EdnsOption { code: EDNS_EDE_OPTION, data }
+    }
 }
 
 impl DnsRecord {
@@ -51,9 +139,10 @@ impl DnsRecord {
 
         let qtype_num = buffer.read_u16()?;
         let qtype = RecordType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
+        let rdata_end = buffer.pos() + data_len as usize;
 
         match qtype {
             RecordType::A => {
@@ -62,41 +151,69 @@ impl DnsRecord {
                     ((raw_addr >> 24) & 0xFF) as u8,
                     ((raw_addr >> 16) & 0xFF) as u8,
                     ((raw_addr >> 8) & 0xFF) as u8,
-                    ((raw_addr >> 0) & 0xFF) as u8,
+                    (raw_addr & 0xFF) as u8,
                 );
 
                 Ok(DnsRecord::A {
-                    domain: domain,
-                    addr: addr,
-                    ttl: ttl,
+                    domain,
+                    addr,
+                    ttl,
                 })
             }
-            RecordType::UNKNOWN(_) => {
+            RecordType::UNKNOWN(_) | RecordType::ANY => {
+                let data = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
                 buffer.steps(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
-                    domain: domain,
+                    domain,
                     qtype: qtype_num,
-                    data_len: data_len,
-                    ttl: ttl,
+                    data_len,
+                    data,
+                    ttl,
                 })
             }
             RecordType::NS => {
                 let mut host = String::new();
                 buffer.read_qname(&mut host)?;
                 Ok(DnsRecord::NS { 
-                    domain: domain, 
-                    host: host, 
-                    ttl: ttl,
+                    domain,
+                    host,
+                    ttl,
                 }) 
             }
             RecordType::CNAME => {
                 let mut cname = String::new();
                 buffer.read_qname(&mut cname)?;
                 Ok(DnsRecord::CNAME {
-                    domain: domain,
+                    domain,
                     host: cname,
-                    ttl: ttl,
+                    ttl,
+                })
+            }
+            RecordType::SOA => {
+                let mut m_name = String::new();
+                buffer.read_qname(&mut m_name)?;
+                let mut r_name = String::new();
+                buffer.read_qname(&mut r_name)?;
+                Ok(DnsRecord::SOA {
+                    domain,
+                    m_name,
+                    r_name,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                })
+            }
+            RecordType::DNAME => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+                Ok(DnsRecord::DNAME {
+                    domain,
+                    host,
+                    ttl,
                 })
             }
             RecordType::MX => {
@@ -104,10 +221,10 @@ impl DnsRecord {
                 let priority = buffer.read_u16()?;
                 buffer.read_qname(&mut host)?;
                 Ok(DnsRecord::MX { 
-                    domain: domain, 
-                    host: host, 
-                    priority: priority, 
-                    ttl: ttl, 
+                    domain,
+                    host,
+                    priority,
+                    ttl,
                 })
             }
             RecordType::AAAA => {
@@ -127,9 +244,111 @@ impl DnsRecord {
                 );
 
                 Ok(DnsRecord::AAAA {
-                    domain: domain,
-                    addr: addr,
-                    ttl: ttl,
+                    domain,
+                    addr,
+                    ttl,
+                })
+            }
+            RecordType::OPT => {
+                let mut options = Vec::new();
+                while buffer.pos() < rdata_end {
+                    let code = buffer.read_u16()?;
+                    let len = buffer.read_u16()? as usize;
+                    let data = buffer.get_range(buffer.pos(), len)?.to_vec();
+                    buffer.steps(len)?;
+                    options.push(EdnsOption { code, data });
+                }
+                Ok(DnsRecord::OPT {
+                    domain,
+                    packet_len: class,
+                    flags: ttl,
+                    options,
+                })
+            }
+            RecordType::DS => {
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read()?;
+                let digest_type = buffer.read()?;
+                let digest = read_until(buffer, rdata_end)?;
+                Ok(DnsRecord::DS {
+                    domain,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ttl,
+                })
+            }
+            RecordType::RRSIG => {
+                let type_covered = RecordType::from_num(buffer.read_u16()?);
+                let algorithm = buffer.read()?;
+                let labels = buffer.read()?;
+                let original_ttl = buffer.read_u32()?;
+                let expiration = buffer.read_u32()?;
+                let inception = buffer.read_u32()?;
+                let key_tag = buffer.read_u16()?;
+                let mut signer_name = String::new();
+                buffer.read_qname(&mut signer_name)?;
+                let signature = read_until(buffer, rdata_end)?;
+                Ok(DnsRecord::RRSIG {
+                    domain,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer_name,
+                    signature,
+                    ttl,
+                })
+            }
+            RecordType::NSEC => {
+                let mut next_domain = String::new();
+                buffer.read_qname(&mut next_domain)?;
+                let types = read_type_bitmaps(&read_until(buffer, rdata_end)?)?;
+                Ok(DnsRecord::NSEC {
+                    domain,
+                    next_domain,
+                    types,
+                    ttl,
+                })
+            }
+            RecordType::DNSKEY => {
+                let flags = buffer.read_u16()?;
+                let protocol = buffer.read()?;
+                let algorithm = buffer.read()?;
+                let public_key = read_until(buffer, rdata_end)?;
+                Ok(DnsRecord::DNSKEY {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                    ttl,
+                })
+            }
+            RecordType::NSEC3 => {
+                let hash_algorithm = buffer.read()?;
+                let flags = buffer.read()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read()? as usize;
+                let salt = buffer.get_range(buffer.pos(), salt_len)?.to_vec();
+                buffer.steps(salt_len)?;
+                let hash_len = buffer.read()? as usize;
+                let next_hashed = buffer.get_range(buffer.pos(), hash_len)?.to_vec();
+                buffer.steps(hash_len)?;
+                let types = read_type_bitmaps(&read_until(buffer, rdata_end)?)?;
+                Ok(DnsRecord::NSEC3 {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed,
+                    types,
+                    ttl,
                 })
             }
         }
@@ -150,13 +369,15 @@ impl DnsRecord {
                     buffer.write(byte)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                // buffer.write_qname(domain)?;
-                // buffer.write_u16(*qtype)?;
-                // buffer.write_u16(1)?;
-                // buffer.write_u32(*ttl)?;
-                // buffer.write_u16(*data_len)?;
-                print!("Skipping record: {:?}", self);
+            DnsRecord::UNKNOWN { domain, qtype, data, ttl, .. } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(*qtype)?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(*ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                for byte in data {
+                    buffer.write(*byte)?;
+                }
             }
             DnsRecord::AAAA { domain, addr, ttl } => {
                 buffer.write_qname(domain)?;
@@ -177,7 +398,33 @@ impl DnsRecord {
                 let start_position = buffer.pos();
                 buffer.write_qname(host)?;
                 let size: usize = buffer.pos() - start_position;
-                buffer.set_u16(start_position - 1, size as u16)?;
+                buffer.set_u16(start_position - 2, size as u16)?;
+            }
+            DnsRecord::SOA { domain, m_name, r_name, serial, refresh, retry, expire, minimum, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(RecordType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(*ttl)?;
+                buffer.write_u16(0)?;
+                let start_position = buffer.pos();
+                buffer.write_qname(m_name)?;
+                buffer.write_qname(r_name)?;
+                for value in [serial, refresh, retry, expire, minimum] {
+                    buffer.write_u32(*value)?;
+                }
+                let size = buffer.pos() - start_position;
+                buffer.set_u16(start_position - 2, size as u16)?;
+            }
+            DnsRecord::DNAME { domain, host, ttl } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(RecordType::DNAME.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(*ttl)?;
+                buffer.write_u16(0)?;
+                let start_position = buffer.pos();
+                buffer.write_qname(host)?;
+                let size: usize = buffer.pos() - start_position;
+                buffer.set_u16(start_position - 2, size as u16)?;
             }
             DnsRecord::NS { domain, host, ttl, } => {
                 buffer.write_qname(domain)?;
@@ -189,7 +436,7 @@ impl DnsRecord {
 
                 buffer.write_qname(host)?;
                 let size = buffer.pos() - start_position;
-                buffer.set_u16(start_pos - 1, size as u16)?;
+                buffer.set_u16(start_position - 2, size as u16)?;
             }
             DnsRecord::MX { domain, priority, host, ttl, } => {
                 buffer.write_qname(domain)?;
@@ -203,9 +450,376 @@ impl DnsRecord {
                 buffer.write_qname(host)?;
 
                 let size = buffer.pos() - start_position;
-                buffer.set_u16(start_position - 1, size as u16)?;
+                buffer.set_u16(start_position - 2, size as u16)?;
+            }
+            DnsRecord::OPT { domain, packet_len, flags, .. } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(RecordType::OPT.to_num())?;
+                buffer.write_u16(*packet_len)?;
+                buffer.write_u32(*flags)?;
+                self.write_rdata(buffer)?;
+            }
+            DnsRecord::DS { domain, ttl, .. }
+            | DnsRecord::RRSIG { domain, ttl, .. }
+            | DnsRecord::NSEC { domain, ttl, .. }
+            | DnsRecord::DNSKEY { domain, ttl, .. }
+            | DnsRecord::NSEC3 { domain, ttl, .. } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(self.record_type().to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(*ttl)?;
+                self.write_rdata(buffer)?;
             }
         }   
         Ok(buffer.pos() - start_pos)
     }
-}
\ No newline at end of file
+
+    /// Write the RDLENGTH and the RDATA of the DNSSEC and OPT records, whose embedded
+    /// names are never compressed (RFC 4034 §3.1.7)
+    fn write_rdata(&self, buffer: &mut BytePacketBuffer) -> Result<(), SimpleError> {
+        let rdata = self.rdata(false);
+        buffer.write_u16(rdata.len() as u16)?;
+        for byte in rdata {
+            buffer.write(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Uncompressed RDATA of the DNSSEC and OPT records, with the names lowercased when
+    /// `canonical` is set. Empty for the other types.
+    fn rdata(&self, canonical: bool) -> Vec<u8> {
+        let mut rdata = Vec::new();
+        match self {
+            DnsRecord::OPT { options, .. } => {
+                for option in options {
+                    rdata.extend_from_slice(&option.code.to_be_bytes());
+                    rdata.extend_from_slice(&(option.data.len() as u16).to_be_bytes());
+                    rdata.extend_from_slice(&option.data);
+                }
+            }
+            DnsRecord::DS { key_tag, algorithm, digest_type, digest, .. } => {
+                rdata.extend_from_slice(&key_tag.to_be_bytes());
+                rdata.push(*algorithm);
+                rdata.push(*digest_type);
+                rdata.extend_from_slice(digest);
+            }
+            DnsRecord::RRSIG { signature, .. } => {
+                rdata.extend_from_slice(&self.rrsig_rdata_prefix(canonical));
+                rdata.extend_from_slice(signature);
+            }
+            DnsRecord::NSEC { next_domain, types, .. } => {
+                // The next domain name is not lowercased in the canonical form (RFC 6840 §5.1)
+                write_name(&mut rdata, next_domain, false);
+                rdata.extend_from_slice(&write_type_bitmaps(types));
+            }
+            DnsRecord::DNSKEY { flags, protocol, algorithm, public_key, .. } => {
+                rdata.extend_from_slice(&flags.to_be_bytes());
+                rdata.push(*protocol);
+                rdata.push(*algorithm);
+                rdata.extend_from_slice(public_key);
+            }
+            DnsRecord::NSEC3 { hash_algorithm, flags, iterations, salt, next_hashed, types, .. } => {
+                rdata.push(*hash_algorithm);
+                rdata.push(*flags);
+                rdata.extend_from_slice(&iterations.to_be_bytes());
+                rdata.push(salt.len() as u8);
+                rdata.extend_from_slice(salt);
+                rdata.push(next_hashed.len() as u8);
+                rdata.extend_from_slice(next_hashed);
+                rdata.extend_from_slice(&write_type_bitmaps(types));
+            }
+            _ => {}
+        }
+        rdata
+    }
+
+    /// RDATA of an RRSIG record without the signature, the signer name in canonical form when
+    /// `canonical` is set. This is the part of the RRSIG covered by the signature (RFC 4034 §3.1.8.1).
+    pub fn rrsig_rdata_prefix(&self, canonical: bool) -> Vec<u8> {
+        let mut rdata = Vec::new();
+        if let DnsRecord::RRSIG { type_covered, algorithm, labels, original_ttl, expiration, inception, key_tag, signer_name, .. } = self {
+            rdata.extend_from_slice(&type_covered.to_num().to_be_bytes());
+            rdata.push(*algorithm);
+            rdata.push(*labels);
+            rdata.extend_from_slice(&original_ttl.to_be_bytes());
+            rdata.extend_from_slice(&expiration.to_be_bytes());
+            rdata.extend_from_slice(&inception.to_be_bytes());
+            rdata.extend_from_slice(&key_tag.to_be_bytes());
+            write_name(&mut rdata, signer_name, canonical);
+        }
+        rdata
+    }
+
+    /// Owner name of the record
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::DNAME { domain, .. }
+            | DnsRecord::OPT { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. } => domain,
+        }
+    }
+
+    /// Time to live of the record, 0 for OPT pseudo-records
+    pub fn ttl(&self) -> u32 {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::DNAME { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. } => *ttl,
+            DnsRecord::OPT { .. } => 0,
+        }
+    }
+
+    /// Change the time to live of the record. OPT pseudo-records have no TTL.
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::DNAME { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. } => *ttl = new_ttl,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
+    /// Type of the record
+    pub fn record_type(&self) -> RecordType {
+        match self {
+            DnsRecord::UNKNOWN { qtype, .. } => RecordType::UNKNOWN(*qtype),
+            DnsRecord::A { .. } => RecordType::A,
+            DnsRecord::NS { .. } => RecordType::NS,
+            DnsRecord::CNAME { .. } => RecordType::CNAME,
+            DnsRecord::SOA { .. } => RecordType::SOA,
+            DnsRecord::MX { .. } => RecordType::MX,
+            DnsRecord::AAAA { .. } => RecordType::AAAA,
+            DnsRecord::DNAME { .. } => RecordType::DNAME,
+            DnsRecord::OPT { .. } => RecordType::OPT,
+            DnsRecord::DS { .. } => RecordType::DS,
+            DnsRecord::RRSIG { .. } => RecordType::RRSIG,
+            DnsRecord::NSEC { .. } => RecordType::NSEC,
+            DnsRecord::DNSKEY { .. } => RecordType::DNSKEY,
+            DnsRecord::NSEC3 { .. } => RecordType::NSEC3,
+        }
+    }
+
+    /// RDATA of the record in canonical form (RFC 4034 §6.2):
+    /// embedded domain names are uncompressed and lowercased
+    pub fn canonical_rdata(&self) -> Vec<u8> {
+        let mut rdata = Vec::new();
+        match self {
+            DnsRecord::UNKNOWN { data, .. } => rdata.extend_from_slice(data),
+            DnsRecord::A { addr, .. } => rdata.extend_from_slice(&addr.octets()),
+            DnsRecord::AAAA { addr, .. } => rdata.extend_from_slice(&addr.octets()),
+            DnsRecord::NS { host, .. } | DnsRecord::CNAME { host, .. } | DnsRecord::DNAME { host, .. } => {
+                write_canonical_name(&mut rdata, host);
+            }
+            DnsRecord::MX { host, priority, .. } => {
+                rdata.extend_from_slice(&priority.to_be_bytes());
+                write_canonical_name(&mut rdata, host);
+            }
+            DnsRecord::SOA { m_name, r_name, serial, refresh, retry, expire, minimum, .. } => {
+                write_canonical_name(&mut rdata, m_name);
+                write_canonical_name(&mut rdata, r_name);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    rdata.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            DnsRecord::OPT { .. }
+            | DnsRecord::DS { .. }
+            | DnsRecord::RRSIG { .. }
+            | DnsRecord::NSEC { .. }
+            | DnsRecord::DNSKEY { .. }
+            | DnsRecord::NSEC3 { .. } => rdata = self.rdata(true),
+        }
+        rdata
+    }
+
+    /// Record in canonical wire form (RFC 4034 §6.2), as used to compute signatures.
+    /// The owner name is lowercased and uncompressed, and the TTL is replaced with `original_ttl`.
+    pub fn canonical_wire(&self, original_ttl: u32) -> Vec<u8> {
+        self.canonical_wire_as(self.domain(), original_ttl)
+    }
+
+    /// Record in canonical wire form with `owner` as owner name, such as the wildcard
+    /// a record was expanded from
+    pub fn canonical_wire_as(&self, owner: &str, original_ttl: u32) -> Vec<u8> {
+        let rdata = self.canonical_rdata();
+        let mut wire = Vec::with_capacity(rdata.len() + owner.len() + 12);
+        write_canonical_name(&mut wire, owner);
+        wire.extend_from_slice(&self.record_type().to_num().to_be_bytes());
+        wire.extend_from_slice(&1u16.to_be_bytes());
+        wire.extend_from_slice(&original_ttl.to_be_bytes());
+        wire.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        wire.extend_from_slice(&rdata);
+        wire
+    }
+
+    /// Canonical ordering of records (RFC 4034 §6.1 and §6.3): by owner name in canonical order,
+    /// then by type, then by RDATA compared as left-justified unsigned octet sequences
+    pub fn canonical_cmp(&self, other: &DnsRecord) -> Ordering {
+        canonical_name_cmp(self.domain(), other.domain())
+            .then_with(|| self.record_type().to_num().cmp(&other.record_type().to_num()))
+            .then_with(|| self.canonical_rdata().cmp(&other.canonical_rdata()))
+    }
+}
+
+/// Append a domain name in canonical form: uncompressed labels, lowercased
+pub fn write_canonical_name(out: &mut Vec<u8>, name: &str) {
+    write_name(out, name, true);
+}
+
+/// Append an uncompressed domain name, lowercased if `lowercase` is set
+fn write_name(out: &mut Vec<u8>, name: &str, lowercase: bool) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        out.push(label.len() as u8);
+        match lowercase {
+            true => out.extend(label.bytes().map(|byte| byte.to_ascii_lowercase())),
+            false => out.extend_from_slice(label.as_bytes()),
+        }
+    }
+    out.push(0);
+}
+
+/// Read the rest of the RDATA, up to `end`
+fn read_until(buffer: &mut BytePacketBuffer, end: usize) -> Result<Vec<u8>, SimpleError> {
+    if end < buffer.pos() {
+        bail!("Record data overflows its length");
+    }
+    let bytes = buffer.get_range(buffer.pos(), end - buffer.pos())?.to_vec();
+    buffer.seek(end)?;
+    Ok(bytes)
+}
+
+/// Decode the type bit maps of NSEC and NSEC3 records (RFC 4034 §4.1.2)
+fn read_type_bitmaps(bytes: &[u8]) -> Result<Vec<RecordType>, SimpleError> {
+    let mut types = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if pos + 2 > bytes.len() {
+            bail!("Truncated type bit map");
+        }
+        let window = bytes[pos] as u16;
+        let len = bytes[pos + 1] as usize;
+        if len == 0 || len > 32 || pos + 2 + len > bytes.len() {
+            bail!("Invalid type bit map");
+        }
+        for (index, byte) in bytes[pos + 2..pos + 2 + len].iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(RecordType::from_num((window << 8) | (index as u16 * 8 + bit)));
+                }
+            }
+        }
+        pos += 2 + len;
+    }
+    Ok(types)
+}
+
+/// Encode types as the bit maps of NSEC and NSEC3 records
+fn write_type_bitmaps(types: &[RecordType]) -> Vec<u8> {
+    let mut numbers: Vec<u16> = types.iter().map(|rtype| rtype.to_num()).collect();
+    numbers.sort_unstable();
+    numbers.dedup();
+
+    let mut out = Vec::new();
+    let mut start = 0;
+    while start < numbers.len() {
+        let window = numbers[start] >> 8;
+        let end = start + numbers[start..].iter().take_while(|number| *number >> 8 == window).count();
+        let mut bitmap = [0u8; 32];
+        for number in &numbers[start..end] {
+            let low = (number & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+        }
+        let len = bitmap.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+        out.push(window as u8);
+        out.push(len as u8);
+        out.extend_from_slice(&bitmap[..len]);
+        start = end;
+    }
+    out
+}
+
+/// Canonical DNS name order (RFC 4034 §6.1): names are compared label by label starting
+/// from the rightmost one, each label being compared as a lowercased octet sequence
+pub fn canonical_name_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name: &'_ str| {
+        name.split('.')
+            .filter(|label| !label.is_empty())
+            .rev()
+            .map(|label| label.to_ascii_lowercase())
+            .collect::<Vec<String>>()
+    };
+    labels(a).cmp(&labels(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ns(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::NS { domain: domain.to_string(), host: host.to_string(), ttl: 3600 }
+    }
+
+    #[test]
+    fn canonical_name_order() {
+        // Example of RFC 4034 §6.1, without the escaped labels
+        let ordered = ["example", "a.example", "yljkjljk.a.example", "Z.a.example", "zABC.a.EXAMPLE", "z.example", "*.z.example"];
+        let mut names = ordered.to_vec();
+        names.reverse();
+        names.sort_by(|a, b| canonical_name_cmp(a, b));
+        assert_eq!(names, ordered);
+        assert_eq!(canonical_name_cmp("Example.COM.", "example.com"), Ordering::Equal);
+    }
+
+    #[test]
+    fn embedded_names_are_lowercased() {
+        let record = DnsRecord::MX { domain: "Example.com".to_string(), host: "Mail.Example.com".to_string(), priority: 10, ttl: 300 };
+        assert_eq!(record.canonical_rdata(), b"\x00\x0a\x04mail\x07example\x03com\x00");
+
+        let wire = record.canonical_wire(3600);
+        let mut expected = b"\x07example\x03com\x00\x00\x0f\x00\x01\x00\x00\x0e\x10\x00\x14".to_vec();
+        expected.extend_from_slice(&record.canonical_rdata());
+        assert_eq!(wire, expected);
+    }
+
+    #[test]
+    fn canonical_record_order() {
+        let a = DnsRecord::A { domain: "example.com".to_string(), addr: Ipv4Addr::new(192, 0, 2, 1), ttl: 300 };
+        // By owner name first, then by type, then by RDATA
+        assert_eq!(ns("a.example.com", "a.ns.test").canonical_cmp(&a), Ordering::Greater);
+        assert_eq!(a.canonical_cmp(&ns("example.com", "a.ns.test")), Ordering::Less);
+        assert_eq!(ns("example.com", "B.ns.test").canonical_cmp(&ns("example.com", "a.ns.test")), Ordering::Greater);
+        // A shorter RDATA comes first when it is a prefix of the other one
+        assert_eq!(ns("example.com", "ns.test").canonical_cmp(&ns("example.com", "ns.test.test")), Ordering::Less);
+        assert_eq!(ns("example.com", "NS.test").canonical_cmp(&ns("EXAMPLE.com", "ns.test")), Ordering::Equal);
+    }
+}
@@ -0,0 +1,203 @@
+//! Represent a set of records sharing the same owner name, type and class
+use super::dns_questions::RecordType;
+use super::dns_record::{canonical_name_cmp, DnsRecord};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Struct to represent an RRset
+pub struct RRset {
+    pub name: String,
+    pub rtype: RecordType,
+    /// Records carry no class, so it is only set when creating the RRset
+    class: u16,
+    /// Kept equal to the TTL of every record, so only changed through `set_ttl`
+    ttl: u32,
+    /// Only added through `push`, which keeps their TTLs equal
+    records: Vec<DnsRecord>,
+}
+
+impl RRset {
+    /// Create an empty RRset of class IN
+    pub fn new(name: String, rtype: RecordType, ttl: u32) -> RRset {
+        RRset::with_class(name, rtype, 1, ttl)
+    }
+
+    /// Create an empty RRset of the given class
+    pub fn with_class(name: String, rtype: RecordType, class: u16, ttl: u32) -> RRset {
+        RRset {
+            name,
+            rtype,
+            class,
+            ttl,
+            records: Vec::new(),
+        }
+    }
+
+    /// Class of the set, IN unless created with `with_class`
+    pub fn class(&self) -> u16 {
+        self.class
+    }
+
+    /// Records of the set, sharing its TTL
+    pub fn records(&self) -> &[DnsRecord] {
+        &self.records
+    }
+
+    /// Take the records out of the set
+    pub fn into_records(self) -> Vec<DnsRecord> {
+        self.records
+    }
+
+    /// Check whether a record belongs to this RRset
+    pub fn matches(&self, record: &DnsRecord) -> bool {
+        record.record_type() == self.rtype && record.domain().eq_ignore_ascii_case(&self.name)
+    }
+
+    /// Add a record to the set. TTLs must be equal within an RRset (RFC 2181 §5.2),
+    /// so the lowest one is kept and applied to every record.
+    pub fn push(&mut self, record: DnsRecord) {
+        if self.records.is_empty() || record.ttl() < self.ttl {
+            self.ttl = record.ttl();
+        }
+        self.records.push(record);
+        self.normalize_ttl();
+    }
+
+    /// Apply the TTL of the set to all its records
+    pub fn normalize_ttl(&mut self) {
+        let ttl = self.ttl;
+        for record in self.records.iter_mut() {
+            record.set_ttl(ttl);
+        }
+    }
+
+    /// TTL shared by the records of the set
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    /// Change the TTL of the set and of all its records
+    pub fn set_ttl(&mut self, ttl: u32) {
+        self.ttl = ttl;
+        self.normalize_ttl();
+    }
+
+    /// Group records into RRsets, keeping the order in which each set first appears
+    pub fn group(records: &[DnsRecord]) -> Vec<RRset> {
+        let mut sets: Vec<RRset> = Vec::new();
+        for record in records {
+            match sets.iter_mut().find(|set| set.matches(record)) {
+                Some(set) => set.push(record.clone()),
+                None => {
+                    let mut set = RRset::new(record.domain().to_string(), record.record_type(), record.ttl());
+                    set.push(record.clone());
+                    sets.push(set);
+                }
+            }
+        }
+        sets
+    }
+
+    /// Flatten RRsets back into a list of records
+    pub fn flatten(sets: Vec<RRset>) -> Vec<DnsRecord> {
+        sets.into_iter().flat_map(RRset::into_records).collect()
+    }
+
+    /// Records sorted in canonical order (RFC 4034 §6.3), duplicates removed
+    pub fn canonical_records(&self) -> Vec<&DnsRecord> {
+        let mut records: Vec<(Vec<u8>, &DnsRecord)> = self.records
+            .iter()
+            .map(|record| (record.canonical_rdata(), record))
+            .collect();
+        records.sort_by(|a, b| a.0.cmp(&b.0));
+        records.dedup_by(|a, b| a.0 == b.0);
+        records.into_iter().map(|(_, record)| record).collect()
+    }
+
+    /// Canonical byte string of the RRset, as covered by an RRSIG: every record in
+    /// canonical wire form with `original_ttl`, sorted by RDATA
+    pub fn canonical_bytes(&self, original_ttl: u32) -> Vec<u8> {
+        self.canonical_records()
+            .into_iter()
+            .flat_map(|record| record.canonical_wire(original_ttl))
+            .collect()
+    }
+
+    /// Order RRsets by owner name in canonical order, then by type
+    pub fn canonical_cmp(&self, other: &RRset) -> std::cmp::Ordering {
+        canonical_name_cmp(&self.name, &other.name)
+            .then_with(|| self.rtype.to_num().cmp(&other.rtype.to_num()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ns(domain: &str, host: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::NS { domain: domain.to_string(), host: host.to_string(), ttl }
+    }
+
+    #[test]
+    fn canonical_records_are_sorted_without_duplicates() {
+        let mut rrset = RRset::new("example.com".to_string(), RecordType::NS, 3600);
+        for host in ["b.ns.test", "A.ns.test", "a.NS.test", "a.ns.test.test"] {
+            rrset.push(ns("example.com", host, 3600));
+        }
+        let hosts: Vec<&str> = rrset.canonical_records()
+            .into_iter()
+            .map(|record| match record {
+                DnsRecord::NS { host, .. } => host.as_str(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(hosts, ["A.ns.test", "a.ns.test.test", "b.ns.test"]);
+
+        let bytes = rrset.canonical_bytes(300);
+        let expected: Vec<u8> = rrset.canonical_records().into_iter().flat_map(|record| record.canonical_wire(300)).collect();
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn canonical_rrset_order() {
+        let rrset = |name: &str, rtype| RRset::new(name.to_string(), rtype, 300);
+        let mut sets = [
+            rrset("www.example.com", RecordType::A),
+            rrset("example.com", RecordType::NS),
+            rrset("Example.com", RecordType::A),
+        ];
+        sets.sort_by(|a, b| a.canonical_cmp(b));
+        let order: Vec<(&str, RecordType)> = sets.iter().map(|set| (set.name.as_str(), set.rtype)).collect();
+        assert_eq!(order, [("Example.com", RecordType::A), ("example.com", RecordType::NS), ("www.example.com", RecordType::A)]);
+    }
+
+    #[test]
+    fn records_are_grouped_in_order_of_appearance() {
+        let a = DnsRecord::A { domain: "ns1.example.com".to_string(), addr: "192.0.2.1".parse().unwrap(), ttl: 300 };
+        let records = [
+            ns("example.com", "ns1.example.com", 3600),
+            a.clone(),
+            ns("EXAMPLE.com", "ns2.example.com", 3600),
+            ns("www.example.com", "ns1.example.com", 3600),
+        ];
+        let sets = RRset::group(&records);
+        assert_eq!(sets.len(), 3);
+        assert_eq!((sets[0].name.as_str(), sets[0].rtype, sets[0].records.len()), ("example.com", RecordType::NS, 2));
+        assert_eq!(sets[1].records, [a]);
+        assert_eq!(sets[2].name, "www.example.com");
+
+        let flattened = RRset::flatten(sets);
+        assert_eq!(flattened, [records[0].clone(), records[2].clone(), records[1].clone(), records[3].clone()]);
+    }
+
+    #[test]
+    fn lowest_ttl_applies_to_the_set() {
+        let sets = RRset::group(&[ns("example.com", "ns1.test", 3600), ns("example.com", "ns2.test", 60), ns("example.com", "ns3.test", 600)]);
+        assert_eq!(sets[0].ttl(), 60);
+        assert!(sets[0].records.iter().all(|record| record.ttl() == 60));
+
+        let mut rrset = sets[0].clone();
+        rrset.set_ttl(10);
+        assert_eq!(rrset.ttl(), 10);
+        assert!(rrset.records.iter().all(|record| record.ttl() == 10));
+    }
+}
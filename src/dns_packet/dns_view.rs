@@ -0,0 +1,533 @@
+//! Borrowed view over the raw bytes of a DNS packet, decoding its content on demand
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use super::dns_header::DnsHeader;
+use super::dns_questions::{DnsQuestions, RecordType};
+use super::dns_record::DnsRecord;
+use crate::BytePacketBuffer;
+use simple_error::SimpleError;
+
+/// Size of the fixed DNS header
+const HEADER_LEN: usize = 12;
+/// Maximum number of compression pointers followed while reading a name
+const MAX_JUMPS: usize = 5;
+
+#[derive(Clone, Copy, Debug)]
+/// Lazily-decoded view over a DNS packet held in a byte slice.
+/// Nothing is allocated: names, questions and records are decoded when iterated over.
+pub struct DnsPacketView<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> DnsPacketView<'a> {
+    /// Wrap a raw packet, only checking that a full header is present
+    pub fn new(data: &'a [u8]) -> Result<DnsPacketView<'a>, SimpleError> {
+        if data.len() < HEADER_LEN {
+            bail!("Packet is shorter than a DNS header");
+        }
+        Ok(DnsPacketView { data })
+    }
+
+    /// Raw bytes of the packet
+    pub fn bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Transaction id of the packet
+    pub fn id(&self) -> u16 {
+        read_u16(self.data, 0).unwrap_or(0)
+    }
+
+    /// Decode the header of the packet
+    pub fn header(&self) -> DnsHeader {
+        let mut header = DnsHeader::new();
+        header.id = self.id();
+        header.read_flags(self.data[2], self.data[3]);
+        header.questions = self.count(0);
+        header.answers = self.count(1);
+        header.authoritative_entries = self.count(2);
+        header.resource_entries = self.count(3);
+        header
+    }
+
+    /// Number of entries announced by the header for the given section
+    fn count(&self, section: usize) -> u16 {
+        read_u16(self.data, 4 + 2 * section).unwrap_or(0)
+    }
+
+    /// Iterator over the question section
+    pub fn questions(&self) -> QuestionIter<'a> {
+        QuestionIter {
+            data: self.data,
+            pos: HEADER_LEN,
+            remaining: self.count(0),
+        }
+    }
+
+    /// Iterator over the answer section
+    pub fn answers(&self) -> RecordIter<'a> {
+        self.records(1)
+    }
+
+    /// Iterator over the authority section
+    pub fn authorities(&self) -> RecordIter<'a> {
+        self.records(2)
+    }
+
+    /// Iterator over the additional section
+    pub fn resources(&self) -> RecordIter<'a> {
+        self.records(3)
+    }
+
+    /// Build an iterator over one of the record sections. Preceding sections are
+    /// skipped without being decoded; an error while skipping is reported by the iterator.
+    fn records(&self, section: usize) -> RecordIter<'a> {
+        let mut start = Ok(HEADER_LEN);
+        for skipped in 0..section {
+            start = start.and_then(|pos| {
+                let mut pos = pos;
+                for _ in 0..self.count(skipped) {
+                    pos = skip_name(self.data, pos)?;
+                    pos += if skipped == 0 { 4 } else { 8 };
+                    if skipped > 0 {
+                        let rdlen = read_u16(self.data, pos)? as usize;
+                        pos += 2 + rdlen;
+                    }
+                }
+                Ok(pos)
+            });
+        }
+
+        match start {
+            Ok(pos) => RecordIter {
+                data: self.data,
+                pos,
+                remaining: self.count(section),
+                error: None,
+            },
+            Err(e) => RecordIter {
+                data: self.data,
+                pos: 0,
+                remaining: 0,
+                error: Some(e),
+            },
+        }
+    }
+
+    /// First question of the packet, without decoding the rest of it
+    pub fn first_question(&self) -> Option<QuestionView<'a>> {
+        self.questions().next().and_then(|q| q.ok())
+    }
+}
+
+#[derive(Clone, Copy)]
+/// Domain name stored somewhere in a packet, possibly compressed
+pub struct NameView<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> NameView<'a> {
+    /// Iterator over the labels of the name, following compression pointers
+    pub fn labels(&self) -> LabelIter<'a> {
+        LabelIter {
+            data: self.data,
+            pos: self.pos,
+            jumps: 0,
+            done: false,
+        }
+    }
+
+    /// Compare the name with a dotted domain, ignoring ASCII case
+    pub fn eq_ignore_case(&self, domain: &str) -> bool {
+        self.compare(domain, |label, other| label.eq_ignore_ascii_case(other))
+    }
+
+    /// Compare the name with a dotted domain, byte for byte
+    pub fn eq_exact(&self, domain: &str) -> bool {
+        self.compare(domain, |label, other| label == other)
+    }
+
+    /// Compare the labels of the name with the ones of a dotted domain
+    fn compare(&self, domain: &str, label_eq: impl Fn(&[u8], &[u8]) -> bool) -> bool {
+        let mut expected = domain.trim_end_matches('.').split('.').filter(|l| !l.is_empty());
+        for label in self.labels() {
+            match (label, expected.next()) {
+                (Ok(label), Some(other)) if label_eq(label, other.as_bytes()) => {}
+                _ => return false,
+            }
+        }
+        expected.next().is_none()
+    }
+}
+
+impl fmt::Display for NameView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut delimiter = "";
+        for label in self.labels() {
+            // A malformed name is still displayed, up to the faulty label
+            let label = match label {
+                Ok(label) => label,
+                Err(_) => return f.write_str("<malformed>"),
+            };
+            f.write_str(delimiter)?;
+            for byte in label {
+                write!(f, "{}", *byte as char)?;
+            }
+            delimiter = ".";
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for NameView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", format_args!("{}", self))
+    }
+}
+
+/// Iterator over the labels of a name
+pub struct LabelIter<'a> {
+    data: &'a [u8],
+    pos: usize,
+    jumps: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for LabelIter<'a> {
+    type Item = Result<&'a [u8], SimpleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let len = match self.data.get(self.pos) {
+                Some(len) => *len,
+                None => {
+                    self.done = true;
+                    return Some(Err(SimpleError::new("End of buffer")));
+                }
+            };
+
+            // Compression pointer: continue reading at the offset given by the 14 lower bits
+            if (len & 0xC0) == 0xC0 {
+                self.jumps += 1;
+                if self.jumps > MAX_JUMPS {
+                    self.done = true;
+                    return Some(Err(SimpleError::new(format!("Limit of {} jumps exceeded", MAX_JUMPS))));
+                }
+                match read_u16(self.data, self.pos) {
+                    Ok(pointer) => self.pos = (pointer & 0x3FFF) as usize,
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+                }
+                continue;
+            }
+
+            // Empty label means end of the domain name
+            if len == 0 {
+                self.done = true;
+                return None;
+            }
+
+            let start = self.pos + 1;
+            let end = start + len as usize;
+            return match self.data.get(start..end) {
+                Some(label) => {
+                    self.pos = end;
+                    Some(Ok(label))
+                }
+                None => {
+                    self.done = true;
+                    Some(Err(SimpleError::new("End of buffer")))
+                }
+            };
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Question decoded from a packet view
+pub struct QuestionView<'a> {
+    pub name: NameView<'a>,
+    pub qtype: RecordType,
+    pub class: u16,
+}
+
+impl QuestionView<'_> {
+    /// Convert into an owned question
+    pub fn to_question(&self) -> DnsQuestions {
+        DnsQuestions::new(self.name.to_string(), self.qtype)
+    }
+}
+
+/// Iterator over the question section of a packet view
+pub struct QuestionIter<'a> {
+    data: &'a [u8],
+    pos: usize,
+    remaining: u16,
+}
+
+impl<'a> Iterator for QuestionIter<'a> {
+    type Item = Result<QuestionView<'a>, SimpleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let result = (|| {
+            let name = NameView { data: self.data, pos: self.pos };
+            let pos = skip_name(self.data, self.pos)?;
+            let qtype = RecordType::from_num(read_u16(self.data, pos)?);
+            let class = read_u16(self.data, pos + 2)?;
+            self.pos = pos + 4;
+            Ok(QuestionView { name, qtype, class })
+        })();
+
+        // Stop the iteration after the first error
+        if result.is_err() {
+            self.remaining = 0;
+        }
+        Some(result)
+    }
+}
+
+#[derive(Clone, Copy)]
+/// Resource record decoded from a packet view. The data is left as raw bytes.
+pub struct RecordView<'a> {
+    data: &'a [u8],
+    pub name: NameView<'a>,
+    pub rtype: RecordType,
+    pub class: u16,
+    pub ttl: u32,
+    rdata_pos: usize,
+    rdata_len: usize,
+}
+
+impl<'a> RecordView<'a> {
+    /// Raw record data
+    pub fn rdata(&self) -> &'a [u8] {
+        &self.data[self.rdata_pos..self.rdata_pos + self.rdata_len]
+    }
+
+    /// Address held by an A record
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        match (self.rtype, self.rdata()) {
+            (RecordType::A, &[a, b, c, d]) => Some(Ipv4Addr::new(a, b, c, d)),
+            _ => None,
+        }
+    }
+
+    /// Address held by an AAAA record
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        match (self.rtype, <[u8; 16]>::try_from(self.rdata())) {
+            (RecordType::AAAA, Ok(octets)) => Some(Ipv6Addr::from(octets)),
+            _ => None,
+        }
+    }
+
+    /// Name held by NS, CNAME, DNAME and MX records. Compression pointers are resolved
+    /// against the whole packet.
+    pub fn target(&self) -> Option<NameView<'a>> {
+        match self.rtype {
+            RecordType::NS | RecordType::CNAME | RecordType::DNAME => Some(NameView { data: self.data, pos: self.rdata_pos }),
+            RecordType::MX if self.rdata_len > 2 => Some(NameView { data: self.data, pos: self.rdata_pos + 2 }),
+            _ => None,
+        }
+    }
+
+    /// Decode into an owned record
+    pub fn to_record(&self) -> Result<DnsRecord, SimpleError> {
+        let mut buffer = BytePacketBuffer::from_bytes(self.data);
+        buffer.seek(self.name.pos)?;
+        DnsRecord::read(&mut buffer)
+    }
+}
+
+impl fmt::Debug for RecordView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordView")
+            .field("name", &self.name)
+            .field("rtype", &self.rtype)
+            .field("class", &self.class)
+            .field("ttl", &self.ttl)
+            .field("rdata", &self.rdata())
+            .finish()
+    }
+}
+
+/// Iterator over a record section of a packet view
+pub struct RecordIter<'a> {
+    data: &'a [u8],
+    pos: usize,
+    remaining: u16,
+    error: Option<SimpleError>,
+}
+
+impl<'a> Iterator for RecordIter<'a> {
+    type Item = Result<RecordView<'a>, SimpleError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let result = (|| {
+            let name = NameView { data: self.data, pos: self.pos };
+            let pos = skip_name(self.data, self.pos)?;
+            let rtype = RecordType::from_num(read_u16(self.data, pos)?);
+            let class = read_u16(self.data, pos + 2)?;
+            let ttl = ((read_u16(self.data, pos + 4)? as u32) << 16) | read_u16(self.data, pos + 6)? as u32;
+            let rdata_len = read_u16(self.data, pos + 8)? as usize;
+            let rdata_pos = pos + 10;
+            if rdata_pos + rdata_len > self.data.len() {
+                bail!("End of buffer");
+            }
+            self.pos = rdata_pos + rdata_len;
+            Ok(RecordView { data: self.data, name, rtype, class, ttl, rdata_pos, rdata_len })
+        })();
+
+        // Stop the iteration after the first error
+        if result.is_err() {
+            self.remaining = 0;
+        }
+        Some(result)
+    }
+}
+
+/// Read a big endian u16 at `pos`
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, SimpleError> {
+    match data.get(pos..pos + 2) {
+        Some(bytes) => Ok(((bytes[0] as u16) << 8) | bytes[1] as u16),
+        None => bail!("End of buffer"),
+    }
+}
+
+/// Return the position right after the name starting at `pos`, without following pointers
+fn skip_name(data: &[u8], mut pos: usize) -> Result<usize, SimpleError> {
+    loop {
+        let len = match data.get(pos) {
+            Some(len) => *len,
+            None => bail!("End of buffer"),
+        };
+        if (len & 0xC0) == 0xC0 {
+            return Ok(pos + 2);
+        }
+        pos += 1;
+        if len == 0 {
+            return Ok(pos);
+        }
+        pos += len as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DnsPacket;
+
+    /// Response to www.example.com A with a CNAME and an A record, its names compressed
+    fn compressed_response() -> Vec<u8> {
+        let mut data = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0];
+        // Question at offset 12, example.com starting at offset 16
+        data.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+        // www.example.com CNAME web.example.com
+        data.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0x0E, 0x10, 0, 6]);
+        let web = data.len();
+        data.extend_from_slice(b"\x03web\xC0\x10");
+        // web.example.com A 192.0.2.1
+        data.extend_from_slice(&[0xC0, web as u8, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        data
+    }
+
+    #[test]
+    fn short_packets_are_rejected() {
+        assert!(DnsPacketView::new(&[0; 11]).is_err());
+    }
+
+    #[test]
+    fn header_and_question() {
+        let data = compressed_response();
+        let view = DnsPacketView::new(&data).unwrap();
+        let header = view.header();
+        assert_eq!(header.id, 0x1234);
+        assert!(header.response);
+        assert_eq!((header.questions, header.answers), (1, 2));
+
+        let question = view.first_question().unwrap();
+        assert!(question.name.eq_exact("www.example.com"));
+        assert!(question.name.eq_ignore_case("WWW.Example.com."));
+        assert!(!question.name.eq_exact("WWW.example.com"));
+        assert!(!question.name.eq_ignore_case("example.com"));
+        assert_eq!(question.qtype, RecordType::A);
+    }
+
+    #[test]
+    fn compressed_names_are_followed() {
+        let data = compressed_response();
+        let view = DnsPacketView::new(&data).unwrap();
+        let answers: Vec<RecordView> = view.answers().collect::<Result<_, _>>().unwrap();
+        assert_eq!(answers.len(), 2);
+
+        assert_eq!(answers[0].name.to_string(), "www.example.com");
+        assert_eq!(answers[0].rtype, RecordType::CNAME);
+        assert_eq!(answers[0].ttl, 3600);
+        assert_eq!(answers[0].target().unwrap().to_string(), "web.example.com");
+
+        assert_eq!(answers[1].name.to_string(), "web.example.com");
+        assert_eq!(answers[1].ipv4(), Some(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(answers[1].ipv6(), None);
+        assert_eq!(
+            answers[1].to_record().unwrap(),
+            DnsRecord::A { domain: "web.example.com".to_string(), addr: Ipv4Addr::new(192, 0, 2, 1), ttl: 60 }
+        );
+        assert!(view.authorities().next().is_none());
+    }
+
+    #[test]
+    fn same_records_as_the_owned_packet() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestions::new("example.com".to_string(), RecordType::NS));
+        packet.authorities.push(DnsRecord::NS { domain: "example.com".to_string(), host: "ns1.example.com".to_string(), ttl: 300 });
+        packet.resources.push(DnsRecord::AAAA { domain: "ns1.example.com".to_string(), addr: "2001:db8::1".parse().unwrap(), ttl: 300 });
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+
+        let view = DnsPacketView::new(&buffer.buf[..buffer.pos]).unwrap();
+        let authority = view.authorities().next().unwrap().unwrap();
+        assert_eq!(authority.target().unwrap().to_string(), "ns1.example.com");
+        let additional = view.resources().next().unwrap().unwrap();
+        assert_eq!(additional.ipv6(), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(additional.to_record().unwrap(), packet.resources[0]);
+    }
+
+    #[test]
+    fn pointer_loops_are_stopped() {
+        let mut data = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        let view = DnsPacketView::new(&data).unwrap();
+        let question = view.first_question().unwrap();
+        assert!(question.name.labels().any(|label| label.is_err()));
+        assert_eq!(question.name.to_string(), "<malformed>");
+    }
+
+    #[test]
+    fn truncated_sections_are_errors() {
+        let data = compressed_response();
+        // The last record is cut after its type
+        let view = DnsPacketView::new(&data[..data.len() - 12]).unwrap();
+        let answers: Vec<_> = view.answers().collect();
+        assert!(answers[0].is_ok());
+        assert!(answers[1].is_err());
+        // Sections after a truncated one cannot be found
+        assert!(view.authorities().next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,861 @@
+//! DNSSEC primitives (RFC 4033, 4034, 4035 and 5155): trust anchors, key tags, DS digests,
+//! RRSIG verification and NSEC/NSEC3 proofs of non-existence.
+//! The chain of trust itself is built by the resolver.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::digest;
+use ring::signature;
+use simple_error::SimpleError;
+use crate::dns_packet::{is_proper_subdomain, is_subdomain};
+use crate::{canonical_name_cmp, write_canonical_name, DnsRecord, RRset, RecordType};
+
+/// DS records of the root key signing keys (https://data.iana.org/root-anchors/root-anchors.xml):
+/// KSK-2017 and KSK-2024
+const BUILTIN_TRUST_ANCHORS: &str = "\
+. IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+. IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16
+";
+
+/// Zone Key flag of DNSKEY records
+const DNSKEY_ZONE_FLAG: u16 = 0x0100;
+/// Opt-Out flag of NSEC3 records
+const NSEC3_OPT_OUT_FLAG: u8 = 0x01;
+/// NSEC3 records with more iterations are treated as insecure (RFC 9276 §3.2)
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Outcome of the validation of DNSSEC data (RFC 4033 §5), from the worst to the best.
+/// The status of data made of several parts is the lowest of them.
+pub enum Security {
+    /// Signatures or proofs that do not check out, although a chain of trust exists
+    Bogus,
+    /// Data from a zone no chain of trust leads to
+    Insecure,
+    /// Data validated from a trust anchor
+    Secure,
+}
+
+/// DS records of the root zone built into the crate
+pub fn builtin_trust_anchors() -> Vec<DnsRecord> {
+    parse_trust_anchors(BUILTIN_TRUST_ANCHORS).expect("Built-in trust anchors are valid")
+}
+
+/// Parse trust anchors given as DS records in presentation format, one per line:
+/// `<owner> [<ttl>] [IN] DS <key tag> <algorithm> <digest type> <digest>`
+pub fn parse_trust_anchors(content: &str) -> Result<Vec<DnsRecord>, SimpleError> {
+    let mut anchors = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let position = match fields.iter().position(|field| field.eq_ignore_ascii_case("DS")) {
+            Some(position) if fields.len() >= position + 5 => position,
+            _ => bail!("Invalid trust anchor at line {}", number + 1),
+        };
+        let parse_error = |e| SimpleError::with("Invalid trust anchor", e);
+        anchors.push(DnsRecord::DS {
+            domain: fields[0].trim_end_matches('.').to_ascii_lowercase(),
+            key_tag: fields[position + 1].parse().map_err(parse_error)?,
+            algorithm: fields[position + 2].parse().map_err(parse_error)?,
+            digest_type: fields[position + 3].parse().map_err(parse_error)?,
+            digest: decode_hex(&fields[position + 4..].concat())?,
+            ttl: 0,
+        });
+    }
+    Ok(anchors)
+}
+
+/// Current time as an RRSIG timestamp: seconds since the epoch, modulo 2^32
+pub fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+/// Signature algorithms this crate can verify: RSA/SHA-256, ECDSA P-256 and P-384, Ed25519
+pub fn is_supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 8 | 13 | 14 | 15)
+}
+
+/// DS digest types this crate can compute: SHA-1, SHA-256 and SHA-384
+pub fn is_supported_digest(digest_type: u8) -> bool {
+    matches!(digest_type, 1 | 2 | 4)
+}
+
+/// Key tag of a DNSKEY record (RFC 4034 Appendix B)
+pub fn key_tag(dnskey: &DnsRecord) -> u16 {
+    let mut accumulator: u32 = 0;
+    for (index, byte) in dnskey.canonical_rdata().iter().enumerate() {
+        accumulator += match index % 2 {
+            0 => (*byte as u32) << 8,
+            _ => *byte as u32,
+        };
+    }
+    accumulator += (accumulator >> 16) & 0xFFFF;
+    (accumulator & 0xFFFF) as u16
+}
+
+/// Check that a DS record designates a DNSKEY record: same owner, key tag and algorithm,
+/// and a digest matching the key (RFC 4034 §5.1.4)
+pub fn ds_matches(ds: &DnsRecord, dnskey: &DnsRecord) -> bool {
+    let (ds_tag, ds_algorithm, digest_type, expected) = match ds {
+        DnsRecord::DS { key_tag, algorithm, digest_type, digest, .. } => (*key_tag, *algorithm, *digest_type, digest),
+        _ => return false,
+    };
+    let key_algorithm = match dnskey {
+        DnsRecord::DNSKEY { algorithm, .. } => *algorithm,
+        _ => return false,
+    };
+    if !ds.domain().eq_ignore_ascii_case(dnskey.domain()) || ds_algorithm != key_algorithm || ds_tag != key_tag(dnskey) {
+        return false;
+    }
+
+    let algorithm = match digest_type {
+        1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        2 => &digest::SHA256,
+        4 => &digest::SHA384,
+        _ => return false,
+    };
+    let mut data = Vec::new();
+    write_canonical_name(&mut data, dnskey.domain());
+    data.extend_from_slice(&dnskey.canonical_rdata());
+    digest::digest(algorithm, &data).as_ref() == expected.as_slice()
+}
+
+/// RRSIG records of `records` covering `rrset`
+pub fn rrsigs_for<'a>(records: &'a [DnsRecord], rrset: &RRset) -> Vec<&'a DnsRecord> {
+    records
+        .iter()
+        .filter(|record| match record {
+            DnsRecord::RRSIG { domain, type_covered, .. } => *type_covered == rrset.rtype && domain.eq_ignore_ascii_case(&rrset.name),
+            _ => false,
+        })
+        .collect()
+}
+
+/// Check that an RRset is signed by one of `keys`, the DNSKEY records of `zone`, through one of `rrsigs`.
+/// Returns the RRSIG that verified.
+pub fn verify_rrset<'a>(rrset: &RRset, rrsigs: &[&'a DnsRecord], keys: &[DnsRecord], zone: &str, now: u32) -> Option<&'a DnsRecord> {
+    rrsigs.iter().copied().find(|rrsig| {
+        matches!(rrsig, DnsRecord::RRSIG { signer_name, .. } if signer_name.eq_ignore_ascii_case(zone))
+            && keys.iter().any(|key| verify_rrsig(rrset, rrsig, key, now).is_ok())
+    })
+}
+
+/// Verify the signature of an RRset with a DNSKEY record (RFC 4035 §5.3)
+pub fn verify_rrsig(rrset: &RRset, rrsig: &DnsRecord, dnskey: &DnsRecord, now: u32) -> Result<(), SimpleError> {
+    let (type_covered, algorithm, labels, original_ttl, expiration, inception, tag, signer_name, sig) = match rrsig {
+        DnsRecord::RRSIG { type_covered, algorithm, labels, original_ttl, expiration, inception, key_tag, signer_name, signature, .. } =>
+            (*type_covered, *algorithm, *labels, *original_ttl, *expiration, *inception, *key_tag, signer_name, signature),
+        _ => bail!("Not an RRSIG record"),
+    };
+    let (flags, protocol, key_algorithm, public_key) = match dnskey {
+        DnsRecord::DNSKEY { flags, protocol, algorithm, public_key, .. } => (*flags, *protocol, *algorithm, public_key),
+        _ => bail!("Not a DNSKEY record"),
+    };
+
+    if type_covered != rrset.rtype || !rrsig.domain().eq_ignore_ascii_case(&rrset.name) {
+        bail!("RRSIG does not cover the RRset");
+    }
+    if !signer_name.eq_ignore_ascii_case(dnskey.domain()) || !is_subdomain(&rrset.name, signer_name) {
+        bail!("RRSIG signer {} cannot sign {}", signer_name, rrset.name);
+    }
+    if flags & DNSKEY_ZONE_FLAG == 0 || protocol != 3 || key_algorithm != algorithm || key_tag(dnskey) != tag {
+        bail!("RRSIG does not match the key");
+    }
+    if labels as usize > label_count(&rrset.name) {
+        bail!("RRSIG labels field exceeds the owner name");
+    }
+    if !serial_le(inception, now) || !serial_le(now, expiration) {
+        bail!("RRSIG is outside its validity period");
+    }
+
+    // Signed data: the RRSIG RDATA without the signature, followed by the RRset in canonical
+    // form, the owner being the wildcard the RRset was expanded from if any (RFC 4035 §5.3.2)
+    let owner = wildcard_source(&rrset.name, labels);
+    let mut data = rrsig.rrsig_rdata_prefix(true);
+    for record in rrset.canonical_records() {
+        data.extend_from_slice(&record.canonical_wire_as(&owner, original_ttl));
+    }
+
+    verify_signature(algorithm, public_key, &data, sig)
+}
+
+/// Check a signature with a public key in the DNSKEY format of `algorithm`
+fn verify_signature(algorithm: u8, public_key: &[u8], data: &[u8], sig: &[u8]) -> Result<(), SimpleError> {
+    let result = match algorithm {
+        // RSA public keys are the exponent length, the exponent and the modulus (RFC 3110 §2)
+        8 => {
+            let (exponent_len, offset) = match public_key {
+                [0, high, low, ..] => (((*high as usize) << 8) | *low as usize, 3),
+                [len, ..] => (*len as usize, 1),
+                [] => bail!("Empty RSA key"),
+            };
+            if public_key.len() <= offset + exponent_len {
+                bail!("Truncated RSA key");
+            }
+            let e = strip_leading_zeros(&public_key[offset..offset + exponent_len]);
+            let n = strip_leading_zeros(&public_key[offset + exponent_len..]);
+            signature::RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, data, sig)
+        }
+        // ECDSA public keys are the uncompressed point without its 0x04 prefix (RFC 6605 §4)
+        13 | 14 => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(public_key);
+            let verification: &dyn signature::VerificationAlgorithm = match algorithm {
+                13 => &signature::ECDSA_P256_SHA256_FIXED,
+                _ => &signature::ECDSA_P384_SHA384_FIXED,
+            };
+            signature::UnparsedPublicKey::new(verification, &point).verify(data, sig)
+        }
+        15 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key).verify(data, sig),
+        _ => bail!("Unsupported algorithm {}", algorithm),
+    };
+    result.map_err(|_| SimpleError::new("Invalid signature"))
+}
+
+/// Check whether an RRSIG shows that its RRset was synthesized from a wildcard
+pub fn is_wildcard_expansion(rrsig: &DnsRecord) -> bool {
+    match rrsig {
+        DnsRecord::RRSIG { domain, labels, .. } => (*labels as usize) < label_count(domain),
+        _ => false,
+    }
+}
+
+/// Check that NSEC or NSEC3 records prove that `qname` has no record of type `qtype`.
+/// `nxdomain` tells whether the name itself is denied.
+pub fn prove_denial(records: &[DnsRecord], qname: &str, qtype: RecordType, nxdomain: bool) -> Security {
+    let nsecs: Vec<&DnsRecord> = records.iter().filter(|record| record.record_type() == RecordType::NSEC).collect();
+    if !nsecs.is_empty() {
+        let proven = match nxdomain {
+            true => nsec_proves_nxdomain(&nsecs, qname),
+            false => nsec_proves_nodata(&nsecs, qname, qtype),
+        };
+        return if proven { Security::Secure } else { Security::Bogus };
+    }
+
+    let nsec3s: Vec<&DnsRecord> = records.iter().filter(|record| record.record_type() == RecordType::NSEC3).collect();
+    if nsec3s.is_empty() {
+        return Security::Bogus;
+    }
+    if let Some(security) = nsec3_unusable(&nsec3s) {
+        return security;
+    }
+    match nxdomain {
+        true => nsec3_proves_nxdomain(&nsec3s, qname),
+        false => nsec3_proves_nodata(&nsec3s, qname, qtype),
+    }
+}
+
+/// Check that NSEC or NSEC3 records prove that `qname` does not exist, as required for an
+/// answer synthesized from a wildcard whose RRSIG has `labels` labels (RFC 4035 §5.3.4)
+pub fn prove_wildcard_expansion(records: &[DnsRecord], qname: &str, labels: u8) -> bool {
+    let nsecs: Vec<&DnsRecord> = records.iter().filter(|record| record.record_type() == RecordType::NSEC).collect();
+    if nsecs.iter().any(|nsec| nsec_covers(nsec, qname)) {
+        return true;
+    }
+
+    // With NSEC3, the next closer name of the wildcard's parent is covered
+    let nsec3s: Vec<&DnsRecord> = records.iter().filter(|record| record.record_type() == RecordType::NSEC3).collect();
+    if nsec3s.is_empty() || nsec3_unusable(&nsec3s).is_some() {
+        return false;
+    }
+    let next_closer = ancestor(qname, labels as usize + 1);
+    nsec3s.iter().any(|nsec3| nsec3_covers(nsec3, &next_closer))
+}
+
+/// What a proof that a name has no DS record tells about the name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoDsProof {
+    /// The name is not a zone cut, it belongs to the same zone as its parent
+    NoZoneCut,
+    /// The name is delegated to an unsigned zone
+    InsecureDelegation,
+}
+
+/// Interpret NSEC or NSEC3 records proving that `name` has no DS record (RFC 4035 §5.2, RFC 5155 §8.6)
+pub fn prove_no_ds(records: &[DnsRecord], name: &str) -> Option<NoDsProof> {
+    let delegation = |types: &[RecordType]| {
+        match types.contains(&RecordType::NS) && !types.contains(&RecordType::SOA) {
+            true => NoDsProof::InsecureDelegation,
+            false => NoDsProof::NoZoneCut,
+        }
+    };
+
+    for record in records {
+        match record {
+            DnsRecord::NSEC { domain, types, .. } if domain.eq_ignore_ascii_case(name) => {
+                if types.contains(&RecordType::DS) || types.contains(&RecordType::CNAME) {
+                    return None;
+                }
+                return Some(delegation(types));
+            }
+            // An empty non-terminal is not a zone cut
+            DnsRecord::NSEC { next_domain, .. } if nsec_covers(record, name) && is_proper_subdomain(next_domain, name) => {
+                return Some(NoDsProof::NoZoneCut);
+            }
+            _ => {}
+        }
+    }
+
+    let nsec3s: Vec<&DnsRecord> = records.iter().filter(|record| record.record_type() == RecordType::NSEC3).collect();
+    if nsec3s.is_empty() {
+        return None;
+    }
+    if let Some(security) = nsec3_unusable(&nsec3s) {
+        return match security {
+            Security::Insecure => Some(NoDsProof::InsecureDelegation),
+            _ => None,
+        };
+    }
+    for nsec3 in &nsec3s {
+        if let DnsRecord::NSEC3 { types, .. } = nsec3 {
+            if nsec3_matches(nsec3, name) {
+                if types.contains(&RecordType::DS) || types.contains(&RecordType::CNAME) {
+                    return None;
+                }
+                return Some(delegation(types));
+            }
+        }
+    }
+
+    // An unsigned delegation may have no NSEC3 record of its own when it is covered by an
+    // Opt-Out NSEC3 record (RFC 5155 §6)
+    let (_, next_closer) = nsec3_closest_encloser(&nsec3s, name)?;
+    let opt_out = nsec3s.iter().any(|nsec3| {
+        matches!(nsec3, DnsRecord::NSEC3 { flags, .. } if flags & NSEC3_OPT_OUT_FLAG != 0) && nsec3_covers(nsec3, &next_closer)
+    });
+    opt_out.then_some(NoDsProof::InsecureDelegation)
+}
+
+/// Check whether an NSEC record covers `name`: `name` sorts strictly between its owner and
+/// its next name, the last NSEC of the zone wrapping around to the apex
+fn nsec_covers(nsec: &DnsRecord, name: &str) -> bool {
+    let next = match nsec {
+        DnsRecord::NSEC { next_domain, .. } => next_domain,
+        _ => return false,
+    };
+    let owner = nsec.domain();
+    let after_owner = canonical_name_cmp(owner, name).is_lt();
+    let before_next = canonical_name_cmp(name, next).is_lt();
+    match canonical_name_cmp(owner, next).is_lt() {
+        true => after_owner && before_next,
+        false => after_owner || before_next,
+    }
+}
+
+/// NXDOMAIN proof with NSEC records: the name is covered, and so is the wildcard of its
+/// closest encloser (RFC 4035 §5.4)
+fn nsec_proves_nxdomain(nsecs: &[&DnsRecord], qname: &str) -> bool {
+    let covering = match nsecs.iter().find(|nsec| nsec_covers(nsec, qname)) {
+        Some(covering) => covering,
+        None => return false,
+    };
+
+    // The closest encloser is the longest ancestor of the name shared with the owner
+    // or the next name of the covering NSEC
+    let next = match covering {
+        DnsRecord::NSEC { next_domain, .. } => next_domain.as_str(),
+        _ => return false,
+    };
+    let encloser = [covering.domain(), next]
+        .iter()
+        .map(|name| common_ancestor(qname, name))
+        .max_by_key(|name| label_count(name))
+        .unwrap_or_default();
+    let wildcard = wildcard_of(&encloser);
+    nsecs.iter().any(|nsec| nsec_covers(nsec, &wildcard))
+}
+
+/// NODATA proof with NSEC records (RFC 4035 §5.4)
+fn nsec_proves_nodata(nsecs: &[&DnsRecord], qname: &str, qtype: RecordType) -> bool {
+    let lacks = |types: &[RecordType]| !types.contains(&qtype) && !types.contains(&RecordType::CNAME);
+    nsecs.iter().any(|nsec| match nsec {
+        // The NSEC of the name itself does not list the type
+        DnsRecord::NSEC { domain, types, .. } if domain.eq_ignore_ascii_case(qname) => lacks(types),
+        // Empty non-terminal: the next name is below the name
+        DnsRecord::NSEC { next_domain, .. } if nsec_covers(nsec, qname) && is_proper_subdomain(next_domain, qname) => true,
+        // Wildcard NODATA: the name is covered and the wildcard has no record of the type
+        DnsRecord::NSEC { domain, types, .. } if domain.starts_with("*.") => {
+            let encloser = &domain[2..];
+            is_proper_subdomain(qname, encloser) && lacks(types) && nsecs.iter().any(|other| nsec_covers(other, qname))
+        }
+        _ => false,
+    })
+}
+
+/// NSEC3 records that cannot be used for a proof: unknown hash algorithm, or too many
+/// iterations. Such answers are treated as insecure (RFC 5155 §8.1, RFC 9276 §3.2).
+fn nsec3_unusable(nsec3s: &[&DnsRecord]) -> Option<Security> {
+    let unusable = nsec3s.iter().any(|nsec3| {
+        matches!(nsec3, DnsRecord::NSEC3 { hash_algorithm, iterations, .. } if *hash_algorithm != 1 || *iterations > MAX_NSEC3_ITERATIONS)
+    });
+    unusable.then_some(Security::Insecure)
+}
+
+/// NXDOMAIN proof with NSEC3 records: closest encloser proof, and the wildcard of the
+/// closest encloser is covered (RFC 5155 §8.4)
+fn nsec3_proves_nxdomain(nsec3s: &[&DnsRecord], qname: &str) -> Security {
+    let (encloser, next_closer) = match nsec3_closest_encloser(nsec3s, qname) {
+        Some(proof) => proof,
+        None => return Security::Bogus,
+    };
+    if !nsec3s.iter().any(|nsec3| nsec3_covers(nsec3, &wildcard_of(&encloser))) {
+        return Security::Bogus;
+    }
+    nsec3_opt_out_security(nsec3s, &next_closer)
+}
+
+/// NODATA proof with NSEC3 records (RFC 5155 §8.5 to §8.7)
+fn nsec3_proves_nodata(nsec3s: &[&DnsRecord], qname: &str, qtype: RecordType) -> Security {
+    let lacks = |types: &[RecordType]| !types.contains(&qtype) && !types.contains(&RecordType::CNAME);
+
+    // The NSEC3 of the name itself does not list the type
+    for nsec3 in nsec3s {
+        if let DnsRecord::NSEC3 { types, .. } = nsec3 {
+            if nsec3_matches(nsec3, qname) {
+                return if lacks(types) { Security::Secure } else { Security::Bogus };
+            }
+        }
+    }
+
+    let (encloser, next_closer) = match nsec3_closest_encloser(nsec3s, qname) {
+        Some(proof) => proof,
+        None => return Security::Bogus,
+    };
+
+    // Wildcard NODATA: the wildcard of the closest encloser has no record of the type
+    let wildcard = wildcard_of(&encloser);
+    for nsec3 in nsec3s {
+        if let DnsRecord::NSEC3 { types, .. } = nsec3 {
+            if nsec3_matches(nsec3, &wildcard) {
+                return if lacks(types) { Security::Secure } else { Security::Bogus };
+            }
+        }
+    }
+
+    // A DS query for an unsigned delegation covered by an Opt-Out NSEC3 record
+    if qtype == RecordType::DS {
+        return nsec3_opt_out_security(nsec3s, &next_closer);
+    }
+    Security::Bogus
+}
+
+/// A name covered by an Opt-Out NSEC3 record may be an unsigned delegation, so that
+/// the proof only makes the answer insecure
+fn nsec3_opt_out_security(nsec3s: &[&DnsRecord], next_closer: &str) -> Security {
+    let opt_out = nsec3s.iter().any(|nsec3| {
+        matches!(nsec3, DnsRecord::NSEC3 { flags, .. } if flags & NSEC3_OPT_OUT_FLAG != 0) && nsec3_covers(nsec3, next_closer)
+    });
+    if opt_out { Security::Insecure } else { Security::Secure }
+}
+
+/// Closest encloser proof (RFC 5155 §8.3): the longest existing ancestor of `qname` has a
+/// matching NSEC3 record, and the next closer name is covered. Returns both names.
+fn nsec3_closest_encloser(nsec3s: &[&DnsRecord], qname: &str) -> Option<(String, String)> {
+    let count = label_count(qname);
+    for labels in (0..count).rev() {
+        let encloser = ancestor(qname, labels);
+        let matching = nsec3s.iter().find(|nsec3| nsec3_matches(nsec3, &encloser));
+        if let Some(DnsRecord::NSEC3 { types, .. }) = matching {
+            // Names below a delegation or a DNAME cannot be proven from this zone
+            if types.contains(&RecordType::DNAME) || (types.contains(&RecordType::NS) && !types.contains(&RecordType::SOA)) {
+                return None;
+            }
+            let next_closer = ancestor(qname, labels + 1);
+            return nsec3s.iter().any(|nsec3| nsec3_covers(nsec3, &next_closer)).then_some((encloser, next_closer));
+        }
+    }
+    None
+}
+
+/// Check whether the owner hash of an NSEC3 record is the hash of `name`
+fn nsec3_matches(nsec3: &DnsRecord, name: &str) -> bool {
+    match (nsec3_owner_hash(nsec3), nsec3_hash_of(nsec3, name)) {
+        (Some(owner), Some(hash)) => owner == hash,
+        _ => false,
+    }
+}
+
+/// Check whether the hash of `name` sorts strictly between the owner hash and the next hash
+/// of an NSEC3 record, the last one of the zone wrapping around
+fn nsec3_covers(nsec3: &DnsRecord, name: &str) -> bool {
+    let next = match nsec3 {
+        DnsRecord::NSEC3 { next_hashed, .. } => next_hashed,
+        _ => return false,
+    };
+    match (nsec3_owner_hash(nsec3), nsec3_hash_of(nsec3, name)) {
+        (Some(owner), Some(hash)) => match owner < *next {
+            true => owner < hash && hash < *next,
+            false => owner < hash || hash < *next,
+        },
+        _ => false,
+    }
+}
+
+/// Hash of `name` with the parameters of an NSEC3 record, provided `name` is in its zone
+fn nsec3_hash_of(nsec3: &DnsRecord, name: &str) -> Option<Vec<u8>> {
+    let (salt, iterations) = match nsec3 {
+        DnsRecord::NSEC3 { salt, iterations, .. } => (salt, *iterations),
+        _ => return None,
+    };
+    let zone = nsec3.domain().split_once('.').map_or("", |(_, zone)| zone);
+    is_subdomain(name, zone).then(|| nsec3_hash(name, salt, iterations))
+}
+
+/// Hashed owner name of an NSEC3 record, decoded from the base32hex first label
+fn nsec3_owner_hash(nsec3: &DnsRecord) -> Option<Vec<u8>> {
+    let label = nsec3.domain().split('.').next()?;
+    decode_base32hex(label)
+}
+
+/// Iterated and salted SHA-1 hash of a name (RFC 5155 §5)
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut data = Vec::new();
+    write_canonical_name(&mut data, name);
+    let mut hash = data;
+    for _ in 0..=iterations {
+        hash.extend_from_slice(salt);
+        hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &hash).as_ref().to_vec();
+    }
+    hash
+}
+
+/// Decode the base32 encoding with extended hex alphabet used by NSEC3 owner names (RFC 4648 §7)
+fn decode_base32hex(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.chars() {
+        let value = c.to_digit(32)?;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+fn decode_hex(text: &str) -> Result<Vec<u8>, SimpleError> {
+    if !text.len().is_multiple_of(2) {
+        bail!("Odd number of hexadecimal digits");
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&text[index..index + 2], 16).map_err(|e| SimpleError::with("Invalid hexadecimal digit", e)))
+        .collect()
+}
+
+fn strip_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+/// Serial number arithmetic (RFC 1982), as used by RRSIG timestamps
+fn serial_le(a: u32, b: u32) -> bool {
+    b.wrapping_sub(a) < 1 << 31
+}
+
+/// Number of labels of a name, the root having none
+fn label_count(name: &str) -> usize {
+    name.split('.').filter(|label| !label.is_empty()).count()
+}
+
+/// Ancestor of `name` made of its `labels` rightmost labels
+fn ancestor(name: &str, labels: usize) -> String {
+    let all: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+    all[all.len() - labels.min(all.len())..].join(".")
+}
+
+/// Owner name a record signed with `labels` labels was expanded from: the name itself,
+/// or a wildcard when the name has more labels (RFC 4035 §5.3.2)
+fn wildcard_source(name: &str, labels: u8) -> String {
+    match label_count(name) > labels as usize {
+        true => wildcard_of(&ancestor(name, labels as usize)),
+        false => name.to_string(),
+    }
+}
+
+fn wildcard_of(name: &str) -> String {
+    match name.is_empty() {
+        true => "*".to_string(),
+        false => format!("*.{}", name),
+    }
+}
+
+/// Longest common ancestor of two names
+fn common_ancestor(a: &str, b: &str) -> String {
+    let a_labels: Vec<&str> = a.split('.').filter(|label| !label.is_empty()).rev().collect();
+    let b_labels: Vec<&str> = b.split('.').filter(|label| !label.is_empty()).rev().collect();
+    let common = a_labels.iter().zip(b_labels.iter()).take_while(|(x, y)| x.eq_ignore_ascii_case(y)).count();
+    ancestor(a, common)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Root key signing keys: KSK-2017 and KSK-2024
+    const ROOT_KSK_2017: &str = "AwEAAaz/tAm8yTn4Mfeh5eyI96WSVexTBAvkMgJzkKTOiW1vkIbzxeF3+/4RgWOq7HrxRixHlFlExOLAJr5emLvN7SWXgnLh4+B5xQlNVz8Og8kvArMtNROxVQuCaSnIDdD5LKyWbRd2n9WGe2R8PzgCmr3EgVLrjyBxWezF0jLHwVN8efS3rCj/EWgvIWgb9tarpVUDK/b58Da+sqqls3eNbuv7pr+eoZG+SrDK6nWeL3c6H5Apxz7LjVc1uTIdsIXxuOLYA4/ilBmSVIzuDWfdRUfhHdY6+cn8HFRm+2hM8AnXGXws9555KrUB5qihylGa8subX2Nn6UwNR1AkUTV74bU=";
+    const ROOT_KSK_2024: &str = "AwEAAa96jeuknZlaeSrvyAJj6ZHv28hhOKkx3rLGXVaC6rXTsDc449/cidltpkyGwCJNnOAlFNKF2jBosZBU5eeHspaQWOmOElZsjICMQMC3aeHbGiShvZsx4wMYSjH8e7Vrhbu6irwCzVBApESjbUdpWWmEnhathWu1jo+siFUiRAAxm9qyJNg/wOZqqzL/dL/q8PkcRU5oUKEpUge71M3ej2/7CPqpdVwuMoTvoB+ZOT4YeGyxMvHmbrxlFzGOHOijtzN+u1TQNatX2XBuzZNQ1K+s2CXkPIZo7s6JgZyvaBevYtxPvYLw4z9mR7K2vaF18UYH9Z9GNUUeayffKC73PYc=";
+
+    fn base64(text: &str) -> Vec<u8> {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut bytes = Vec::new();
+        let (mut buffer, mut bits) = (0u32, 0);
+        for c in text.bytes().filter(|c| *c != b'=' && !c.is_ascii_whitespace()) {
+            buffer = (buffer << 6) | ALPHABET.iter().position(|a| *a == c).unwrap() as u32;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+                buffer &= (1 << bits) - 1;
+            }
+        }
+        bytes
+    }
+
+    fn dnskey(domain: &str, algorithm: u8, public_key: &str) -> DnsRecord {
+        DnsRecord::DNSKEY { domain: domain.to_string(), flags: 257, protocol: 3, algorithm, public_key: base64(public_key), ttl: 3600 }
+    }
+
+    fn ds(domain: &str, key_tag: u16, algorithm: u8, digest_type: u8, digest: &str) -> DnsRecord {
+        DnsRecord::DS { domain: domain.to_string(), key_tag, algorithm, digest_type, digest: decode_hex(digest).unwrap(), ttl: 3600 }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn rrsig(domain: &str, type_covered: RecordType, algorithm: u8, labels: u8, expiration: u32, inception: u32, key_tag: u16, signer: &str, signature: &str) -> DnsRecord {
+        DnsRecord::RRSIG {
+            domain: domain.to_string(),
+            type_covered,
+            algorithm,
+            labels,
+            original_ttl: 3600,
+            expiration,
+            inception,
+            key_tag,
+            signer_name: signer.to_string(),
+            signature: base64(signature),
+            ttl: 3600,
+        }
+    }
+
+    fn rrset(records: &[DnsRecord]) -> RRset {
+        RRset::group(records).remove(0)
+    }
+
+    #[test]
+    fn root_key_tags_and_digests() {
+        let ksk_2017 = dnskey("", 8, ROOT_KSK_2017);
+        let ksk_2024 = dnskey("", 8, ROOT_KSK_2024);
+        assert_eq!(key_tag(&ksk_2017), 20326);
+        assert_eq!(key_tag(&ksk_2024), 38696);
+
+        let anchors = builtin_trust_anchors();
+        assert!(ds_matches(&anchors[0], &ksk_2017));
+        assert!(ds_matches(&anchors[1], &ksk_2024));
+        assert!(!ds_matches(&anchors[0], &ksk_2024));
+        assert!(!ds_matches(&anchors[1], &ksk_2017));
+
+        // Same key and tag under another owner name
+        let mut other = anchors[0].clone();
+        if let DnsRecord::DS { domain, .. } = &mut other {
+            *domain = "com".to_string();
+        }
+        assert!(!ds_matches(&other, &ksk_2017));
+    }
+
+    #[test]
+    fn ecdsa_p256_rfc6605() {
+        let key = dnskey("example.net", 13, "GojIhhXUN/u4v54ZQqGSnyhWJwaubCvTmeexv7bR6edbkrSqQpF64cYbcB7wNcP+e+MAnLr+Wi9xMWyQLc8NAA==");
+        assert_eq!(key_tag(&key), 55648);
+        assert!(ds_matches(&ds("example.net", 55648, 13, 2, "b4c8c1fe2e7477127b27115656ad6256f424625bf5c1e2770ce6d6e37df61d17"), &key));
+
+        let a = rrset(&[DnsRecord::A { domain: "www.example.net".to_string(), addr: "192.0.2.1".parse().unwrap(), ttl: 3600 }]);
+        let sig = rrsig("www.example.net", RecordType::A, 13, 3, 1284026679, 1281607479, 55648, "example.net",
+            "qx6wLYqmh+l9oCKTN6qIc+bw6ya+KJ8oMz0YP107epXAyGmt+3SNruPFKG7tZoLBLlUzGGus7ZwmwWep666VCw==");
+        assert!(verify_rrsig(&a, &sig, &key, 1282000000).is_ok());
+        assert!(verify_rrsig(&a, &sig, &key, 1290000000).is_err());
+    }
+
+    #[test]
+    fn ecdsa_p384_rfc6605() {
+        let key = dnskey("example.net", 14, "xKYaNhWdGOfJ+nPrL8/arkwf2EY3MDJ+SErKivBVSum1w/egsXvSADtNJhyem5RCOpgQ6K8X1DRSEkrbYQ+OB+v8/uX45NBwY8rp65F6Glur8I/mlVNgF6W/qTI37m40");
+        assert_eq!(key_tag(&key), 10771);
+        assert!(ds_matches(&ds("example.net", 10771, 14, 4, "72d7b62976ce06438e9c0bf319013cf801f09ecc84b8d7e9495f27e305c6a9b0563a9b5f4d288405c3008a946df983d6"), &key));
+
+        let a = rrset(&[DnsRecord::A { domain: "www.example.net".to_string(), addr: "192.0.2.1".parse().unwrap(), ttl: 3600 }]);
+        let sig = rrsig("www.example.net", RecordType::A, 14, 3, 1284027625, 1281608425, 10771, "example.net",
+            "/L5hDKIvGDyI1fcARX3z65qrmPsVz73QD1Mr5CEqOiLP95hxQouuroGCeZOvzFaxsT8Glr74hbavRKayJNuydCuzWTSSPdz7wnqXL5bdcJzusdnI0RSMROxxwGipWcJm");
+        assert!(verify_rrsig(&a, &sig, &key, 1282000000).is_ok());
+    }
+
+    #[test]
+    fn ed25519_rfc8080() {
+        let mx = rrset(&[DnsRecord::MX { domain: "example.com".to_string(), host: "mail.example.com".to_string(), priority: 10, ttl: 3600 }]);
+        let vectors = [
+            ("l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=", 3613, "3aa5ab37efce57f737fc1627013fee07bdf241bd10f3b1964ab55c78e79a304b",
+             "oL9krJun7xfBOIWcGHi7mag5/hdZrKWw15jPGrHpjQeRAvTdszaPD+QLs3fx8A4M3e23mRZ9VrbpMngwcrqNAg=="),
+            ("zPnZ/QwEe7S8C5SPz2OfS5RR40ATk2/rYnE9xHIEijs=", 35217, "401781b934e392de492ec77ae2e15d70f6575a1c0bc59c5275c04ebe80c6614c",
+             "zXQ0bkYgQTEFyfLyi9QoiY6D8ZdYo4wyUhVioYZXFdT410QPRITQSqJSnzQoSm5poJ7gD7AQR0O7KuI5k2pcBg=="),
+        ];
+        for (public_key, tag, digest, signature) in vectors {
+            let key = dnskey("example.com", 15, public_key);
+            assert_eq!(key_tag(&key), tag);
+            assert!(ds_matches(&ds("example.com", tag, 15, 2, digest), &key));
+            let sig = rrsig("example.com", RecordType::MX, 15, 2, 1440021600, 1438207200, tag, "example.com", signature);
+            assert!(verify_rrsig(&mx, &sig, &key, 1439000000).is_ok());
+        }
+    }
+
+    #[test]
+    fn nsec3_hash_rfc5155() {
+        let salt = decode_hex("aabbccdd").unwrap();
+        let vectors = [
+            ("example", "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"),
+            ("a.example", "35mthgpgcu1qg68fab165klnsnk3dpvl"),
+            ("ai.example", "gjeqe526plbf1g8mklp59enfd789njgi"),
+            ("ns1.example", "2t7b4g4vsa5smi47k61mv5bv1a22bojr"),
+            ("ns2.example", "q04jkcevqvmu85r014c7dkba38o0ji5r"),
+            ("w.example", "k8udemvp1j2f7eg6jebps17vp3n8i58h"),
+            ("*.w.example", "r53bq7cc2uvmubfu5ocmm6pers9tk9en"),
+            ("x.w.example", "b4um86eghhds6nea196smvmlo4ors995"),
+            ("y.w.example", "ji6neoaepv8b5o6k4ev33abha8ht9fgc"),
+            ("x.y.w.example", "2vptu5timamqttgl4luu9kg21e0aor3s"),
+            ("xx.example", "t644ebqk9bibcna874givr6joj62mlhv"),
+        ];
+        for (name, hash) in vectors {
+            assert_eq!(nsec3_hash(name, &salt, 12), decode_base32hex(hash).unwrap(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rsa_sha256() {
+        // 1024 bits key and signature made with OpenSSL: ring rejects the 512 bits key of RFC 5702
+        let key = dnskey("example.net", 8, "AwEAAblNLluEyHxAJE0ZcPzPOU2DbuAdY5+KrDVLh+yTyDbvwQTPsofissoR0PABT6igSd9023l2Z/GIL6Ln5s1x4ZedoKbkf7INklfMZxsssE9S5EvFmI7NMw0KQl4CYd6b6SZcyo7Uc0gtKSaXomxh5lk8ECMY759CaQtodxxhqJOP");
+        let a = rrset(&[DnsRecord::A { domain: "www.example.net".to_string(), addr: "192.0.2.1".parse().unwrap(), ttl: 3600 }]);
+        let sig = rrsig("www.example.net", RecordType::A, 8, 3, 1893456000, 1262304000, 60535, "example.net",
+            "UOunArhxBME9GkJQ04+r3FlB2KsVBT5EflDa/grPiMGl8OyvwRTMwgVvtO9YJ85rsyxtQMJOWedYExsBEhBmItbe79K+1mh2d7ac8Wpn2G8Ube4tKoyyMW58UkqdbxHdEmty3YXITEIETX3TVLxMPRouIGfVbFx4jqy/LgcXqwk=");
+        assert!(verify_rrsig(&a, &sig, &key, 1700000000).is_ok());
+
+        // Other data, or a key of another owner, do not verify
+        let other = rrset(&[DnsRecord::A { domain: "www.example.net".to_string(), addr: "192.0.2.2".parse().unwrap(), ttl: 3600 }]);
+        assert!(verify_rrsig(&other, &sig, &key, 1700000000).is_err());
+        let mut renamed = key.clone();
+        if let DnsRecord::DNSKEY { domain, .. } = &mut renamed {
+            *domain = "example.org".to_string();
+        }
+        assert!(verify_rrsig(&a, &sig, &renamed, 1700000000).is_err());
+    }
+
+    fn nsec(domain: &str, next_domain: &str, types: &[RecordType]) -> DnsRecord {
+        DnsRecord::NSEC { domain: domain.to_string(), next_domain: next_domain.to_string(), types: types.to_vec(), ttl: 3600 }
+    }
+
+    /// NSEC chain of a zone with the names example, a.example and d.example
+    fn nsec_chain() -> Vec<DnsRecord> {
+        vec![
+            nsec("example", "a.example", &[RecordType::NS, RecordType::SOA, RecordType::RRSIG, RecordType::NSEC, RecordType::DNSKEY]),
+            nsec("a.example", "d.example", &[RecordType::A, RecordType::RRSIG, RecordType::NSEC]),
+            nsec("d.example", "example", &[RecordType::A, RecordType::MX, RecordType::RRSIG, RecordType::NSEC]),
+        ]
+    }
+
+    #[test]
+    fn nsec_nxdomain_proof() {
+        let chain = nsec_chain();
+        assert_eq!(prove_denial(&chain[..2], "b.example", RecordType::A, true), Security::Secure);
+        // The name is covered, but nothing denies the wildcard
+        assert_eq!(prove_denial(&chain[1..2], "b.example", RecordType::A, true), Security::Bogus);
+    }
+
+    #[test]
+    fn nsec_nodata_proof() {
+        let chain = nsec_chain();
+        assert_eq!(prove_denial(&chain[1..2], "a.example", RecordType::MX, false), Security::Secure);
+        // The type exists at the name
+        assert_eq!(prove_denial(&chain[1..2], "a.example", RecordType::A, false), Security::Bogus);
+    }
+
+    fn encode_base32hex(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+        let mut text = String::new();
+        let (mut buffer, mut bits) = (0u32, 0);
+        for byte in bytes {
+            buffer = (buffer << 8) | *byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                text.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+            }
+        }
+        text
+    }
+
+    /// NSEC3 chain of the zone of RFC 5155 Appendix A
+    fn appendix_a_zone() -> Vec<DnsRecord> {
+        let names: [(&str, &[RecordType]); 11] = [
+            ("example", &[RecordType::NS, RecordType::SOA, RecordType::MX, RecordType::RRSIG, RecordType::DNSKEY]),
+            ("a.example", &[RecordType::NS, RecordType::DS, RecordType::RRSIG]),
+            ("ai.example", &[RecordType::A, RecordType::AAAA, RecordType::RRSIG]),
+            ("ns1.example", &[RecordType::A, RecordType::RRSIG]),
+            ("ns2.example", &[RecordType::A, RecordType::RRSIG]),
+            ("w.example", &[]),
+            ("*.w.example", &[RecordType::MX, RecordType::RRSIG]),
+            ("x.w.example", &[RecordType::MX, RecordType::RRSIG]),
+            ("y.w.example", &[]),
+            ("x.y.w.example", &[RecordType::MX, RecordType::RRSIG]),
+            ("xx.example", &[RecordType::A, RecordType::RRSIG]),
+        ];
+        let salt = decode_hex("aabbccdd").unwrap();
+        let mut hashes: Vec<(Vec<u8>, &[RecordType])> = names.iter().map(|(name, types)| (nsec3_hash(name, &salt, 12), *types)).collect();
+        hashes.sort();
+        (0..hashes.len())
+            .map(|index| DnsRecord::NSEC3 {
+                domain: format!("{}.example", encode_base32hex(&hashes[index].0)),
+                hash_algorithm: 1,
+                flags: 0,
+                iterations: 12,
+                salt: salt.clone(),
+                next_hashed: hashes[(index + 1) % hashes.len()].0.clone(),
+                types: hashes[index].1.to_vec(),
+                ttl: 3600,
+            })
+            .collect()
+    }
+
+    /// Records of `zone` matching or covering one of `names`
+    fn nsec3_proof(zone: &[DnsRecord], names: &[&str]) -> Vec<DnsRecord> {
+        zone.iter()
+            .filter(|nsec3| names.iter().any(|name| nsec3_matches(nsec3, name) || nsec3_covers(nsec3, name)))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn nsec3_nxdomain_proof() {
+        let zone = appendix_a_zone();
+        // Closest encloser x.w.example, next closer c.x.w.example and wildcard *.x.w.example
+        let proof = nsec3_proof(&zone, &["x.w.example", "c.x.w.example", "*.x.w.example"]);
+        assert_eq!(prove_denial(&proof, "a.c.x.w.example", RecordType::A, true), Security::Secure);
+        let no_wildcard = nsec3_proof(&zone, &["x.w.example", "c.x.w.example"]);
+        assert_eq!(prove_denial(&no_wildcard, "a.c.x.w.example", RecordType::A, true), Security::Bogus);
+    }
+
+    #[test]
+    fn nsec3_nodata_proof() {
+        let zone = appendix_a_zone();
+        let proof = nsec3_proof(&zone, &["ns1.example"]);
+        assert_eq!(prove_denial(&proof, "ns1.example", RecordType::MX, false), Security::Secure);
+        assert_eq!(prove_denial(&proof, "ns1.example", RecordType::A, false), Security::Bogus);
+    }
+}
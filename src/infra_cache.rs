@@ -0,0 +1,286 @@
+//! Infrastructure cache: smoothed round trip time and failures of the name servers,
+//! used to favour the fast ones when picking the server to query
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use simple_error::SimpleError;
+use crate::random::{random_below, random_u32, shuffle};
+
+/// RTT assumed for a server never queried, low enough for it to be tried early (as in Unbound)
+const UNKNOWN_SERVER_RTT: u32 = 376;
+/// Servers within this many milliseconds of the fastest one are picked at random among themselves
+const RTT_BAND: u32 = 400;
+/// Upper bound of the smoothed RTT, in milliseconds
+const MAX_RTT: u32 = 120_000;
+/// Consecutive failures after which a server is only tried once the others failed
+const MAX_FAILURES: u32 = 3;
+/// One selection out of this many puts a server picked at random first, so that
+/// the estimates of the slow servers get refreshed
+const EXPLORATION_RATIO: u32 = 20;
+/// Consecutive responses echoing the query name in another case after which a server is
+/// queried without DNS 0x20
+const MAX_CASE_MISMATCHES: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// What is known about a name server
+pub struct ServerStats {
+    /// Smoothed round trip time, in milliseconds
+    pub srtt: u32,
+    /// Number of consecutive queries left unanswered
+    pub failures: u32,
+    /// Number of consecutive queries over UDP left unanswered, answers over TCP aside
+    pub udp_failures: u32,
+    /// Number of consecutive responses that did not preserve the case of the query name
+    pub case_mismatches: u32,
+    /// Until then, the case of the query names sent to the server is not randomized
+    pub no_0x20_until: Option<Instant>,
+    updated: Instant,
+}
+
+impl ServerStats {
+    fn new() -> ServerStats {
+        ServerStats {
+            srtt: UNKNOWN_SERVER_RTT,
+            failures: 0,
+            udp_failures: 0,
+            case_mismatches: 0,
+            no_0x20_until: None,
+            updated: Instant::now(),
+        }
+    }
+}
+
+/// Statistics of the name servers keyed by address, forgotten after `ttl` so that
+/// servers which were slow or down get another chance
+pub struct InfraCache {
+    servers: HashMap<IpAddr, ServerStats>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl InfraCache {
+    pub fn new(capacity: usize, ttl: Duration) -> InfraCache {
+        InfraCache {
+            servers: HashMap::new(),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Statistics of a server, if they are still fresh
+    pub fn get(&self, server: &IpAddr) -> Option<ServerStats> {
+        self.servers
+            .get(server)
+            .filter(|stats| stats.updated.elapsed() < self.ttl)
+            .copied()
+    }
+
+    /// Smoothed RTT of a server, in milliseconds
+    pub fn srtt(&self, server: &IpAddr) -> u32 {
+        self.get(server).map_or(UNKNOWN_SERVER_RTT, |stats| stats.srtt)
+    }
+
+    /// Record the time a server took to answer: the smoothed RTT moves by 1/8 of the
+    /// difference (RFC 6298), and the failures are cleared
+    pub fn record_rtt(&mut self, server: IpAddr, rtt: Duration) {
+        let rtt = rtt.as_millis().min(MAX_RTT as u128) as u32;
+        let mut stats = self.get(&server).unwrap_or(ServerStats { srtt: rtt, ..ServerStats::new() });
+        stats.srtt = (stats.srtt * 7 + rtt) / 8;
+        stats.failures = 0;
+        self.update(server, stats);
+    }
+
+    /// Record a query left unanswered: the smoothed RTT is doubled, like a retransmission timeout
+    pub fn record_failure(&mut self, server: IpAddr) {
+        let mut stats = self.get(&server).unwrap_or_else(ServerStats::new);
+        stats.srtt = stats.srtt.saturating_mul(2).min(MAX_RTT);
+        stats.failures += 1;
+        self.update(server, stats);
+    }
+
+    /// Record whether a query over UDP got a response, so that a server unreachable over UDP
+    /// can be queried over TCP
+    pub fn record_udp(&mut self, server: IpAddr, answered: bool) {
+        let mut stats = self.get(&server).unwrap_or_else(ServerStats::new);
+        stats.udp_failures = if answered { 0 } else { stats.udp_failures + 1 };
+        self.update(server, stats);
+    }
+
+    /// Record whether a response preserved the randomized case of the query name. A server
+    /// that keeps changing it is queried without DNS 0x20 for the TTL of the cache.
+    pub fn record_case(&mut self, server: IpAddr, preserved: bool) {
+        let mut stats = self.get(&server).unwrap_or_else(ServerStats::new);
+        stats.case_mismatches = if preserved { 0 } else { stats.case_mismatches + 1 };
+        if stats.case_mismatches >= MAX_CASE_MISMATCHES {
+            stats.case_mismatches = 0;
+            stats.no_0x20_until = Some(Instant::now() + self.ttl);
+        }
+        self.update(server, stats);
+    }
+
+    /// Check whether the case of the query names sent to a server is to be randomized
+    pub fn uses_0x20(&self, server: &IpAddr) -> bool {
+        let until = self.get(server).and_then(|stats| stats.no_0x20_until);
+        until.is_none_or(|until| until <= Instant::now())
+    }
+
+    /// Order candidate servers, most preferred first. The servers whose smoothed RTT is within
+    /// `RTT_BAND` of the fastest come first in random order, the others follow by increasing
+    /// smoothed RTT and the failing ones last. Once in a while a random server is put first.
+    pub fn order(&self, servers: &[IpAddr]) -> Result<Vec<IpAddr>, SimpleError> {
+        let failing = |server: &IpAddr| self.get(server).is_some_and(|stats| stats.failures >= MAX_FAILURES);
+        let mut ordered = servers.to_vec();
+        shuffle(&mut ordered)?;
+        ordered.sort_by_key(|server| (failing(server), self.srtt(server)));
+
+        // Failing servers stay last, even when their RTT falls within the band
+        let best = ordered.first().map_or(0, |server| self.srtt(server));
+        let band = ordered
+            .iter()
+            .take_while(|server| !failing(server) && self.srtt(server) <= best.saturating_add(RTT_BAND))
+            .count();
+        shuffle(&mut ordered[..band])?;
+
+        if ordered.len() > 1 && random_u32()?.is_multiple_of(EXPLORATION_RATIO) {
+            let explored = random_below(ordered.len() as u32)? as usize;
+            ordered[..=explored].rotate_right(1);
+        }
+        Ok(ordered)
+    }
+
+    /// Drop the statistics older than the TTL
+    pub fn remove_expired(&mut self) {
+        let ttl = self.ttl;
+        self.servers.retain(|_, stats| stats.updated.elapsed() < ttl);
+    }
+
+    fn update(&mut self, server: IpAddr, mut stats: ServerStats) {
+        stats.updated = Instant::now();
+        if self.capacity == 0 {
+            return;
+        }
+        if !self.servers.contains_key(&server) && self.servers.len() >= self.capacity {
+            self.remove_expired();
+            if self.servers.len() >= self.capacity {
+                return;
+            }
+        }
+        self.servers.insert(server, stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    const SERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn server(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    #[test]
+    fn rtt_is_smoothed() {
+        let mut infra = InfraCache::new(10, Duration::from_secs(60));
+        assert_eq!(infra.srtt(&SERVER), UNKNOWN_SERVER_RTT);
+        infra.record_rtt(SERVER, Duration::from_millis(100));
+        assert_eq!(infra.srtt(&SERVER), 100);
+        infra.record_rtt(SERVER, Duration::from_millis(180));
+        assert_eq!(infra.srtt(&SERVER), 110);
+
+        infra.record_failure(SERVER);
+        assert_eq!(infra.srtt(&SERVER), 220);
+        assert_eq!(infra.get(&SERVER).unwrap().failures, 1);
+        // An answer clears the failures
+        infra.record_rtt(SERVER, Duration::from_millis(220));
+        assert_eq!(infra.get(&SERVER).unwrap().failures, 0);
+    }
+
+    #[test]
+    fn fast_servers_come_first() {
+        let mut infra = InfraCache::new(10, Duration::from_secs(60));
+        infra.record_rtt(server(1), Duration::from_millis(2000));
+        infra.record_rtt(server(2), Duration::from_millis(20));
+        infra.record_rtt(server(3), Duration::from_millis(900));
+        let servers = [server(1), server(2), server(3)];
+
+        // A random server is put first once in a while, so only most orderings are checked
+        let expected = [server(2), server(3), server(1)];
+        let ordered = (0..100).filter(|_| infra.order(&servers).unwrap() == expected).count();
+        assert!(ordered >= 75, "{} orderings by RTT out of 100", ordered);
+    }
+
+    #[test]
+    fn servers_within_the_band_are_shuffled() {
+        let mut infra = InfraCache::new(10, Duration::from_secs(60));
+        infra.record_rtt(server(1), Duration::from_millis(20));
+        infra.record_rtt(server(2), Duration::from_millis(20 + RTT_BAND as u64));
+        let servers = [server(1), server(2)];
+
+        let first = (0..200).filter(|_| infra.order(&servers).unwrap()[0] == server(1)).count();
+        assert!((50..150).contains(&first), "{} out of 200 first", first);
+    }
+
+    #[test]
+    fn failing_servers_come_last() {
+        let mut infra = InfraCache::new(10, Duration::from_secs(60));
+        infra.record_rtt(server(1), Duration::from_millis(MAX_RTT as u64));
+        for _ in 0..MAX_FAILURES {
+            infra.record_failure(server(2));
+        }
+        assert!(infra.srtt(&server(2)) < infra.srtt(&server(1)));
+
+        let servers = [server(2), server(1)];
+        let last = (0..100).filter(|_| infra.order(&servers).unwrap()[1] == server(2)).count();
+        assert!(last >= 75, "failing server last {} times out of 100", last);
+    }
+
+    #[test]
+    fn statistics_expire() {
+        let mut infra = InfraCache::new(10, Duration::from_millis(20));
+        infra.record_rtt(SERVER, Duration::from_millis(5000));
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(infra.srtt(&SERVER), UNKNOWN_SERVER_RTT);
+        infra.remove_expired();
+        assert!(infra.servers.is_empty());
+    }
+
+    #[test]
+    fn repeated_case_mismatches_disable_0x20() {
+        let mut infra = InfraCache::new(10, Duration::from_secs(60));
+        assert!(infra.uses_0x20(&SERVER));
+        for _ in 1..MAX_CASE_MISMATCHES {
+            infra.record_case(SERVER, false);
+        }
+        // A single preserved case starts the count again
+        infra.record_case(SERVER, true);
+        for _ in 1..MAX_CASE_MISMATCHES {
+            infra.record_case(SERVER, false);
+        }
+        assert!(infra.uses_0x20(&SERVER));
+        infra.record_case(SERVER, false);
+        assert!(!infra.uses_0x20(&SERVER));
+
+        // Answers over the same period do not extend the downgrade
+        infra.record_rtt(SERVER, Duration::from_millis(20));
+        assert!(!infra.uses_0x20(&SERVER));
+    }
+
+    #[test]
+    fn disabled_0x20_expires() {
+        let mut infra = InfraCache::new(10, Duration::from_millis(50));
+        for _ in 0..MAX_CASE_MISMATCHES {
+            infra.record_case(SERVER, false);
+        }
+        assert!(!infra.uses_0x20(&SERVER));
+        thread::sleep(Duration::from_millis(30));
+        infra.record_rtt(SERVER, Duration::from_millis(20));
+        thread::sleep(Duration::from_millis(30));
+        assert!(infra.get(&SERVER).is_some());
+        assert!(infra.uses_0x20(&SERVER));
+    }
+}
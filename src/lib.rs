@@ -0,0 +1,25 @@
+//! A simple DNS library: packet codec, recursive resolver and the request handler used by the server binary
+#[macro_use]
+extern crate simple_error;
+
+pub mod byte_packet_buffer;
+pub mod cache;
+pub mod dns_packet;
+pub mod dnssec;
+pub mod infra_cache;
+pub mod pcap;
+mod random;
+pub mod resolver;
+pub mod root_hints;
+pub mod server;
+pub mod tcp;
+
+pub use byte_packet_buffer::*;
+pub use cache::*;
+pub use dns_packet::*;
+pub use dnssec::*;
+pub use infra_cache::*;
+pub use resolver::*;
+pub use root_hints::*;
+pub use server::*;
+pub use tcp::*;
@@ -0,0 +1,927 @@
+//! Read DNS messages out of pcap and pcapng captures, and write DNS packets as pcap files
+//!
+//! Messages are extracted from UDP datagrams and TCP streams on any port. TCP segments are
+//! reassembled per flow before the two bytes length prefixed messages are decoded.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+use simple_error::SimpleError;
+use crate::{BytePacketBuffer, DnsPacket, MAX_MESSAGE_SIZE};
+
+/// Magic number of a pcap file with microsecond timestamps
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2c3d4;
+/// Magic number of a pcap file with nanosecond timestamps
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+/// Type of the pcapng section header block, also used to detect the format
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+/// Byte order magic of a pcapng section header block
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_OBSOLETE_PACKET: u32 = 2;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+/// Option of the interface description block giving the timestamp resolution
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+const LINKTYPE_NULL: u16 = 0;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_LOOP: u16 = 108;
+const LINKTYPE_LINUX_SLL: u16 = 113;
+const LINKTYPE_IPV4: u16 = 228;
+const LINKTYPE_IPV6: u16 = 229;
+const LINKTYPE_LINUX_SLL2: u16 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+/// Transport protocol a message was captured on
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+#[derive(Clone, Debug)]
+/// DNS message extracted from a capture
+pub struct CapturedMessage {
+    /// Capture time, since the UNIX epoch
+    pub timestamp: Duration,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub transport: Transport,
+    pub packet: DnsPacket,
+}
+
+/// Read every DNS message of a pcap or pcapng file
+pub fn read_capture_file<P: AsRef<Path>>(path: P) -> Result<Vec<CapturedMessage>, SimpleError> {
+    let file = File::open(path).map_err(|e| SimpleError::with("Error opening capture file", e))?;
+    read_capture(file)
+}
+
+/// Read every DNS message of a pcap or pcapng capture. The format is detected from the magic number.
+pub fn read_capture<R: Read>(mut reader: R) -> Result<Vec<CapturedMessage>, SimpleError> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).map_err(|e| SimpleError::with("Error reading capture", e))?;
+
+    let frames = match read_u32(&data, 0, false) {
+        Ok(PCAPNG_SECTION_HEADER) => read_pcapng_frames(&data)?,
+        Ok(_) => read_pcap_frames(&data)?,
+        Err(_) => bail!("Capture is too short"),
+    };
+
+    let mut extractor = Extractor::default();
+    for frame in &frames {
+        extractor.add_frame(frame);
+    }
+    Ok(extractor.finish())
+}
+
+/// Link layer frame read from a capture
+struct Frame<'a> {
+    timestamp: Duration,
+    link_type: u16,
+    data: &'a [u8],
+}
+
+/// Split a classic pcap file into frames. A truncated last record is ignored.
+fn read_pcap_frames(data: &[u8]) -> Result<Vec<Frame<'_>>, SimpleError> {
+    // The magic number is written in the byte order of the host that wrote the file
+    let magic = read_u32(data, 0, false)?;
+    let (big_endian, nanos) = match magic {
+        PCAP_MAGIC_MICROS => (false, false),
+        PCAP_MAGIC_NANOS => (false, true),
+        _ if magic.swap_bytes() == PCAP_MAGIC_MICROS => (true, false),
+        _ if magic.swap_bytes() == PCAP_MAGIC_NANOS => (true, true),
+        _ => bail!("Unknown capture format"),
+    };
+    let link_type = (read_u32(data, 20, big_endian)? & 0xffff) as u16;
+
+    let mut frames = Vec::new();
+    let mut pos = 24;
+    while pos + 16 <= data.len() {
+        let seconds = read_u32(data, pos, big_endian)? as u64;
+        let fraction = read_u32(data, pos + 4, big_endian)?;
+        let captured_len = read_u32(data, pos + 8, big_endian)? as usize;
+        // A capture cut off while being written ends with a partial record, keep what precedes it
+        let frame = match slice(data, pos + 16, captured_len) {
+            Ok(frame) => frame,
+            Err(_) => break,
+        };
+        let timestamp = if nanos {
+            Duration::new(seconds, fraction)
+        } else {
+            Duration::new(seconds, 0) + Duration::from_micros(fraction as u64)
+        };
+
+        frames.push(Frame { timestamp, link_type, data: frame });
+        pos += 16 + captured_len;
+    }
+    Ok(frames)
+}
+
+/// Interface described in a pcapng section
+struct Interface {
+    link_type: u16,
+    /// Number of timestamp units per second
+    units_per_second: u64,
+}
+
+/// Split a pcapng file into frames. A truncated last block is ignored.
+fn read_pcapng_frames(data: &[u8]) -> Result<Vec<Frame<'_>>, SimpleError> {
+    let mut frames = Vec::new();
+    let mut interfaces: Vec<Interface> = Vec::new();
+    let mut big_endian = false;
+    let mut pos = 0;
+
+    while pos + 12 <= data.len() {
+        let block_type = read_u32(data, pos, big_endian)?;
+
+        // A section header block resets the byte order and the interfaces
+        if block_type == PCAPNG_SECTION_HEADER {
+            big_endian = match read_u32(data, pos + 8, true)? {
+                PCAPNG_BYTE_ORDER_MAGIC => true,
+                magic if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => false,
+                _ => bail!("Invalid pcapng byte order magic"),
+            };
+            interfaces.clear();
+        }
+
+        let block_len = read_u32(data, pos + 4, big_endian)? as usize;
+        if block_len < 12 || !block_len.is_multiple_of(4) {
+            bail!("Invalid pcapng block length");
+        }
+        // A capture cut off while being written ends with a partial block, keep what precedes it
+        let body = match slice(data, pos + 8, block_len - 12) {
+            Ok(body) => body,
+            Err(_) => break,
+        };
+
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                let link_type = read_u16(body, 0, big_endian)?;
+                let units_per_second = read_tsresol(slice(body, 8, body.len().saturating_sub(8))?, big_endian)?;
+                interfaces.push(Interface { link_type, units_per_second });
+            }
+            PCAPNG_ENHANCED_PACKET | PCAPNG_OBSOLETE_PACKET => {
+                // The obsolete packet block stores a 16 bits interface id followed by a drop count
+                let interface_id = if block_type == PCAPNG_ENHANCED_PACKET {
+                    read_u32(body, 0, big_endian)? as usize
+                } else {
+                    read_u16(body, 0, big_endian)? as usize
+                };
+                let interface = match interfaces.get(interface_id) {
+                    Some(interface) => interface,
+                    None => bail!("Packet references an unknown interface"),
+                };
+                let units = ((read_u32(body, 4, big_endian)? as u64) << 32) | read_u32(body, 8, big_endian)? as u64;
+                let captured_len = read_u32(body, 12, big_endian)? as usize;
+                frames.push(Frame {
+                    timestamp: units_to_duration(units, interface.units_per_second),
+                    link_type: interface.link_type,
+                    data: slice(body, 20, captured_len)?,
+                });
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                // Simple packets always belong to the first interface and have no timestamp
+                let interface = match interfaces.first() {
+                    Some(interface) => interface,
+                    None => bail!("Packet references an unknown interface"),
+                };
+                let original_len = read_u32(body, 0, big_endian)? as usize;
+                let captured_len = original_len.min(body.len().saturating_sub(4));
+                frames.push(Frame {
+                    timestamp: Duration::ZERO,
+                    link_type: interface.link_type,
+                    data: slice(body, 4, captured_len)?,
+                });
+            }
+            // Other blocks (name resolution, statistics, ...) are of no interest
+            _ => {}
+        }
+
+        pos += block_len;
+    }
+    Ok(frames)
+}
+
+/// Look for the timestamp resolution in the options of an interface description block
+fn read_tsresol(mut options: &[u8], big_endian: bool) -> Result<u64, SimpleError> {
+    // Default resolution is the microsecond
+    let mut units_per_second = 1_000_000;
+    while options.len() >= 4 {
+        let code = read_u16(options, 0, big_endian)?;
+        let len = read_u16(options, 2, big_endian)? as usize;
+        if code == 0 {
+            break;
+        }
+        if code == PCAPNG_OPTION_TSRESOL && len >= 1 {
+            let resolution = slice(options, 4, 1)?[0];
+            // The most significant bit tells whether the value is a power of two or of ten
+            let exponent = (resolution & 0x7f) as u32;
+            units_per_second = if resolution & 0x80 == 0 {
+                10u64.checked_pow(exponent)
+            } else {
+                2u64.checked_pow(exponent)
+            }.unwrap_or(1_000_000);
+        }
+        let padded_len = (len + 3) & !3;
+        options = &options[(4 + padded_len).min(options.len())..];
+    }
+    Ok(units_per_second)
+}
+
+/// Convert a timestamp expressed in units of the interface resolution
+fn units_to_duration(units: u64, units_per_second: u64) -> Duration {
+    let seconds = units / units_per_second;
+    let remainder = units % units_per_second;
+    let nanos = (remainder as u128 * 1_000_000_000 / units_per_second as u128) as u32;
+    Duration::new(seconds, nanos)
+}
+
+/// Transport segment carried by a frame
+struct Segment<'a> {
+    source: SocketAddr,
+    destination: SocketAddr,
+    protocol: u8,
+    /// TCP sequence number and flags, unused for UDP
+    sequence: u32,
+    syn: bool,
+    payload: &'a [u8],
+}
+
+/// Decode the link, network and transport layers of a frame
+fn decode_frame<'a>(frame: &Frame<'a>) -> Option<Segment<'a>> {
+    let data = frame.data;
+    let (ethertype, ip) = match frame.link_type {
+        LINKTYPE_ETHERNET => {
+            let mut pos = 12;
+            let mut ethertype = read_u16(data, pos, true).ok()?;
+            // Skip 802.1Q and 802.1ad tags
+            while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+                pos += 4;
+                ethertype = read_u16(data, pos, true).ok()?;
+            }
+            (Some(ethertype), data.get(pos + 2..)?)
+        }
+        LINKTYPE_LINUX_SLL => (Some(read_u16(data, 14, true).ok()?), data.get(16..)?),
+        LINKTYPE_LINUX_SLL2 => (Some(read_u16(data, 0, true).ok()?), data.get(20..)?),
+        // Loopback captures start with the address family, whose value depends on the OS
+        LINKTYPE_NULL | LINKTYPE_LOOP => (None, data.get(4..)?),
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => (None, data),
+        _ => return None,
+    };
+
+    let version = ip.first()? >> 4;
+    match (ethertype, version) {
+        (Some(ETHERTYPE_IPV4), 4) | (None, 4) => decode_ipv4(ip).ok().flatten(),
+        (Some(ETHERTYPE_IPV6), 6) | (None, 6) => decode_ipv6(ip),
+        _ => None,
+    }
+}
+
+/// Decode an IPv4 packet. Fragments are ignored, giving `None`. Fails when the packet is too
+/// short for its header.
+fn decode_ipv4(ip: &[u8]) -> Result<Option<Segment<'_>>, SimpleError> {
+    let header_len = ((slice(ip, 0, 1)?[0] & 0x0f) as usize) * 4;
+    if header_len < 20 {
+        bail!("Invalid IPv4 header length {}", header_len);
+    }
+    let header = slice(ip, 0, header_len)?;
+    let total_len = read_u16(header, 2, true)? as usize;
+    let fragment = read_u16(header, 6, true)?;
+    if fragment & 0x3fff != 0 {
+        return Ok(None);
+    }
+    let protocol = header[9];
+    let source = Ipv4Addr::new(header[12], header[13], header[14], header[15]);
+    let destination = Ipv4Addr::new(header[16], header[17], header[18], header[19]);
+    // Captures may add padding after the IP packet, trust the announced length when possible
+    let end = if total_len >= header_len { total_len.min(ip.len()) } else { ip.len() };
+    Ok(decode_transport(IpAddr::V4(source), IpAddr::V4(destination), protocol, &ip[header_len..end]))
+}
+
+/// Decode an IPv6 packet, skipping its extension headers. Fragments are ignored.
+fn decode_ipv6(ip: &[u8]) -> Option<Segment<'_>> {
+    let payload_len = read_u16(ip, 4, true).ok()? as usize;
+    let mut next_header = *ip.get(6)?;
+    let source = Ipv6Addr::from(<[u8; 16]>::try_from(ip.get(8..24)?).ok()?);
+    let destination = Ipv6Addr::from(<[u8; 16]>::try_from(ip.get(24..40)?).ok()?);
+
+    let end = (40 + payload_len).min(ip.len());
+    let mut pos = 40;
+    loop {
+        match next_header {
+            // Hop-by-hop, routing and destination options
+            0 | 43 | 60 => {
+                next_header = *ip.get(pos)?;
+                pos += (*ip.get(pos + 1)? as usize + 1) * 8;
+            }
+            // Fragment
+            44 => return None,
+            _ => break,
+        }
+    }
+    decode_transport(IpAddr::V6(source), IpAddr::V6(destination), next_header, ip.get(pos..end)?)
+}
+
+/// Decode a UDP or TCP header. TCP headers announcing less than the 20 bytes minimum are ignored.
+fn decode_transport(source: IpAddr, destination: IpAddr, protocol: u8, data: &[u8]) -> Option<Segment<'_>> {
+    let source_port = read_u16(data, 0, true).ok()?;
+    let destination_port = read_u16(data, 2, true).ok()?;
+    let (sequence, syn, payload) = match protocol {
+        PROTOCOL_UDP => (0, false, data.get(8..)?),
+        PROTOCOL_TCP => {
+            let sequence = read_u32(data, 4, true).ok()?;
+            let header_len = ((data.get(12)? >> 4) as usize) * 4;
+            if header_len < 20 {
+                return None;
+            }
+            let syn = data.get(13)? & 0x02 != 0;
+            (sequence, syn, data.get(header_len..)?)
+        }
+        _ => return None,
+    };
+    Some(Segment {
+        source: SocketAddr::new(source, source_port),
+        destination: SocketAddr::new(destination, destination_port),
+        protocol,
+        sequence,
+        syn,
+        payload,
+    })
+}
+
+/// Direction of a TCP connection
+type FlowKey = (SocketAddr, SocketAddr);
+
+#[derive(Default)]
+/// Data received in one direction of a TCP connection
+struct TcpFlow {
+    /// Sequence number of the first byte of the stream, known once a SYN is seen
+    initial_sequence: Option<u32>,
+    /// Segments as (timestamp, sequence number, payload)
+    segments: Vec<(Duration, u32, Vec<u8>)>,
+}
+
+impl TcpFlow {
+    /// Put the segments back in order and return the stream, along with the capture time
+    /// of the segment holding each byte range start
+    fn reassemble(mut self) -> (Vec<u8>, Vec<(usize, Duration)>) {
+        let base = match self.initial_sequence {
+            Some(sequence) => sequence,
+            None => match self.segments.iter().map(|(_, sequence, _)| *sequence).min_by_key(|sequence| {
+                // Without a SYN, start from the earliest sequence number, taking wrap around into account
+                sequence.wrapping_sub(self.segments[0].1).wrapping_add(1 << 31)
+            }) {
+                Some(sequence) => sequence,
+                None => return (Vec::new(), Vec::new()),
+            },
+        };
+        self.segments.sort_by_key(|(_, sequence, _)| sequence.wrapping_sub(base));
+
+        let mut stream = Vec::new();
+        let mut timestamps = Vec::new();
+        for (timestamp, sequence, payload) in self.segments {
+            let offset = sequence.wrapping_sub(base) as usize;
+            // Stop at the first hole, the rest of the stream cannot be decoded
+            if offset > stream.len() {
+                break;
+            }
+            // Retransmitted bytes are skipped
+            let skip = stream.len() - offset;
+            if skip < payload.len() {
+                timestamps.push((stream.len(), timestamp));
+                stream.extend_from_slice(&payload[skip..]);
+            }
+        }
+        (stream, timestamps)
+    }
+}
+
+#[derive(Default)]
+/// Collect DNS messages from decoded frames
+struct Extractor {
+    messages: Vec<CapturedMessage>,
+    flows: HashMap<FlowKey, TcpFlow>,
+    /// Order in which flows were first seen, to keep the output stable
+    flow_order: Vec<FlowKey>,
+}
+
+impl Extractor {
+    fn add_frame(&mut self, frame: &Frame) {
+        let segment = match decode_frame(frame) {
+            Some(segment) => segment,
+            None => return,
+        };
+
+        if segment.protocol == PROTOCOL_UDP {
+            // Any port may carry DNS, keep the datagrams that decode as a DNS message
+            if let Some(packet) = parse_message(segment.payload) {
+                self.messages.push(CapturedMessage {
+                    timestamp: frame.timestamp,
+                    source: segment.source,
+                    destination: segment.destination,
+                    transport: Transport::Udp,
+                    packet,
+                });
+            }
+            return;
+        }
+
+        let key = (segment.source, segment.destination);
+        if !self.flows.contains_key(&key) {
+            self.flow_order.push(key);
+        }
+        let flow = self.flows.entry(key).or_default();
+        if segment.syn {
+            // The SYN consumes one sequence number
+            flow.initial_sequence = Some(segment.sequence.wrapping_add(1));
+        }
+        if !segment.payload.is_empty() {
+            flow.segments.push((frame.timestamp, segment.sequence, segment.payload.to_vec()));
+        }
+    }
+
+    /// Decode the reassembled TCP streams and return all the messages, sorted by capture time
+    fn finish(mut self) -> Vec<CapturedMessage> {
+        for key in self.flow_order {
+            let flow = match self.flows.remove(&key) {
+                Some(flow) => flow,
+                None => continue,
+            };
+            let (stream, timestamps) = flow.reassemble();
+
+            // Each message is prefixed with its length on two bytes
+            let mut pos = 0;
+            while let Ok(len) = read_u16(&stream, pos, true) {
+                let message = match stream.get(pos + 2..pos + 2 + len as usize) {
+                    Some(message) => message,
+                    None => break,
+                };
+                let timestamp = timestamps
+                    .iter()
+                    .take_while(|(start, _)| *start <= pos)
+                    .last()
+                    .map(|(_, timestamp)| *timestamp)
+                    .unwrap_or_default();
+                match parse_message(message) {
+                    Some(packet) => self.messages.push(CapturedMessage {
+                        timestamp,
+                        source: key.0,
+                        destination: key.1,
+                        transport: Transport::Tcp,
+                        packet,
+                    }),
+                    // Not a DNS stream, give up on this flow
+                    None => break,
+                }
+                pos += 2 + len as usize;
+            }
+        }
+
+        self.messages.sort_by_key(|message| message.timestamp);
+        self.messages
+    }
+}
+
+/// Decode a DNS message, rejecting payloads that are obviously something else
+fn parse_message(data: &[u8]) -> Option<DnsPacket> {
+    if data.len() < 12 || data.len() > MAX_MESSAGE_SIZE {
+        return None;
+    }
+    let mut buffer = BytePacketBuffer::from_bytes(data);
+    let packet = DnsPacket::from_buffer(&mut buffer).ok()?;
+    // A DNS message fills the whole payload and uses one of the assigned opcodes
+    if buffer.pos() != data.len() || packet.header.opcode > 6 {
+        return None;
+    }
+    Some(packet)
+}
+
+/// Write DNS packets into a pcap file, each one in its own UDP datagram
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl PcapWriter<BufWriter<File>> {
+    /// Create a pcap file at `path`
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, SimpleError> {
+        let file = File::create(path).map_err(|e| SimpleError::with("Error creating capture file", e))?;
+        PcapWriter::new(BufWriter::new(file))
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the pcap header. Frames are raw IP packets, with microsecond timestamps.
+    pub fn new(mut writer: W) -> Result<Self, SimpleError> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC_MICROS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&(MAX_MESSAGE_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&(LINKTYPE_RAW as u32).to_le_bytes());
+        writer.write_all(&header).map_err(|e| SimpleError::with("Error writing capture", e))?;
+        Ok(PcapWriter { writer })
+    }
+
+    /// Encode a packet and append it as a UDP datagram sent from `source` to `destination`.
+    /// Both addresses must be of the same family.
+    pub fn write_packet(
+        &mut self,
+        timestamp: Duration,
+        source: SocketAddr,
+        destination: SocketAddr,
+        packet: &mut DnsPacket,
+    ) -> Result<(), SimpleError> {
+        let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+        packet.write(&mut buffer)?;
+        let payload = &buffer.buf[..buffer.pos()];
+
+        let frame = match (source.ip(), destination.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => build_ipv4_udp(src, dst, source.port(), destination.port(), payload),
+            (IpAddr::V6(src), IpAddr::V6(dst)) => build_ipv6_udp(src, dst, source.port(), destination.port(), payload),
+            _ => bail!("Source and destination must be of the same address family"),
+        };
+
+        let mut record = Vec::with_capacity(16 + frame.len());
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&frame);
+        self.writer.write_all(&record).map_err(|e| SimpleError::with("Error writing capture", e))
+    }
+
+    /// Flush and return the underlying writer
+    pub fn into_inner(mut self) -> Result<W, SimpleError> {
+        self.writer.flush().map_err(|e| SimpleError::with("Error writing capture", e))?;
+        Ok(self.writer)
+    }
+}
+
+/// Build a UDP header followed by the payload. The checksum is left to the caller.
+fn build_udp(source_port: u16, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut udp = Vec::with_capacity(8 + payload.len());
+    udp.extend_from_slice(&source_port.to_be_bytes());
+    udp.extend_from_slice(&destination_port.to_be_bytes());
+    udp.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+    udp
+}
+
+fn build_ipv4_udp(source: Ipv4Addr, destination: Ipv4Addr, source_port: u16, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut udp = build_udp(source_port, destination_port, payload);
+    let mut pseudo_header = Vec::with_capacity(12);
+    pseudo_header.extend_from_slice(&source.octets());
+    pseudo_header.extend_from_slice(&destination.octets());
+    pseudo_header.extend_from_slice(&[0, PROTOCOL_UDP]);
+    pseudo_header.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+    set_udp_checksum(&mut udp, &pseudo_header);
+
+    let mut ip = Vec::with_capacity(20 + udp.len());
+    ip.extend_from_slice(&[0x45, 0]);
+    ip.extend_from_slice(&((20 + udp.len()) as u16).to_be_bytes());
+    // Identification, flags (don't fragment) and fragment offset
+    ip.extend_from_slice(&[0, 0, 0x40, 0]);
+    ip.extend_from_slice(&[64, PROTOCOL_UDP, 0, 0]);
+    ip.extend_from_slice(&source.octets());
+    ip.extend_from_slice(&destination.octets());
+    let checksum = internet_checksum(&[&ip]);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+    ip.extend_from_slice(&udp);
+    ip
+}
+
+fn build_ipv6_udp(source: Ipv6Addr, destination: Ipv6Addr, source_port: u16, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut udp = build_udp(source_port, destination_port, payload);
+    let mut pseudo_header = Vec::with_capacity(40);
+    pseudo_header.extend_from_slice(&source.octets());
+    pseudo_header.extend_from_slice(&destination.octets());
+    pseudo_header.extend_from_slice(&(udp.len() as u32).to_be_bytes());
+    pseudo_header.extend_from_slice(&[0, 0, 0, PROTOCOL_UDP]);
+    set_udp_checksum(&mut udp, &pseudo_header);
+
+    let mut ip = Vec::with_capacity(40 + udp.len());
+    ip.extend_from_slice(&[0x60, 0, 0, 0]);
+    ip.extend_from_slice(&(udp.len() as u16).to_be_bytes());
+    ip.extend_from_slice(&[PROTOCOL_UDP, 64]);
+    ip.extend_from_slice(&source.octets());
+    ip.extend_from_slice(&destination.octets());
+    ip.extend_from_slice(&udp);
+    ip
+}
+
+/// Compute the UDP checksum over the pseudo header and the datagram
+fn set_udp_checksum(udp: &mut [u8], pseudo_header: &[u8]) {
+    let checksum = match internet_checksum(&[pseudo_header, udp]) {
+        // A computed checksum of zero is transmitted as all ones
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// One's complement sum used by IP and UDP (RFC 1071)
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for chunk in part.chunks(2) {
+            let word = if chunk.len() == 2 { ((chunk[0] as u32) << 8) | chunk[1] as u32 } else { (chunk[0] as u32) << 8 };
+            sum += word;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Take `len` bytes starting at `pos`
+fn slice(data: &[u8], pos: usize, len: usize) -> Result<&[u8], SimpleError> {
+    match data.get(pos..pos + len) {
+        Some(bytes) => Ok(bytes),
+        None => bail!("Truncated capture"),
+    }
+}
+
+fn read_u16(data: &[u8], pos: usize, big_endian: bool) -> Result<u16, SimpleError> {
+    let bytes = <[u8; 2]>::try_from(slice(data, pos, 2)?).unwrap();
+    Ok(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+}
+
+fn read_u32(data: &[u8], pos: usize, big_endian: bool) -> Result<u32, SimpleError> {
+    let bytes = <[u8; 4]>::try_from(slice(data, pos, 4)?).unwrap();
+    Ok(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DnsQuestions, RecordType};
+
+    fn query(id: u16, name: &str) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = id;
+        packet.questions.push(DnsQuestions::new(name.to_string(), RecordType::A));
+        packet
+    }
+
+    /// Wrap raw IP frames into a pcap file
+    fn capture(frames: &[&[u8]]) -> Vec<u8> {
+        let mut data = PcapWriter::new(Vec::new()).unwrap().into_inner().unwrap();
+        for frame in frames {
+            data.extend_from_slice(&[0; 8]);
+            data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            data.extend_from_slice(frame);
+        }
+        data
+    }
+
+    /// Build an IPv4 packet holding a TCP segment from 192.0.2.1:1234 to 192.0.2.2:53,
+    /// its header announcing `data_offset` 32 bits words. Checksums are left at zero.
+    fn tcp_frame(sequence: u32, syn: bool, data_offset: u8, payload: &[u8]) -> Vec<u8> {
+        let mut tcp = Vec::new();
+        tcp.extend_from_slice(&1234u16.to_be_bytes());
+        tcp.extend_from_slice(&53u16.to_be_bytes());
+        tcp.extend_from_slice(&sequence.to_be_bytes());
+        tcp.extend_from_slice(&[0, 0, 0, 0]);
+        tcp.extend_from_slice(&[data_offset << 4, if syn { 0x02 } else { 0x18 }]);
+        tcp.extend_from_slice(&[0xff, 0xff, 0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+
+        let mut ip = vec![0x45, 0];
+        ip.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+        ip.extend_from_slice(&[0, 0, 0x40, 0, 64, PROTOCOL_TCP, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2]);
+        ip.extend_from_slice(&tcp);
+        ip
+    }
+
+    #[test]
+    fn tcp_header_shorter_than_the_minimum_is_ignored() {
+        for data_offset in [0, 4] {
+            let frame = tcp_frame(1, false, data_offset, &[0; 12]);
+            assert!(decode_ipv4(&frame).unwrap().is_none(), "data offset {}", data_offset);
+        }
+        let frame = tcp_frame(1, false, 5, &[0; 12]);
+        let segment = decode_ipv4(&frame).unwrap().unwrap();
+        assert_eq!(segment.payload, [0; 12]);
+    }
+
+    /// Encode a DNS message, prefixed with its length as on a TCP stream
+    fn framed(packet: &mut DnsPacket) -> Vec<u8> {
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let mut data = (buffer.pos() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&buffer.buf[..buffer.pos()]);
+        data
+    }
+
+    #[test]
+    fn message_split_across_segments_is_reassembled() {
+        let message = framed(&mut query(1, "example.com"));
+        let (first, second) = message.split_at(7);
+        // The SYN takes sequence number 100, the segments arrive out of order
+        let frames = [
+            tcp_frame(100, true, 5, &[]),
+            tcp_frame(101 + first.len() as u32, false, 5, second),
+            tcp_frame(101, false, 5, first),
+        ];
+        let frames: Vec<&[u8]> = frames.iter().map(|frame| &frame[..]).collect();
+
+        let messages = read_capture(&capture(&frames)[..]).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].transport, Transport::Tcp);
+        assert_eq!(messages[0].source, "192.0.2.1:1234".parse().unwrap());
+        assert_eq!(messages[0].packet.questions[0].name, "example.com");
+    }
+
+    #[test]
+    fn messages_sharing_a_segment_are_split() {
+        let mut payload = framed(&mut query(1, "example.com"));
+        payload.extend_from_slice(&framed(&mut query(2, "example.org")));
+        // The start of a third message, never completed
+        payload.extend_from_slice(&framed(&mut query(3, "example.net"))[..10]);
+        let frame = tcp_frame(5000, false, 5, &payload);
+
+        let messages = read_capture(&capture(&[&frame])[..]).unwrap();
+        let ids: Vec<u16> = messages.iter().map(|message| message.packet.header.id).collect();
+        assert_eq!(ids, [1, 2]);
+    }
+
+    /// Append a pcapng block, padding its body to 32 bits
+    fn block(out: &mut Vec<u8>, block_type: u32, body: &[u8], big_endian: bool) {
+        let u32_bytes = |value: u32| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        let padded = (body.len() + 3) & !3;
+        let len = (12 + padded) as u32;
+        out.extend_from_slice(&u32_bytes(block_type));
+        out.extend_from_slice(&u32_bytes(len));
+        out.extend_from_slice(body);
+        out.resize(out.len() + padded - body.len(), 0);
+        out.extend_from_slice(&u32_bytes(len));
+    }
+
+    /// Build a pcapng capture of raw IP frames on a single interface. `tsresol` is the value
+    /// of the timestamp resolution option, if any, and `timestamps` are in its units.
+    fn pcapng(frames: &[(u64, &[u8])], tsresol: Option<u8>, big_endian: bool) -> Vec<u8> {
+        let u16_bytes = |value: u16| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        let u32_bytes = |value: u32| if big_endian { value.to_be_bytes() } else { value.to_le_bytes() };
+        let mut data = Vec::new();
+
+        let mut section = u32_bytes(PCAPNG_BYTE_ORDER_MAGIC).to_vec();
+        section.extend_from_slice(&u16_bytes(1));
+        section.extend_from_slice(&u16_bytes(0));
+        section.extend_from_slice(&[0xff; 8]);
+        block(&mut data, PCAPNG_SECTION_HEADER, &section, big_endian);
+
+        let mut interface = u16_bytes(LINKTYPE_RAW).to_vec();
+        interface.extend_from_slice(&[0, 0]);
+        interface.extend_from_slice(&u32_bytes(65535));
+        if let Some(tsresol) = tsresol {
+            interface.extend_from_slice(&u16_bytes(PCAPNG_OPTION_TSRESOL));
+            interface.extend_from_slice(&u16_bytes(1));
+            interface.extend_from_slice(&[tsresol, 0, 0, 0]);
+            interface.extend_from_slice(&[0; 4]);
+        }
+        block(&mut data, PCAPNG_INTERFACE_DESCRIPTION, &interface, big_endian);
+
+        for (timestamp, frame) in frames {
+            let mut packet = u32_bytes(0).to_vec();
+            packet.extend_from_slice(&u32_bytes((timestamp >> 32) as u32));
+            packet.extend_from_slice(&u32_bytes(*timestamp as u32));
+            packet.extend_from_slice(&u32_bytes(frame.len() as u32));
+            packet.extend_from_slice(&u32_bytes(frame.len() as u32));
+            packet.extend_from_slice(frame);
+            block(&mut data, PCAPNG_ENHANCED_PACKET, &packet, big_endian);
+        }
+        data
+    }
+
+    fn udp_frame(id: u16) -> Vec<u8> {
+        let mut buffer = BytePacketBuffer::new();
+        query(id, "example.com").write(&mut buffer).unwrap();
+        build_ipv4_udp(Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2), 1234, 53, &buffer.buf[..buffer.pos()])
+    }
+
+    #[test]
+    fn pcapng_in_both_byte_orders() {
+        let (first, second) = (udp_frame(1), udp_frame(2));
+        for big_endian in [false, true] {
+            // Default resolution of a microsecond
+            let data = pcapng(&[(10_500_000, &first), (11_000_001, &second)], None, big_endian);
+            let messages = read_capture(&data[..]).unwrap();
+            let ids: Vec<u16> = messages.iter().map(|message| message.packet.header.id).collect();
+            assert_eq!(ids, [1, 2], "big endian: {}", big_endian);
+            assert_eq!(messages[0].timestamp, Duration::new(10, 500_000_000));
+            assert_eq!(messages[1].timestamp, Duration::new(11, 1_000));
+        }
+    }
+
+    #[test]
+    fn pcapng_timestamp_resolution() {
+        let frame = udp_frame(1);
+        // Nanoseconds
+        let data = pcapng(&[(1_000_000_123, &frame)], Some(9), false);
+        assert_eq!(read_capture(&data[..]).unwrap()[0].timestamp, Duration::new(1, 123));
+        // Powers of two: 1/1024th of a second
+        let data = pcapng(&[(2048 + 512, &frame)], Some(0x80 | 10), false);
+        assert_eq!(read_capture(&data[..]).unwrap()[0].timestamp, Duration::new(2, 500_000_000));
+    }
+
+    #[test]
+    fn pcapng_truncated_last_block_is_ignored() {
+        let (first, second) = (udp_frame(1), udp_frame(2));
+        let data = pcapng(&[(0, &first), (0, &second)], None, false);
+        let messages = read_capture(&data[..data.len() - 8]).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].packet.header.id, 1);
+    }
+
+    #[test]
+    fn pcapng_packet_of_unknown_interface_is_rejected() {
+        let mut data = Vec::new();
+        let mut section = PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        section.extend_from_slice(&[1, 0, 0, 0]);
+        section.extend_from_slice(&[0xff; 8]);
+        block(&mut data, PCAPNG_SECTION_HEADER, &section, false);
+        block(&mut data, PCAPNG_ENHANCED_PACKET, &[0; 20], false);
+        assert!(read_capture(&data[..]).is_err());
+    }
+
+    #[test]
+    fn truncated_ipv4_header_is_rejected() {
+        let frame = build_ipv4_udp(Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2), 1234, 53, &[0; 12]);
+        for len in [0, 1, 10, 15, 17, 19] {
+            assert!(decode_ipv4(&frame[..len]).is_err(), "length {}", len);
+        }
+        let mut short_header = frame.clone();
+        short_header[0] = 0x44;
+        assert!(decode_ipv4(&short_header).is_err());
+        assert!(decode_ipv4(&frame).unwrap().is_some());
+    }
+
+    #[test]
+    fn truncated_frames_are_skipped() {
+        let frame = build_ipv4_udp(Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2), 1234, 53, &[0; 12]);
+        let messages = read_capture(&capture(&[&frame[..15], &frame[..19]])[..]).unwrap();
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn writer_round_trip() {
+        let v4_source: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+        let v4_destination: SocketAddr = "192.0.2.2:53".parse().unwrap();
+        let v6_source: SocketAddr = "[2001:db8::1]:5353".parse().unwrap();
+        let v6_destination: SocketAddr = "[2001:db8::2]:53".parse().unwrap();
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_packet(Duration::new(10, 500_000), v4_source, v4_destination, &mut query(1, "example.com")).unwrap();
+        writer.write_packet(Duration::new(11, 0), v6_source, v6_destination, &mut query(2, "example.org")).unwrap();
+        assert!(writer.write_packet(Duration::ZERO, v4_source, v6_destination, &mut query(3, "example.net")).is_err());
+        let data = writer.into_inner().unwrap();
+
+        let messages = read_capture(&data[..]).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].timestamp, Duration::new(10, 500_000));
+        assert_eq!((messages[0].source, messages[0].destination), (v4_source, v4_destination));
+        assert_eq!(messages[0].packet.header.id, 1);
+        assert_eq!(messages[0].packet.questions[0].name, "example.com");
+        assert!(matches!(messages[0].transport, Transport::Udp));
+        assert_eq!((messages[1].source, messages[1].destination), (v6_source, v6_destination));
+        assert_eq!(messages[1].packet.questions[0].name, "example.org");
+    }
+
+    #[test]
+    fn truncated_last_record_is_ignored() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        let source: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+        let destination: SocketAddr = "192.0.2.2:53".parse().unwrap();
+        writer.write_packet(Duration::new(10, 0), source, destination, &mut query(1, "example.com")).unwrap();
+        writer.write_packet(Duration::new(11, 0), source, destination, &mut query(2, "example.org")).unwrap();
+        let data = writer.into_inner().unwrap();
+
+        let messages = read_capture(&data[..data.len() - 5]).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].packet.header.id, 1);
+    }
+
+    #[test]
+    fn short_capture_is_rejected() {
+        assert!(read_capture(&[0u8, 1][..]).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+//! Cryptographically secure random numbers, drawn from the operating system
+
+use simple_error::SimpleError;
+
+/// Random 16 bits number, used for transaction ids and source ports
+pub fn random_u16() -> Result<u16, SimpleError> {
+    let mut bytes = [0u8; 2];
+    getrandom::fill(&mut bytes).map_err(|e| SimpleError::with("Error drawing random bytes", e))?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+/// Random 32 bits number
+pub fn random_u32() -> Result<u32, SimpleError> {
+    getrandom::u32().map_err(|e| SimpleError::with("Error drawing random bytes", e))
+}
+
+/// Random number below `bound`, without the bias of a modulo: draws from the incomplete
+/// range at the top of the u32 values are rejected
+pub fn random_below(bound: u32) -> Result<u32, SimpleError> {
+    if bound == 0 {
+        bail!("Empty range for a random number");
+    }
+    // Largest multiple of `bound` that fits in a u32, minus one
+    let limit = u32::MAX - (u32::MAX - bound + 1) % bound;
+    loop {
+        let value = random_u32()?;
+        if value <= limit {
+            return Ok(value % bound);
+        }
+    }
+}
+
+/// Shuffle a slice in place (Fisher-Yates)
+pub fn shuffle<T>(items: &mut [T]) -> Result<(), SimpleError> {
+    for i in (1..items.len()).rev() {
+        let j = random_below((i + 1) as u32)? as usize;
+        items.swap(i, j);
+    }
+    Ok(())
+}
+
+/// Flip the case of the ASCII letters of a name at random (DNS 0x20), so that a spoofed
+/// response also has to guess the case of the query name
+pub fn randomize_case(name: &str) -> Result<String, SimpleError> {
+    let mut randomized = String::with_capacity(name.len());
+    let mut bits = 0;
+    let mut available = 0;
+    for c in name.chars() {
+        if !c.is_ascii_alphabetic() {
+            randomized.push(c);
+            continue;
+        }
+        if available == 0 {
+            bits = random_u32()?;
+            available = 32;
+        }
+        randomized.push(if bits & 1 == 1 { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() });
+        bits >>= 1;
+        available -= 1;
+    }
+    Ok(randomized)
+}
+
+/// Pick an item of a slice at random
+pub fn choose<T>(items: &[T]) -> Result<Option<&T>, SimpleError> {
+    if items.is_empty() {
+        return Ok(None);
+    }
+    Ok(items.get(random_below(items.len() as u32)? as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_below_stays_in_range() {
+        assert!(random_below(0).is_err());
+        assert_eq!(random_below(1).unwrap(), 0);
+        for bound in [2, 3, 1000, u32::MAX / 2 + 1, u32::MAX] {
+            for _ in 0..100 {
+                assert!(random_below(bound).unwrap() < bound);
+            }
+        }
+    }
+
+    #[test]
+    fn random_below_covers_the_range() {
+        let mut seen = [false; 6];
+        for _ in 0..1000 {
+            seen[random_below(6).unwrap() as usize] = true;
+        }
+        assert!(seen.iter().all(|seen| *seen));
+    }
+
+    #[test]
+    fn shuffle_keeps_the_items() {
+        let mut items: Vec<u32> = (0..50).collect();
+        shuffle(&mut items).unwrap();
+        items.sort_unstable();
+        assert_eq!(items, (0..50).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn randomized_case_keeps_the_name() {
+        let name = "www.a-rather-long-example-name.com";
+        let randomized = randomize_case(name).unwrap();
+        assert!(randomized.eq_ignore_ascii_case(name));
+        assert_eq!(randomized.replace(|c: char| c.is_ascii_alphabetic(), ""), name.replace(|c: char| c.is_ascii_alphabetic(), ""));
+    }
+}
@@ -0,0 +1,1282 @@
+//! Recursive resolver, walking the delegation chain from the root name servers
+
+mod forwarder;
+mod https;
+mod tls;
+mod validator;
+
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{mpsc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use simple_error::SimpleError;
+use crate::random::{random_below, random_u16, randomize_case, shuffle};
+use crate::{BytePacketBuffer, Cache, CacheKey, CachedRRset, EdnsOption, EDE_STALE_ANSWER, EDNS_MESSAGE_SIZE, InfraCache, Trust, DnsPacket, DnsPacketView, DnsQuestions, DnsRecord, RRset, RecordType, ResultCode, RootHints, Security};
+use crate::dnssec::{builtin_trust_anchors, rrsigs_for};
+use crate::tcp::{read_message, write_message};
+use validator::Link;
+use forwarder::Upstreams;
+use https::HttpsConnections;
+use tls::TlsConnections;
+pub use forwarder::{parse_upstream, ForwardConfig, ForwardStrategy, Upstream, UpstreamHealth};
+pub use https::{HttpMethod, HttpsUpstream};
+pub use tls::{TlsUpstream, DOT_PORT};
+
+/// First port of the range used as source port for upstream queries, below are the well-known ports
+const MIN_SOURCE_PORT: u16 = 1024;
+/// Number of random ports tried before letting the system pick one
+const MAX_BIND_ATTEMPTS: usize = 10;
+/// Part of the TTL, in percent, left when popular RRsets get prefetched
+const PREFETCH_PERCENT: u32 = 10;
+/// Number of prefetches waiting for `Resolver::run_prefetcher`, above which no more are queued
+const PREFETCH_QUEUE_SIZE: usize = 100;
+/// Time after which a failed priming query is tried again
+const PRIMING_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Send a single query for `qname` to `server` and return its response,
+/// waiting at most `timeout` for it. The case of the query name is randomized and has to be
+/// echoed exactly (DNS 0x20): a response in another case fails, as it may be spoofed.
+pub fn lookup(qname: &str, qtype: RecordType, server: (IpAddr, u16), timeout: Duration) -> Result<DnsPacket, SimpleError> {
+    match lookup_with_case(qname, qtype, server, timeout, true)? {
+        Some(response) => Ok(response),
+        None => bail!("Question case mismatch from {}:{}", server.0, server.1),
+    }
+}
+
+/// Same as `lookup`, the case of the query name being randomized only with `use_0x20`.
+/// A response that does not preserve the randomized case gives `None`.
+fn lookup_with_case(qname: &str, qtype: RecordType, server: (IpAddr, u16), timeout: Duration, use_0x20: bool) -> Result<Option<DnsPacket>, SimpleError> {
+    let deadline = Instant::now() + timeout;
+    let name = match use_0x20 {
+        true => randomize_case(qname)?,
+        false => qname.to_string(),
+    };
+    let response = match exchange(&name, qtype, server, deadline, use_0x20)? {
+        Some(response) => response,
+        None => return Ok(None),
+    };
+
+    // A truncated response is only a part of the answer: the whole of it is asked over TCP
+    if response.header.truncated_message {
+        println!("truncated response from {}:{}, retrying over TCP", server.0, server.1);
+        return lookup_tcp(qname, qtype, server, deadline.saturating_duration_since(Instant::now())).map(Some);
+    }
+    Ok(Some(response))
+}
+
+/// Send a single query for `qname` to `server` over TCP and return its response,
+/// waiting at most `timeout` for it
+pub fn lookup_tcp(qname: &str, qtype: RecordType, server: (IpAddr, u16), timeout: Duration) -> Result<DnsPacket, SimpleError> {
+    let deadline = Instant::now() + timeout;
+    let remaining = || match deadline.saturating_duration_since(Instant::now()) {
+        remaining if remaining.is_zero() => Err(SimpleError::new(format!("Timeout waiting for a response from {}:{}", server.0, server.1))),
+        remaining => Ok(remaining),
+    };
+
+    let server_addr = SocketAddr::from(server);
+    let mut stream = TcpStream::connect_timeout(&server_addr, remaining()?)
+        .map_err(|e| SimpleError::with("Error connecting", e))?;
+
+    let mut packet = build_query(qname, qtype)?;
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+
+    stream.set_write_timeout(Some(remaining()?))
+        .map_err(|e| SimpleError::with("Error setting socket timeout", e))?;
+    write_message(&mut stream, &req_buffer.buf[..req_buffer.pos])?;
+
+    // The connection only carries our query, so anything else than its response is an error
+    stream.set_read_timeout(Some(remaining()?))
+        .map_err(|e| SimpleError::with("Error setting socket timeout", e))?;
+    let mut res_buffer = read_message(&mut stream)?;
+    validate_response(&res_buffer.buf, server_addr, &packet, server_addr)?;
+    DnsPacket::from_buffer(&mut res_buffer)
+}
+
+/// Build a query for `qname`. It's important that we remember to set the `recursion_desired`
+/// flag. The packet id is drawn at random. The OPT record advertises a larger buffer and asks
+/// for the DNSSEC records (RFC 3225).
+fn build_query(qname: &str, qtype: RecordType) -> Result<DnsPacket, SimpleError> {
+    let mut packet = DnsPacket::new();
+
+    packet.header.id = random_u16()?;
+    packet.header.questions = 1;
+    packet.header.recursion_desired = true;
+    packet
+        .questions
+        .push(DnsQuestions::new(qname.to_string(), qtype));
+    packet.set_edns(EDNS_MESSAGE_SIZE as u16, true, Vec::new());
+    Ok(packet)
+}
+
+/// Send a query for `qname` to `server` and wait until `deadline` for its response. With
+/// `exact_case`, a response echoing the query name in another case gives `None`.
+fn exchange(qname: &str, qtype: RecordType, server: (IpAddr, u16), deadline: Instant, exact_case: bool) -> Result<Option<DnsPacket>, SimpleError> {
+    // Every query gets its own socket on a random port, so that a spoofed answer
+    // has to guess both the port and the transaction id
+    let socket = bind_random_port(server.0)?;
+
+    // Build our query packet
+    let mut packet = build_query(qname, qtype)?;
+
+    // Write the packet to a buffer...
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+
+    // ...and send it off to the server using our socket:
+    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)
+        .map_err(|e| SimpleError::with("Error sending packet", e))?;
+
+    // Wait for the response. Anything that is not the answer to our query is dropped,
+    // and we keep waiting until the timeout expires.
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            bail!("Timeout waiting for a response from {}:{}", server.0, server.1);
+        }
+        socket.set_read_timeout(Some(remaining))
+            .map_err(|e| SimpleError::with("Error setting socket timeout", e))?;
+
+        // New `BytePacketBuffer` to prepare for receiving the response.
+        // Ask the socket to write the response directly into our buffer.
+        let mut res_buffer = BytePacketBuffer::with_size(EDNS_MESSAGE_SIZE);
+        let (len, src_addr) = match socket.recv_from(&mut res_buffer.buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return Err(SimpleError::with("Error receiving packet", e)),
+        };
+
+        match validate_response(&res_buffer.buf[..len], src_addr, &packet, server.into()) {
+            Ok(false) if exact_case => return Ok(None),
+            Ok(_) => return DnsPacket::from_buffer(&mut res_buffer).map(Some),
+            Err(e) => println!("Discarding packet from {}: {}", src_addr, e),
+        }
+    }
+}
+
+/// Check that a datagram is the response to `query`, as sent by `server`.
+/// Only the header and the question are decoded. Returns whether the question
+/// was echoed in the exact same case.
+fn validate_response(data: &[u8], src_addr: SocketAddr, query: &DnsPacket, server: SocketAddr) -> Result<bool, SimpleError> {
+    if src_addr != server {
+        bail!("unexpected source address");
+    }
+
+    let view = DnsPacketView::new(data)?;
+    let header = view.header();
+    if header.id != query.header.id {
+        bail!("transaction id mismatch");
+    }
+    if !header.response {
+        bail!("not a response");
+    }
+
+    // The question has to be echoed back as it was sent
+    if header.questions as usize != query.questions.len() {
+        bail!("question count mismatch");
+    }
+    let mut exact_case = true;
+    for (question, expected) in view.questions().zip(query.questions.iter()) {
+        let question = question?;
+        if question.qtype != expected.qtype || question.class != 1 || !question.name.eq_ignore_case(&expected.name) {
+            bail!("question mismatch");
+        }
+        exact_case &= question.name.eq_exact(&expected.name);
+    }
+
+    Ok(exact_case)
+}
+
+/// Bind a UDP socket on a random port, falling back to an ephemeral port chosen by the system.
+/// The socket uses the address family of `server`.
+fn bind_random_port(server: IpAddr) -> Result<UdpSocket, SimpleError> {
+    let local: IpAddr = match server {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    for _ in 0..MAX_BIND_ATTEMPTS {
+        let port = MIN_SOURCE_PORT + random_below((u16::MAX - MIN_SOURCE_PORT) as u32 + 1)? as u16;
+        match UdpSocket::bind((local, port)) {
+            Ok(socket) => return Ok(socket),
+            // The port is already taken, try another one
+            Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(SimpleError::with("Error creating socket", e)),
+        }
+    }
+    UdpSocket::bind((local, 0)).map_err(|e| SimpleError::with("Error creating socket", e))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Address families used to reach name servers
+pub enum IpPolicy {
+    Ipv4Only,
+    Ipv6Only,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+impl IpPolicy {
+    /// Drop the addresses of a forbidden family and put the preferred family first.
+    /// The relative order of the addresses of the same family is kept.
+    pub fn apply(&self, mut addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+        match self {
+            IpPolicy::Ipv4Only => addrs.retain(|addr| addr.is_ipv4()),
+            IpPolicy::Ipv6Only => addrs.retain(|addr| addr.is_ipv6()),
+            IpPolicy::PreferIpv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            IpPolicy::PreferIpv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+        }
+        addrs
+    }
+
+    /// Record types to query to find the addresses of a name server, most preferred first
+    pub fn address_types(&self) -> &'static [RecordType] {
+        match self {
+            IpPolicy::Ipv4Only => &[RecordType::A],
+            IpPolicy::Ipv6Only => &[RecordType::AAAA],
+            IpPolicy::PreferIpv4 => &[RecordType::A, RecordType::AAAA],
+            IpPolicy::PreferIpv6 => &[RecordType::AAAA, RecordType::A],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Settings of the recursive resolver
+pub struct ResolverConfig {
+    /// Time given to a name server to answer the first attempt of a query
+    pub query_timeout: Duration,
+    /// Number of rounds over the candidate name servers before giving up
+    pub attempts: usize,
+    /// Consecutive failures of a name server over UDP after which it is queried over TCP instead,
+    /// until its statistics expire
+    pub udp_failures_before_tcp: u32,
+    /// Factor applied to the query timeout after each unsuccessful round
+    pub backoff_factor: u32,
+    /// Upper bound of the query timeout, after backoff
+    pub max_query_timeout: Duration,
+    /// Overall time budget for resolving a client query
+    pub resolution_timeout: Duration,
+    /// Root name servers used until a priming query refreshes them
+    pub root_hints: RootHints,
+    /// Address families used to reach name servers
+    pub ip_policy: IpPolicy,
+    /// Maximum number of CNAME or DNAME records followed for a single query
+    pub max_alias_chain: usize,
+    /// Maximum number of RRsets kept in the cache
+    pub cache_size: usize,
+    /// Upper bound of the TTL of cached records, in seconds
+    pub max_cache_ttl: u32,
+    /// Upper bound of the TTL of cached NXDOMAIN and NODATA answers, in seconds
+    pub max_negative_ttl: u32,
+    /// Time expired cache entries are kept, to answer from when they cannot be refreshed
+    /// (RFC 8767). Zero disables serving stale data.
+    pub stale_window: Duration,
+    /// TTL of the stale records sent to clients, in seconds
+    pub stale_answer_ttl: u32,
+    /// Time after a failed refresh during which stale data is served without trying again
+    pub stale_refresh_interval: Duration,
+    /// Refresh the RRsets answered at least this many times once they enter the last 10% of
+    /// their TTL, so that they do not expire. Zero disables prefetching.
+    pub prefetch_min_hits: u32,
+    /// Reveal the query name to each server one label at a time (RFC 9156)
+    pub qname_minimisation: bool,
+    /// Record type of the minimised queries, NS or A
+    pub minimisation_qtype: RecordType,
+    /// Number of minimised queries after which the full query name is sent
+    pub max_minimised_queries: usize,
+    /// Maximum number of referrals followed for a client query
+    pub max_referrals: usize,
+    /// Maximum nesting of name server address resolutions
+    pub max_ns_depth: usize,
+    /// Maximum number of queries sent upstream for a client query
+    pub max_upstream_queries: usize,
+    /// Maximum number of name servers whose round trip time is tracked
+    pub infra_cache_size: usize,
+    /// Time after which the statistics of a name server are forgotten
+    pub infra_ttl: Duration,
+    /// Validate the DNSSEC signatures of the answers, failing on bogus data
+    pub dnssec_validation: bool,
+    /// DS records the chains of trust start from. Without any, every answer is insecure.
+    pub trust_anchors: Vec<DnsRecord>,
+    /// Upstream resolvers the queries are forwarded to, instead of resolving from the root servers
+    pub forward: Option<ForwardConfig>,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            query_timeout: Duration::from_millis(800),
+            attempts: 3,
+            udp_failures_before_tcp: 2,
+            backoff_factor: 2,
+            max_query_timeout: Duration::from_secs(4),
+            resolution_timeout: Duration::from_secs(10),
+            root_hints: RootHints::builtin(),
+            ip_policy: IpPolicy::PreferIpv4,
+            max_alias_chain: 12,
+            cache_size: 10_000,
+            max_cache_ttl: 86_400,
+            max_negative_ttl: 10_800,
+            stale_window: Duration::from_secs(86_400),
+            stale_answer_ttl: 30,
+            stale_refresh_interval: Duration::from_secs(30),
+            prefetch_min_hits: 3,
+            qname_minimisation: true,
+            minimisation_qtype: RecordType::NS,
+            max_minimised_queries: 10,
+            max_referrals: 30,
+            max_ns_depth: 4,
+            max_upstream_queries: 100,
+            infra_cache_size: 10_000,
+            infra_ttl: Duration::from_secs(900),
+            dnssec_validation: true,
+            trust_anchors: builtin_trust_anchors(),
+            forward: None,
+        }
+    }
+}
+
+/// Limits and bookkeeping shared by every step of the resolution of a client query,
+/// nested name server resolutions included
+struct Resolution {
+    /// Time after which no query is sent anymore
+    deadline: Instant,
+    referrals: usize,
+    /// Current nesting of name server address resolutions
+    depth: usize,
+    queries: usize,
+    /// Server, name and type of the queries sent so far
+    sent: HashSet<(IpAddr, String, RecordType)>,
+    /// Reason of the failure, once a limit is hit
+    aborted: Option<String>,
+}
+
+impl Resolution {
+    fn new(config: &ResolverConfig) -> Resolution {
+        Resolution {
+            deadline: Instant::now() + config.resolution_timeout,
+            referrals: 0,
+            depth: 0,
+            queries: 0,
+            sent: HashSet::new(),
+            aborted: None,
+        }
+    }
+
+    /// Fail when a limit was hit, even if the error was swallowed by a nested resolution
+    fn check(&self) -> Result<(), SimpleError> {
+        match &self.aborted {
+            Some(reason) => Err(SimpleError::new(reason.as_str())),
+            None => Ok(()),
+        }
+    }
+
+    /// Record a query about to be sent to `ns`, failing when it was already sent there by an
+    /// earlier step of the resolution: the delegations form a loop. Minimised queries are not
+    /// checked, they legitimately repeat across nested resolutions.
+    fn check_loop(&mut self, qname: &str, qtype: RecordType, ns: IpAddr) -> Result<(), SimpleError> {
+        self.check()?;
+        if !self.sent.insert((ns, qname.to_ascii_lowercase(), qtype)) {
+            return Err(self.abort(format!("Loop detected: {:?} {} already sent to {}", qtype, qname, ns)));
+        }
+        Ok(())
+    }
+
+    /// Record the failure so that the whole resolution stops
+    fn abort(&mut self, reason: String) -> SimpleError {
+        println!("aborting resolution: {}", reason);
+        self.aborted = Some(reason.clone());
+        SimpleError::new(reason)
+    }
+}
+
+/// Recursive resolver, starting every lookup from the root name servers
+pub struct Resolver {
+    pub config: ResolverConfig,
+    /// Current root name servers, from the hints or from the last priming query
+    roots: RwLock<RootHints>,
+    /// Time at which the root servers are primed again, once they have been primed
+    roots_expiry: Mutex<Option<Instant>>,
+    /// RRsets learned from previous lookups
+    cache: Mutex<Cache>,
+    /// Round trip times and failures of the name servers
+    infra: Mutex<InfraCache>,
+    /// Links of the chains of trust, along with their expiration time
+    links: Mutex<HashMap<String, (Link, Instant)>>,
+    /// Health of the upstream resolvers, in forwarding mode
+    upstreams: Upstreams,
+    /// Connections to the upstream resolvers reached over TLS
+    tls: TlsConnections,
+    /// Connections to the upstream resolvers reached over HTTPS
+    https: HttpsConnections,
+    /// Time of the last failed refresh of the names and types answered with stale data
+    refresh_failures: Mutex<HashMap<CacheKey, Instant>>,
+    /// RRsets waiting to be prefetched, or being prefetched
+    prefetching: Mutex<HashSet<CacheKey>>,
+    prefetch_queue: mpsc::SyncSender<CacheKey>,
+    prefetch_requests: Mutex<mpsc::Receiver<CacheKey>>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver::new(ResolverConfig::default())
+    }
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Resolver {
+        let roots = RwLock::new(config.root_hints.clone());
+        let roots_expiry = Mutex::new(None);
+        let cache = Mutex::new(Cache::new(config.cache_size, config.max_cache_ttl, config.max_negative_ttl, config.stale_window));
+        let infra = Mutex::new(InfraCache::new(config.infra_cache_size, config.infra_ttl));
+        let links = Mutex::new(HashMap::new());
+        let upstreams = Upstreams::default();
+        let tls = TlsConnections::default();
+        let https = HttpsConnections::default();
+        let refresh_failures = Mutex::new(HashMap::new());
+        let prefetching = Mutex::new(HashSet::new());
+        let (prefetch_queue, prefetch_requests) = mpsc::sync_channel(PREFETCH_QUEUE_SIZE);
+        let prefetch_requests = Mutex::new(prefetch_requests);
+        Resolver { config, roots, roots_expiry, cache, infra, links, upstreams, tls, https, refresh_failures, prefetching, prefetch_queue, prefetch_requests }
+    }
+
+    /// Send a priming query (RFC 8109) to the root servers from the hints, and merge the NS set
+    /// of the root zone found in the response into them. The root servers are primed again
+    /// when the TTL of this NS set expires.
+    pub fn prime(&self) -> Result<(), SimpleError> {
+        // Until it succeeds, priming is tried again after a while
+        *self.roots_expiry.lock().unwrap() = Some(Instant::now() + PRIMING_RETRY_INTERVAL);
+
+        let mut resolution = Resolution::new(&self.config);
+        let response = self.query_servers("", RecordType::NS, &self.root_servers()?, false, &mut resolution)?;
+        let hints = self.roots.read().unwrap().with_priming_response(&response)?;
+        let ttl = RootHints::priming_ttl(&response).unwrap_or(0);
+        println!("Primed {} root servers for {} seconds", hints.servers.len(), ttl);
+        *self.roots.write().unwrap() = hints;
+        *self.roots_expiry.lock().unwrap() = Some(Instant::now() + Duration::from_secs(ttl as u64));
+        Ok(())
+    }
+
+    /// Addresses of the root servers in random order, so that the load is spread across all of them.
+    /// Root servers primed earlier are primed again first when their TTL expired (RFC 8109 §4.1).
+    fn root_servers(&self) -> Result<Vec<IpAddr>, SimpleError> {
+        let expired = {
+            let mut expiry = self.roots_expiry.lock().unwrap();
+            match *expiry {
+                // Claimed by this thread, the others keep using the current root servers
+                Some(time) if time <= Instant::now() => {
+                    *expiry = Some(Instant::now() + PRIMING_RETRY_INTERVAL);
+                    true
+                }
+                _ => false,
+            }
+        };
+        if expired {
+            if let Err(e) = self.prime() {
+                println!("Priming query failed, keeping the current root servers: {}", e);
+            }
+        }
+
+        let mut servers = self.roots.read().unwrap().addresses();
+        shuffle(&mut servers)?;
+        Ok(self.config.ip_policy.apply(servers))
+    }
+
+    /// Perform a recursive lookup, starting from the root name servers
+    pub fn recursive_lookup(&self, qname: &str, qtype: RecordType) -> Result<DnsPacket, SimpleError> {
+        self.recursive_lookup_with_cd(qname, qtype, false)
+    }
+
+    /// Perform a recursive lookup, validating the answer unless `checking_disabled` is set (the
+    /// CD bit of the client query). The AD bit of the response tells whether the answer is secure.
+    pub fn recursive_lookup_with_cd(&self, qname: &str, qtype: RecordType, checking_disabled: bool) -> Result<DnsPacket, SimpleError> {
+        let mut resolution = Resolution::new(&self.config);
+        let validate = self.config.dnssec_validation && !checking_disabled;
+        self.resolve_chain(qname, qtype, validate, &mut resolution)
+    }
+
+    /// Resolve `qname`, following CNAME and DNAME records across zones until the records of
+    /// type `qtype` are found. The whole chain is assembled in the answer section, and the AD bit
+    /// is only set when every step of the chain is secure. The response is flagged as stale
+    /// when any step is.
+    fn resolve_chain(&self, qname: &str, qtype: RecordType, validate: bool, resolution: &mut Resolution) -> Result<DnsPacket, SimpleError> {
+        let mut chain = Vec::new();
+        let mut authed_data = validate;
+        let mut stale = false;
+        let mut steps = 0;
+        let mut current = qname.to_string();
+        let mut seen = HashSet::new();
+        seen.insert(current.to_ascii_lowercase());
+
+        loop {
+            let queried = current.clone();
+            let mut response = self.resolve(&queried, qtype, validate, resolution)?;
+            authed_data &= response.header.authed_data;
+            stale |= response.extended_errors().contains(&EDE_STALE_ANSWER);
+
+            // Follow the aliases as far as this response allows. Aliases are not followed
+            // when they are what the client asked for.
+            while qtype != RecordType::CNAME && qtype != RecordType::DNAME && !response.has_answer(&current, qtype) {
+                let (records, target) = match response.get_alias(&current) {
+                    Some(alias) => alias,
+                    None => break,
+                };
+                let signatures = signatures_of(&records, &response.answers);
+                chain.extend(records);
+                chain.extend(signatures);
+                steps += 1;
+                if steps > self.config.max_alias_chain {
+                    bail!("Alias chain of {} is too long", qname);
+                }
+                if !seen.insert(target.to_ascii_lowercase()) {
+                    bail!("Alias loop detected for {}", qname);
+                }
+                println!("following alias {} -> {}", current, target);
+                current = target;
+            }
+
+            // Query again when the target of the chain is neither answered nor denied by this response
+            let followed = !current.eq_ignore_ascii_case(&queried);
+            if followed && response.header.rescode == ResultCode::NOERROR && !response.has_answer(&current, qtype) {
+                continue;
+            }
+
+            // The final records are the ones owned by the end of the chain, with their signatures
+            let final_records: Vec<_> = response.answers
+                .drain(..)
+                .filter(|record| is_final_record(record, &current, qtype))
+                .collect();
+            chain.extend(final_records);
+            response.answers = chain;
+            response.header.authed_data = authed_data;
+            if stale {
+                response.set_edns(EDNS_MESSAGE_SIZE as u16, false, vec![EdnsOption::extended_error(EDE_STALE_ANSWER, "")]);
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Answer from the cache, or else walk the delegation chain for `qname`, within the limits
+    /// of `resolution`. When `validate` is set, the final response is validated with DNSSEC:
+    /// bogus answers fail, and the AD bit tells whether the response is secure. When the answer
+    /// cannot be refreshed, expired data is served (RFC 8767), flagged with an Extended DNS Error.
+    fn resolve(&self, qname: &str, qtype: RecordType, validate: bool, resolution: &mut Resolution) -> Result<DnsPacket, SimpleError> {
+        // Nothing to do if the answer is already known
+        if let Some((response, security)) = self.cached_answer(qname, qtype, false) {
+            println!("cache hit for {:?} {}", qtype, qname);
+            return self.check_cached(response, security, qname, qtype, validate, resolution);
+        }
+
+        // A refresh that failed recently is not tried again before the recheck timer expires
+        // (RFC 8767 §5)
+        let key = CacheKey::new(qname, qtype);
+        let failed = self.refresh_failures.lock().unwrap().get(&key).copied();
+        if failed.is_some_and(|failed| failed.elapsed() < self.config.stale_refresh_interval) {
+            if let Some(response) = self.stale_answer(qname, qtype, validate, resolution) {
+                return Ok(response);
+            }
+        }
+
+        match self.fetch(qname, qtype, validate, resolution) {
+            Ok(response) => {
+                if failed.is_some() {
+                    self.refresh_failures.lock().unwrap().remove(&key);
+                }
+                Ok(response)
+            }
+            Err(e) => match self.stale_answer(qname, qtype, validate, resolution) {
+                Some(response) => {
+                    println!("refresh of {:?} {} failed ({}), serving stale data", qtype, qname, e);
+                    let mut failures = self.refresh_failures.lock().unwrap();
+                    failures.retain(|_, failed| failed.elapsed() < self.config.stale_refresh_interval);
+                    failures.insert(key, Instant::now());
+                    Ok(response)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Validate a response built from the cache, unless its validation status is known
+    fn check_cached(&self, mut response: DnsPacket, security: Option<Security>, qname: &str, qtype: RecordType, validate: bool, resolution: &mut Resolution) -> Result<DnsPacket, SimpleError> {
+        if validate {
+            let security = match security {
+                Some(security) => security,
+                None => self.validate(&response, qname, qtype, resolution)?,
+            };
+            if security == Security::Bogus {
+                bail!("DNSSEC validation failed for {:?} {}", qtype, qname);
+            }
+            response.header.authed_data = security == Security::Secure;
+        }
+        Ok(response)
+    }
+
+    /// Response built from the cache, expired data included, its stale records getting
+    /// `stale_answer_ttl` and the response the "Stale Answer" Extended DNS Error (RFC 8914 §4.4)
+    fn stale_answer(&self, qname: &str, qtype: RecordType, validate: bool, resolution: &mut Resolution) -> Option<DnsPacket> {
+        if self.config.stale_window.is_zero() {
+            return None;
+        }
+        let (mut response, security) = self.cached_answer(qname, qtype, true)?;
+        for record in response.answers.iter_mut().chain(response.authorities.iter_mut()) {
+            if record.ttl() == 0 {
+                record.set_ttl(self.config.stale_answer_ttl);
+            }
+        }
+        let mut response = self.check_cached(response, security, qname, qtype, validate, resolution).ok()?;
+        response.set_edns(EDNS_MESSAGE_SIZE as u16, false, vec![EdnsOption::extended_error(EDE_STALE_ANSWER, "")]);
+        println!("stale answer for {:?} {}", qtype, qname);
+        Some(response)
+    }
+
+    /// Walk the delegation chain for `qname`, or ask the upstream resolvers in forwarding mode,
+    /// and cache the response
+    fn fetch(&self, qname: &str, qtype: RecordType, validate: bool, resolution: &mut Resolution) -> Result<DnsPacket, SimpleError> {
+        // In forwarding mode the upstream resolvers do the work, the delegation chain is only
+        // walked when none of them answers
+        if let Some(forward) = &self.config.forward {
+            match self.forward(forward, qname, qtype, resolution) {
+                Ok(response) => return self.complete(qname, qtype, response, validate, resolution),
+                Err(e) if forward.fallback_to_recursion => {
+                    resolution.check()?;
+                    println!("forwarding of {:?} {} failed ({}), resolving from the root servers", qtype, qname, e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Start from the closest delegation found in the cache, or else from the root servers
+        // (https://www.internic.net/domain/named.root). DS records live in the parent zone,
+        // above the zone cut.
+        let parent_name = match qtype {
+            RecordType::DS if !qname.is_empty() => qname.split_once('.').map_or("", |(_, parent)| parent),
+            _ => qname,
+        };
+        let delegation = self.cache.lock().unwrap().closest_delegation(parent_name);
+        let (mut zone, mut servers) = match delegation {
+            Some((zone, mut addrs)) => {
+                println!("starting from cached delegation of {:?}", zone);
+                shuffle(&mut addrs)?;
+                (zone, self.config.ip_policy.apply(addrs))
+            }
+            None => (String::new(), self.root_servers()?),
+        };
+
+        // With QNAME minimisation, the servers only learn one more label of the query name
+        // at each step. `known` is the longest name known to be served by the current servers.
+        let mut minimise = self.config.qname_minimisation;
+        let mut minimised_queries = 0;
+        let mut known = zone.clone();
+
+        // Since it might take an arbitrary number of steps, we enter an unbounded loop.
+        loop {
+            let child = child_name(qname, &known);
+            if minimise && !child.eq_ignore_ascii_case(qname) {
+                minimised_queries += 1;
+                if minimised_queries >= self.config.max_minimised_queries {
+                    minimise = false;
+                }
+
+                let qtype = self.config.minimisation_qtype;
+                let mut response = match self.query_servers(&child, qtype, &servers, false, resolution) {
+                    Ok(response) if response.header.rescode == ResultCode::NOERROR => response,
+                    // Some servers wrongly deny the existence of empty non-terminals, or fail on
+                    // the minimised query: go on with the full query name
+                    Ok(response) => {
+                        println!("minimised query for {} answered {:?}, sending the full name", child, response.header.rescode);
+                        minimise = false;
+                        continue;
+                    }
+                    Err(e) => {
+                        resolution.check()?;
+                        println!("minimised query for {} failed ({}), sending the full name", child, e);
+                        minimise = false;
+                        continue;
+                    }
+                };
+                self.retain_in_bailiwick(&mut response, &zone);
+                self.cache_referral(&response);
+
+                // A referral to a deeper zone switches to its name servers, otherwise there is
+                // no zone cut at this name and the next label is revealed to the same servers
+                let referral = match response.get_referral_zone(&child) {
+                    Some(cut) if response.answers.is_empty() && cut.len() > zone.len() => Some(cut.to_string()),
+                    _ => None,
+                };
+                if let Some(cut) = referral {
+                    resolution.referrals += 1;
+                    let next = self.referral_servers(&response, &child, resolution)?;
+                    // As with the full query name, we go with what the last server told us when
+                    // none of the name servers of the referral can be reached
+                    if next.is_empty() {
+                        response.header.authed_data = false;
+                        return Ok(response);
+                    }
+                    zone = cut;
+                    servers = next;
+                }
+                known = child;
+                continue;
+            }
+
+            // The next step is to send the query to the candidate servers, until one answers.
+            let mut response = self.query_servers(qname, qtype, &servers, true, resolution)?;
+            self.retain_in_bailiwick(&mut response, &zone);
+
+            // If there are entries in the answer section, and no errors, we are done!
+            if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
+                return self.complete(qname, qtype, response, validate, resolution);
+            }
+
+            // We might also get a `NXDOMAIN` reply, which is the authoritative name servers
+            // way of telling us that the name doesn't exist.
+            if response.header.rescode == ResultCode::NXDOMAIN {
+                return self.complete(qname, qtype, response, validate, resolution);
+            }
+            // Only the delegation is cached here: a NODATA answer is cached by `complete`, once validated
+            self.cache_referral(&response);
+
+            // Otherwise, switch to the name servers of the referral and retry the loop. If there is
+            // no usable referral, we'll go with what the last server told us.
+            if !response.get_all_unresolved_ns(qname).is_empty() {
+                resolution.referrals += 1;
+            }
+            let next = self.referral_servers(&response, qname, resolution)?;
+            if next.is_empty() {
+                return self.complete(qname, qtype, response, validate, resolution);
+            }
+            zone = response.get_referral_zone(qname).unwrap_or_default().to_string();
+            known = zone.clone();
+            servers = next;
+        }
+    }
+
+    /// Validate the final response to a query when `validate` is set, then cache it. Bogus
+    /// responses are neither cached nor returned.
+    fn complete(&self, qname: &str, qtype: RecordType, mut response: DnsPacket, validate: bool, resolution: &mut Resolution) -> Result<DnsPacket, SimpleError> {
+        let security = match validate {
+            true => Some(self.validate(&response, qname, qtype, resolution)?),
+            false => None,
+        };
+        if security == Some(Security::Bogus) {
+            bail!("DNSSEC validation failed for {:?} {}", qtype, qname);
+        }
+        response.header.authed_data = security == Some(Security::Secure);
+        self.cache_response(qname, qtype, &response, security);
+        Ok(response)
+    }
+
+    /// Drop the records of a response sent by the servers of `zone` that are out of their bailiwick
+    fn retain_in_bailiwick(&self, response: &mut DnsPacket, zone: &str) {
+        let dropped = response.retain_in_bailiwick(zone);
+        if dropped > 0 {
+            println!("dropped {} records out of the bailiwick of {:?}", dropped, zone);
+        }
+    }
+
+    /// Addresses of the name servers a referral points to for `qname`. Empty when the response
+    /// is not a referral or none of its name servers can be reached. Fails when the referral
+    /// goes beyond the limits of the resolution.
+    fn referral_servers(&self, response: &DnsPacket, qname: &str, resolution: &mut Resolution) -> Result<Vec<IpAddr>, SimpleError> {
+        // We'll try to find new nameservers based on NS and corresponding A or AAAA
+        // records in the additional section.
+        if resolution.referrals > self.config.max_referrals {
+            return Err(resolution.abort(format!("Too many referrals while resolving {}", qname)));
+        }
+        let resolved = self.config.ip_policy.apply(response.get_all_resolved_ns(qname));
+        if !resolved.is_empty() {
+            return Ok(resolved);
+        }
+
+        // If not, we'll have to resolve the ip of a NS record.
+        // Here we go down the rabbit hole by starting _another_ lookup sequence in the midst of
+        // our current one, moving on to the next name server when one of them cannot be resolved.
+        // Hopefully, this will give us the IP of an appropriate name server.
+        if resolution.depth >= self.config.max_ns_depth {
+            return Err(resolution.abort(format!("Name server resolutions nested too deeply for {}", qname)));
+        }
+        resolution.depth += 1;
+        let mut addrs = Vec::new();
+        for ns_name in response.get_all_unresolved_ns(qname) {
+            addrs = self.resolve_ns_addresses(ns_name, resolution);
+            if !addrs.is_empty() || resolution.aborted.is_some() {
+                break;
+            }
+        }
+        resolution.depth -= 1;
+        resolution.check()?;
+        Ok(addrs)
+    }
+
+    /// Build a response from the cache: the RRset of type `qtype` for `qname`, or else its CNAME.
+    /// A cached negative answer is returned with its SOA record and its proof in the authority
+    /// section. The validation status of the cached data comes along, if it was validated.
+    /// With `stale`, data expired within the stale window is used as well. A popular RRset
+    /// close to expiration is queued for prefetching.
+    fn cached_answer(&self, qname: &str, qtype: RecordType, stale: bool) -> Option<(DnsPacket, Option<Security>)> {
+        let mut cache = self.cache.lock().unwrap();
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+        response.questions.push(DnsQuestions::new(qname.to_string(), qtype));
+
+        let mut get = |rtype| if stale { cache.get_stale_answer(qname, rtype) } else { cache.get_answer(qname, rtype) };
+        let rrset = get(qtype)
+            .or_else(|| if qtype != RecordType::CNAME { get(RecordType::CNAME) } else { None });
+        let security = match rrset {
+            Some(cached) => {
+                if !stale && needs_prefetch(&cached, self.config.prefetch_min_hits) {
+                    self.queue_prefetch(CacheKey::new(qname, qtype));
+                }
+                response.answers = cached.rrset.into_records();
+                response.answers.extend(cached.rrsigs);
+                cached.security
+            }
+            None => {
+                let negative = if stale { cache.get_stale_negative(qname, qtype)? } else { cache.get_negative(qname, qtype)? };
+                response.header.rescode = negative.rescode;
+                response.authorities.push(negative.soa);
+                response.authorities.extend(negative.proof);
+                negative.security
+            }
+        };
+        Some((response, security))
+    }
+
+    /// Queue a prefetch, unless the same one is already waiting or running
+    fn queue_prefetch(&self, key: CacheKey) {
+        let mut prefetching = self.prefetching.lock().unwrap();
+        if prefetching.insert(key.clone()) && self.prefetch_queue.try_send(key.clone()).is_err() {
+            prefetching.remove(&key);
+        }
+    }
+
+    /// Refresh the RRsets queued for prefetching as they come, bypassing the cache. Meant to run
+    /// on threads of its own, so that clients keep being answered from the cache meanwhile.
+    pub fn run_prefetcher(&self) {
+        loop {
+            let key = match self.prefetch_requests.lock().unwrap().recv() {
+                Ok(key) => key,
+                Err(_) => return,
+            };
+            println!("prefetching {:?} {}", key.rtype, key.name);
+            let mut resolution = Resolution::new(&self.config);
+            if let Err(e) = self.fetch(&key.name, key.rtype, self.config.dnssec_validation, &mut resolution) {
+                println!("prefetch of {:?} {} failed: {}", key.rtype, key.name, e);
+            }
+            self.prefetching.lock().unwrap().remove(&key);
+        }
+    }
+
+    /// Store the RRsets of a response in the cache: the answers, along with the name servers
+    /// and glue records of referrals, ranked by credibility (RFC 2181 §5.4.1). NXDOMAIN and NODATA answers carrying the SOA record of the
+    /// zone are cached as negative answers for (`qname`, `qtype`). Signatures are kept with the RRsets they
+    /// cover, and `security` is the validation status of the answer, if it was validated.
+    fn cache_response(&self, qname: &str, qtype: RecordType, response: &DnsPacket, security: Option<Security>) {
+        self.cache_answers(qname, qtype, response, security);
+        self.cache_referral(response);
+    }
+
+    /// Store the answers of a response, or its negative answer, with their validation status
+    fn cache_answers(&self, qname: &str, qtype: RecordType, response: &DnsPacket, security: Option<Security>) {
+        let mut cache = self.cache.lock().unwrap();
+
+        if response.answers.is_empty() && matches!(response.header.rescode, ResultCode::NOERROR | ResultCode::NXDOMAIN) {
+            let soa = response.authorities.iter().find(|record| record.record_type() == RecordType::SOA);
+            if let Some(soa) = soa {
+                let proof = response.authorities
+                    .iter()
+                    .filter(|record| matches!(record.record_type(), RecordType::NSEC | RecordType::NSEC3 | RecordType::RRSIG))
+                    .cloned()
+                    .collect();
+                cache.insert_negative(qname, qtype, response.header.rescode, soa.clone(), proof, security);
+            }
+        }
+
+        if response.header.rescode != ResultCode::NOERROR {
+            return;
+        }
+        let answer_trust = match response.header.authoritative_answer {
+            true => Trust::AuthAnswer,
+            false => Trust::NonAuthAnswer,
+        };
+        for rrset in RRset::group(&response.answers) {
+            if rrset.rtype != RecordType::RRSIG {
+                let rrsigs = rrsigs_for(&response.answers, &rrset).into_iter().cloned().collect();
+                cache.insert_signed(rrset, rrsigs, answer_trust, security);
+            }
+        }
+    }
+
+    /// Store the name servers and glue records of a response. They are not signed, so they
+    /// are cached without waiting for the validation of the response.
+    fn cache_referral(&self, response: &DnsPacket) {
+        if response.header.rescode != ResultCode::NOERROR {
+            return;
+        }
+        let authority_trust = match response.header.authoritative_answer {
+            true => Trust::AuthAuthority,
+            false => Trust::Additional,
+        };
+        let mut cache = self.cache.lock().unwrap();
+        for rrset in RRset::group(&response.authorities) {
+            if rrset.rtype == RecordType::NS {
+                cache.insert(rrset, authority_trust);
+            }
+        }
+        for rrset in RRset::group(&response.resources) {
+            if rrset.rtype == RecordType::A || rrset.rtype == RecordType::AAAA {
+                cache.insert(rrset, Trust::Additional);
+            }
+        }
+    }
+
+    /// Resolve the addresses of a name server, following the address family policy. The less
+    /// preferred family is only queried when the preferred one gives nothing.
+    fn resolve_ns_addresses(&self, ns_name: &str, resolution: &mut Resolution) -> Vec<IpAddr> {
+        for qtype in self.config.ip_policy.address_types() {
+            if let Ok(response) = self.resolve_chain(ns_name, *qtype, false, resolution) {
+                let addrs = response.get_all_addresses();
+                if !addrs.is_empty() {
+                    return addrs;
+                }
+            }
+        }
+        Vec::new()
+    }
+
+    /// Send a single query over UDP, as `lookup` does. Servers that keep changing the case of the
+    /// query name are remembered in the infrastructure cache, and queried without DNS 0x20 for a while.
+    fn lookup_udp(&self, qname: &str, qtype: RecordType, server: (IpAddr, u16), timeout: Duration) -> Result<DnsPacket, SimpleError> {
+        let use_0x20 = self.infra.lock().unwrap().uses_0x20(&server.0);
+        let response = lookup_with_case(qname, qtype, server, timeout, use_0x20)?;
+        if use_0x20 {
+            self.infra.lock().unwrap().record_case(server.0, response.is_some());
+        }
+        match response {
+            Some(response) => Ok(response),
+            None => bail!("{}:{} did not preserve the case of {}", server.0, server.1, qname),
+        }
+    }
+
+    /// Send the query to each candidate server in turn until one gives a usable response, the fastest
+    /// servers first. Every unsuccessful round over the servers multiplies the timeout by the backoff factor.
+    /// With `check_loop`, the resolution fails when the query reaches a server it was already sent to.
+    fn query_servers(&self, qname: &str, qtype: RecordType, servers: &[IpAddr], check_loop: bool, resolution: &mut Resolution) -> Result<DnsPacket, SimpleError> {
+        let mut timeout = self.config.query_timeout;
+
+        resolution.check()?;
+
+        let servers = self.config.ip_policy.apply(self.infra.lock().unwrap().order(servers)?);
+
+        for attempt in 0..self.config.attempts {
+            for ns in &servers {
+                resolution.queries += 1;
+                if resolution.queries > self.config.max_upstream_queries {
+                    return Err(resolution.abort(format!("Too many upstream queries while resolving {}", qname)));
+                }
+                let remaining = resolution.deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    bail!("Resolution of {} timed out", qname);
+                }
+                // Retries of this query are not loops, only its first sending to each server is checked
+                if check_loop && attempt == 0 {
+                    resolution.check_loop(qname, qtype, *ns)?;
+                }
+
+                // A server that keeps failing over UDP may be behind a network dropping
+                // large or fragmented datagrams: it is asked over TCP instead
+                let udp_failures = self.infra.lock().unwrap().get(ns).map_or(0, |stats| stats.udp_failures);
+                let over_tcp = udp_failures >= self.config.udp_failures_before_tcp;
+                println!("attempting lookup of {:?} {} with ns {}{}", qtype, qname, ns, if over_tcp { " over TCP" } else { "" });
+                let start = Instant::now();
+                let result = match over_tcp {
+                    true => lookup_tcp(qname, qtype, (*ns, 53), timeout.min(remaining)),
+                    false => {
+                        let result = self.lookup_udp(qname, qtype, (*ns, 53), timeout.min(remaining));
+                        self.infra.lock().unwrap().record_udp(*ns, result.is_ok());
+                        result
+                    }
+                };
+                match result {
+                    // A server failing to process the query is treated like an unresponsive one
+                    Ok(response) if matches!(
+                        response.header.rescode,
+                        ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::NOTIMP | ResultCode::FORMERR
+                    ) => {
+                        println!("ns {} answered {:?}", ns, response.header.rescode);
+                        self.infra.lock().unwrap().record_failure(*ns);
+                    }
+                    Ok(response) => {
+                        self.infra.lock().unwrap().record_rtt(*ns, start.elapsed());
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        println!("ns {} failed: {}", ns, e);
+                        self.infra.lock().unwrap().record_failure(*ns);
+                    }
+                }
+            }
+            timeout = (timeout * self.config.backoff_factor).min(self.config.max_query_timeout);
+        }
+
+        bail!("No name server answered for {}", qname)
+    }
+}
+
+/// Check whether a record belongs to the final answer for `qtype` at `name`: records of the
+/// type and their signatures, or every record owned by `name` for an ANY query
+fn is_final_record(record: &DnsRecord, name: &str, qtype: RecordType) -> bool {
+    record.domain().eq_ignore_ascii_case(name) && match record {
+        _ if qtype == RecordType::ANY => true,
+        DnsRecord::RRSIG { type_covered, .. } => *type_covered == qtype,
+        _ => record.record_type() == qtype,
+    }
+}
+
+/// Check whether a cached answer is worth prefetching: returned at least `min_hits` times,
+/// zero disabling prefetching, with no more than `PREFETCH_PERCENT` of its TTL left
+fn needs_prefetch(cached: &CachedRRset, min_hits: u32) -> bool {
+    min_hits > 0 && cached.hits >= min_hits && cached.rrset.ttl() as u64 * 100 <= cached.original_ttl as u64 * PREFETCH_PERCENT as u64
+}
+
+/// RRSIG records of `answers` covering the RRsets of `records`
+fn signatures_of(records: &[DnsRecord], answers: &[DnsRecord]) -> Vec<DnsRecord> {
+    answers
+        .iter()
+        .filter(|rrsig| match rrsig {
+            DnsRecord::RRSIG { domain, type_covered, .. } => records
+                .iter()
+                .any(|record| record.record_type() == *type_covered && record.domain().eq_ignore_ascii_case(domain)),
+            _ => false,
+        })
+        .cloned()
+        .collect()
+}
+
+/// Name made of the labels of `known` plus the next label of `qname` towards the left.
+/// `known` is an ancestor of `qname`, the root being the empty name.
+fn child_name(qname: &str, known: &str) -> String {
+    let labels: Vec<&str> = qname.split('.').filter(|label| !label.is_empty()).collect();
+    let known_labels = known.split('.').filter(|label| !label.is_empty()).count();
+    let count = (known_labels + 1).min(labels.len());
+    labels[labels.len() - count..].join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn a(domain: &str) -> DnsRecord {
+        DnsRecord::A { domain: domain.to_string(), addr: Ipv4Addr::new(192, 0, 2, 1), ttl: 300 }
+    }
+
+    fn mx(domain: &str) -> DnsRecord {
+        DnsRecord::MX { domain: domain.to_string(), host: "mail.example.com".to_string(), priority: 10, ttl: 300 }
+    }
+
+    #[test]
+    fn repeated_lookup_is_a_loop() {
+        let mut resolution = Resolution::new(&ResolverConfig::default());
+        let first = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let second = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        assert!(resolution.check_loop("www.example.com", RecordType::A, first).is_ok());
+        assert!(resolution.check_loop("www.example.com", RecordType::A, second).is_ok());
+        assert!(resolution.check_loop("www.example.com", RecordType::AAAA, first).is_ok());
+        assert!(resolution.check_loop("mail.example.com", RecordType::A, first).is_ok());
+
+        assert!(resolution.check_loop("WWW.example.com", RecordType::A, first).is_err());
+        // The whole resolution stops
+        assert!(resolution.check().is_err());
+        assert!(resolution.check_loop("other.example.com", RecordType::A, first).is_err());
+    }
+
+    #[test]
+    fn only_queried_servers_are_recorded() {
+        // Nothing listens on these addresses, and the resolution stops after the first query
+        let resolver = Resolver::new(ResolverConfig {
+            query_timeout: Duration::from_millis(100),
+            attempts: 2,
+            max_upstream_queries: 1,
+            ..ResolverConfig::default()
+        });
+        let mut resolution = Resolution::new(&resolver.config);
+        let servers = [IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))];
+        assert!(resolver.query_servers("www.example.com", RecordType::A, &servers, true, &mut resolution).is_err());
+        assert_eq!(resolution.sent.len(), 1);
+    }
+
+    #[test]
+    fn failed_repriming_keeps_the_root_servers() {
+        // Nothing listens on the root server
+        let resolver = Resolver::new(ResolverConfig {
+            root_hints: RootHints::parse(". NS a.root.test.\na.root.test. A 127.0.0.1").unwrap(),
+            query_timeout: Duration::from_millis(100),
+            attempts: 1,
+            ..ResolverConfig::default()
+        });
+        assert_eq!(resolver.root_servers().unwrap(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        assert!(resolver.roots_expiry.lock().unwrap().is_none());
+
+        *resolver.roots_expiry.lock().unwrap() = Some(Instant::now());
+        assert_eq!(resolver.root_servers().unwrap(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        let expiry = resolver.roots_expiry.lock().unwrap().unwrap();
+        assert!(expiry > Instant::now() + PRIMING_RETRY_INTERVAL / 2);
+    }
+
+    /// Answer the queries received on a local UDP port with `respond`, which gets the query
+    pub(super) fn udp_server(respond: fn(DnsPacket) -> DnsPacket) -> (IpAddr, u16) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || loop {
+            let mut buffer = BytePacketBuffer::new();
+            let (_, src) = socket.recv_from(&mut buffer.buf).unwrap();
+            let mut response = respond(DnsPacket::from_buffer(&mut buffer).unwrap());
+            response.header.response = true;
+            let mut out = BytePacketBuffer::new();
+            response.write(&mut out).unwrap();
+            socket.send_to(&out.buf[..out.pos], src).unwrap();
+        });
+        (addr.ip(), addr.port())
+    }
+
+    /// Answer the queries received on a local TCP port with `respond`, which gets the query
+    fn tcp_server(port: u16, respond: fn(DnsPacket) -> DnsPacket) {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+        std::thread::spawn(move || loop {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = read_message(&mut stream).unwrap();
+            let mut response = respond(DnsPacket::from_buffer(&mut buffer).unwrap());
+            response.header.response = true;
+            let mut out = BytePacketBuffer::new();
+            response.write(&mut out).unwrap();
+            write_message(&mut stream, &out.buf[..out.pos]).unwrap();
+        });
+    }
+
+    fn truncated(mut query: DnsPacket) -> DnsPacket {
+        query.header.truncated_message = true;
+        query
+    }
+
+    fn answered(mut query: DnsPacket) -> DnsPacket {
+        query.answers.push(a(&query.questions[0].name));
+        query
+    }
+
+    #[test]
+    fn truncated_responses_are_retried_over_tcp() {
+        let server = udp_server(truncated);
+        tcp_server(server.1, answered);
+        let response = lookup("www.example.com", RecordType::A, server, Duration::from_secs(1)).unwrap();
+        assert!(!response.header.truncated_message);
+        assert_eq!(response.answers, vec![a("www.example.com")]);
+    }
+
+    #[test]
+    fn servers_failing_over_udp_are_queried_over_tcp() {
+        // Nothing listens on the server, over UDP or TCP
+        let resolver = Resolver::new(ResolverConfig {
+            query_timeout: Duration::from_millis(100),
+            attempts: 4,
+            udp_failures_before_tcp: 2,
+            ..ResolverConfig::default()
+        });
+        let mut resolution = Resolution::new(&resolver.config);
+        let server = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(resolver.query_servers("www.example.com", RecordType::A, &[server], false, &mut resolution).is_err());
+
+        // The first two attempts went over UDP, the next ones over TCP
+        let stats = resolver.infra.lock().unwrap().get(&server).unwrap();
+        assert_eq!(stats.udp_failures, 2);
+        assert_eq!(stats.failures, 4);
+
+        // A response over UDP resets the count
+        resolver.infra.lock().unwrap().record_udp(server, true);
+        assert_eq!(resolver.infra.lock().unwrap().get(&server).unwrap().udp_failures, 0);
+    }
+
+    fn uppercase_question(mut query: DnsPacket) -> DnsPacket {
+        query.questions[0].name = query.questions[0].name.to_ascii_uppercase();
+        query
+    }
+
+    #[test]
+    fn case_mismatches_disable_0x20_for_the_server() {
+        let server = udp_server(uppercase_question);
+        let qname = "www.a-rather-long-example-name.com";
+        assert!(lookup(qname, RecordType::A, server, Duration::from_secs(1)).is_err());
+
+        // The server is not queried without 0x20 until it failed several times in a row
+        let resolver = Resolver::default();
+        let mut failures = 0;
+        while resolver.lookup_udp(qname, RecordType::A, server, Duration::from_secs(1)).is_err() {
+            failures += 1;
+            assert!(failures < 10);
+        }
+        assert!(failures > 1);
+        assert!(!resolver.infra.lock().unwrap().uses_0x20(&server.0));
+    }
+
+    #[test]
+    fn final_records_of_the_query_type() {
+        assert!(is_final_record(&a("Example.com"), "example.com", RecordType::A));
+        assert!(!is_final_record(&mx("example.com"), "example.com", RecordType::A));
+        assert!(!is_final_record(&a("www.example.com"), "example.com", RecordType::A));
+    }
+
+    #[test]
+    fn any_query_keeps_every_record_of_the_name() {
+        assert!(is_final_record(&a("example.com"), "example.com", RecordType::ANY));
+        assert!(is_final_record(&mx("example.com"), "example.com", RecordType::ANY));
+        assert!(!is_final_record(&a("www.example.com"), "example.com", RecordType::ANY));
+
+        let mut response = DnsPacket::new();
+        response.answers.push(mx("example.com"));
+        assert!(response.has_answer("example.com", RecordType::ANY));
+        assert!(!response.has_answer("example.com", RecordType::A));
+    }
+
+    fn cached(ttl: u32, original_ttl: u32, hits: u32) -> CachedRRset {
+        let mut rrset = RRset::group(&[a("example.com")]).remove(0);
+        rrset.set_ttl(ttl);
+        CachedRRset { rrset, rrsigs: Vec::new(), security: None, original_ttl, hits }
+    }
+
+    #[test]
+    fn popular_answers_close_to_expiration_are_prefetched() {
+        assert!(needs_prefetch(&cached(30, 300, 3), 3));
+        assert!(needs_prefetch(&cached(0, 300, 10), 3));
+        // Not popular enough
+        assert!(!needs_prefetch(&cached(30, 300, 2), 3));
+        // More than 10% of the TTL left
+        assert!(!needs_prefetch(&cached(31, 300, 3), 3));
+        // Prefetching disabled
+        assert!(!needs_prefetch(&cached(0, 300, 10), 0));
+    }
+
+    #[test]
+    fn only_the_delegation_is_cached_before_validation() {
+        let resolver = Resolver::default();
+        let mut response = DnsPacket::new();
+        response.authorities.push(DnsRecord::NS { domain: "example.com".to_string(), host: "ns1.example.com".to_string(), ttl: 3600 });
+        response.authorities.push(DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+            ttl: 3600,
+        });
+        response.resources.push(a("ns1.example.com"));
+        resolver.cache_referral(&response);
+
+        let cache = resolver.cache.lock().unwrap();
+        assert!(cache.get("example.com", RecordType::NS).is_some());
+        assert_eq!(cache.addresses("ns1.example.com"), [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+        assert!(cache.get_negative("www.example.com", RecordType::A).is_none());
+    }
+}
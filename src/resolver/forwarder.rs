@@ -0,0 +1,385 @@
+//! Forwarding mode: queries are sent to configured upstream resolvers instead of walking the
+//! delegation chain from the root servers
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use simple_error::SimpleError;
+use crate::{DnsPacket, RecordType, ResultCode};
+use super::{HttpsUpstream, Resolution, Resolver, TlsUpstream};
+
+/// RTT assumed for an upstream that failed before answering, in milliseconds, so that the
+/// fastest strategy puts it after the ones that answer
+const FAILED_UPSTREAM_RTT: u32 = 1000;
+/// Upper bound of the smoothed RTT, in milliseconds
+const MAX_RTT: u32 = 120_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Order in which the upstream resolvers are tried
+pub enum ForwardStrategy {
+    /// Always in the configured order, the next ones being backups
+    Sequential,
+    /// Starting from the next upstream at each query, to spread the load
+    RoundRobin,
+    /// By increasing smoothed round trip time
+    Fastest,
+}
+
+impl FromStr for ForwardStrategy {
+    type Err = SimpleError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "sequential" => Ok(ForwardStrategy::Sequential),
+            "round-robin" => Ok(ForwardStrategy::RoundRobin),
+            "fastest" => Ok(ForwardStrategy::Fastest),
+            _ => bail!("Unknown forwarding strategy {:?}", text),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+/// Upstream resolver, along with the transport used to reach it
+pub enum Upstream {
+    /// Plain DNS, over UDP with TCP for truncated responses
+    Udp(SocketAddr),
+    /// DNS over TLS (RFC 7858)
+    Tls(TlsUpstream),
+    /// DNS over HTTPS (RFC 8484)
+    Https(HttpsUpstream),
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Udp(addr) => write!(f, "{}", addr),
+            Upstream::Tls(upstream) => write!(f, "{}", upstream),
+            Upstream::Https(upstream) => write!(f, "{}", upstream),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Settings of the forwarding mode
+pub struct ForwardConfig {
+    /// Upstream resolvers
+    pub upstreams: Vec<Upstream>,
+    pub strategy: ForwardStrategy,
+    /// Number of rounds over the upstreams before giving up
+    pub attempts: usize,
+    /// Consecutive failures after which an upstream is marked down
+    pub max_failures: u32,
+    /// Time an upstream marked down is skipped, unless every upstream is down
+    pub down_time: Duration,
+    /// Resolve from the root servers when no upstream answers
+    pub fallback_to_recursion: bool,
+}
+
+impl ForwardConfig {
+    pub fn new(upstreams: Vec<Upstream>, strategy: ForwardStrategy) -> ForwardConfig {
+        ForwardConfig {
+            upstreams,
+            strategy,
+            attempts: 2,
+            max_failures: 3,
+            down_time: Duration::from_secs(30),
+            fallback_to_recursion: true,
+        }
+    }
+}
+
+/// Parse an upstream resolver: an address, the port being 53 unless given, `tls://` followed
+/// by the settings parsed by `TlsUpstream::parse`, or `https://` followed by the ones parsed
+/// by `HttpsUpstream::parse`
+pub fn parse_upstream(text: &str) -> Result<Upstream, SimpleError> {
+    if let Some(tls) = text.strip_prefix("tls://") {
+        return TlsUpstream::parse(tls).map(Upstream::Tls);
+    }
+    if let Some(https) = text.strip_prefix("https://") {
+        return HttpsUpstream::parse(https).map(Upstream::Https);
+    }
+    if let Ok(addr) = text.parse::<SocketAddr>() {
+        return Ok(Upstream::Udp(addr));
+    }
+    let ip = text.parse::<IpAddr>().map_err(|e| SimpleError::with("Invalid upstream address", e))?;
+    Ok(Upstream::Udp(SocketAddr::new(ip, 53)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// What is known about an upstream resolver
+pub struct UpstreamHealth {
+    /// Smoothed round trip time, in milliseconds
+    pub srtt: u32,
+    /// Number of consecutive queries left unanswered or failed
+    pub failures: u32,
+    /// Time until which the upstream is skipped
+    pub down_until: Option<Instant>,
+}
+
+/// Health of the upstream resolvers, and position of the round-robin
+#[derive(Default)]
+pub(super) struct Upstreams {
+    health: Mutex<HashMap<Upstream, UpstreamHealth>>,
+    next: AtomicUsize,
+}
+
+impl Upstreams {
+    /// Upstreams in the order they should be tried. The ones marked down are left out,
+    /// unless all of them are.
+    fn order(&self, config: &ForwardConfig) -> Vec<Upstream> {
+        let mut upstreams = config.upstreams.clone();
+        let health = self.health.lock().unwrap();
+        match config.strategy {
+            ForwardStrategy::Sequential => {}
+            ForwardStrategy::RoundRobin if !upstreams.is_empty() => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % upstreams.len();
+                upstreams.rotate_left(start);
+            }
+            ForwardStrategy::RoundRobin => {}
+            // Upstreams never queried come first, so that their round trip time gets measured
+            ForwardStrategy::Fastest => upstreams.sort_by_key(|upstream| health.get(upstream).map_or(0, |stats| stats.srtt)),
+        }
+
+        let now = Instant::now();
+        let up: Vec<Upstream> = upstreams
+            .iter()
+            .filter(|upstream| health.get(upstream).is_none_or(|stats| stats.down_until.is_none_or(|until| until <= now)))
+            .cloned()
+            .collect();
+        if up.is_empty() { upstreams } else { up }
+    }
+
+    /// Record an answer from an upstream: the smoothed RTT moves by 1/8 of the difference
+    /// and the upstream is up again
+    fn record_rtt(&self, upstream: &Upstream, rtt: Duration) {
+        let rtt = rtt.as_millis().min(MAX_RTT as u128) as u32;
+        let mut health = self.health.lock().unwrap();
+        let srtt = match health.get(upstream) {
+            Some(stats) if stats.srtt > 0 => (stats.srtt * 7 + rtt) / 8,
+            _ => rtt,
+        };
+        health.insert(upstream.clone(), UpstreamHealth { srtt, failures: 0, down_until: None });
+    }
+
+    /// Record a failure of an upstream, marking it down after `max_failures` in a row
+    fn record_failure(&self, upstream: &Upstream, config: &ForwardConfig) {
+        let mut health = self.health.lock().unwrap();
+        let stats = health.entry(upstream.clone()).or_insert(UpstreamHealth { srtt: 0, failures: 0, down_until: None });
+        stats.failures += 1;
+        stats.srtt = stats.srtt.saturating_mul(2).clamp(FAILED_UPSTREAM_RTT, MAX_RTT);
+        if stats.failures >= config.max_failures && stats.down_until.is_none_or(|until| until <= Instant::now()) {
+            println!("upstream {} marked down for {:?}", upstream, config.down_time);
+            stats.down_until = Some(Instant::now() + config.down_time);
+        }
+    }
+
+    /// Health of the upstreams queried so far
+    fn snapshot(&self) -> Vec<(Upstream, UpstreamHealth)> {
+        self.health.lock().unwrap().iter().map(|(upstream, stats)| (upstream.clone(), *stats)).collect()
+    }
+}
+
+impl Resolver {
+    /// Health of the upstream resolvers queried so far, in forwarding mode
+    pub fn upstream_health(&self) -> Vec<(Upstream, UpstreamHealth)> {
+        self.upstreams.snapshot()
+    }
+
+    /// Send the query to the upstream resolvers, following the strategy, until one of them answers.
+    /// Every unsuccessful round over the upstreams multiplies the timeout by the backoff factor.
+    pub(super) fn forward(&self, config: &ForwardConfig, qname: &str, qtype: RecordType, resolution: &mut Resolution) -> Result<DnsPacket, SimpleError> {
+        let mut timeout = self.config.query_timeout;
+        let upstreams = self.upstreams.order(config);
+
+        for _ in 0..config.attempts {
+            for upstream in &upstreams {
+                resolution.queries += 1;
+                if resolution.queries > self.config.max_upstream_queries {
+                    return Err(resolution.abort(format!("Too many upstream queries while resolving {}", qname)));
+                }
+                let remaining = resolution.deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    bail!("Resolution of {} timed out", qname);
+                }
+
+                println!("forwarding {:?} {} to {}", qtype, qname, upstream);
+                let start = Instant::now();
+                let response = match upstream {
+                    Upstream::Udp(addr) => self.lookup_udp(qname, qtype, (addr.ip(), addr.port()), timeout.min(remaining)),
+                    Upstream::Tls(tls) => self.tls.lookup(tls, qname, qtype, timeout.min(remaining)),
+                    Upstream::Https(https) => self.https.lookup(https, qname, qtype, timeout.min(remaining)),
+                };
+                match response {
+                    Ok(response) if matches!(response.header.rescode, ResultCode::NOERROR | ResultCode::NXDOMAIN) => {
+                        self.upstreams.record_rtt(upstream, start.elapsed());
+                        return Ok(response);
+                    }
+                    Ok(response) => {
+                        println!("upstream {} answered {:?}", upstream, response.header.rescode);
+                        self.upstreams.record_failure(upstream, config);
+                    }
+                    Err(e) => {
+                        println!("upstream {} failed: {}", upstream, e);
+                        self.upstreams.record_failure(upstream, config);
+                    }
+                }
+            }
+            timeout = (timeout * self.config.backoff_factor).min(self.config.max_query_timeout);
+        }
+
+        bail!("No upstream resolver answered for {}", qname)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::thread;
+    use crate::DnsRecord;
+    use super::*;
+    use super::super::{ResolverConfig, RootHints};
+    use super::super::tests::udp_server;
+
+    fn udp(last: u8) -> Upstream {
+        Upstream::Udp(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)), 53))
+    }
+
+    fn config(strategy: ForwardStrategy) -> ForwardConfig {
+        ForwardConfig::new(vec![udp(1), udp(2), udp(3)], strategy)
+    }
+
+    #[test]
+    fn sequential_order_is_the_configured_one() {
+        let (upstreams, config) = (Upstreams::default(), config(ForwardStrategy::Sequential));
+        for _ in 0..3 {
+            assert_eq!(upstreams.order(&config), vec![udp(1), udp(2), udp(3)]);
+        }
+    }
+
+    #[test]
+    fn round_robin_starts_from_the_next_upstream() {
+        let (upstreams, config) = (Upstreams::default(), config(ForwardStrategy::RoundRobin));
+        assert_eq!(upstreams.order(&config), vec![udp(1), udp(2), udp(3)]);
+        assert_eq!(upstreams.order(&config), vec![udp(2), udp(3), udp(1)]);
+        assert_eq!(upstreams.order(&config), vec![udp(3), udp(1), udp(2)]);
+        assert_eq!(upstreams.order(&config), vec![udp(1), udp(2), udp(3)]);
+    }
+
+    #[test]
+    fn fastest_order_puts_upstreams_never_queried_first() {
+        let (upstreams, config) = (Upstreams::default(), config(ForwardStrategy::Fastest));
+        upstreams.record_rtt(&udp(1), Duration::from_millis(50));
+        upstreams.record_rtt(&udp(2), Duration::from_millis(10));
+        assert_eq!(upstreams.order(&config), vec![udp(3), udp(2), udp(1)]);
+        // A failure is slower than any answer
+        upstreams.record_failure(&udp(3), &config);
+        assert_eq!(upstreams.order(&config), vec![udp(2), udp(1), udp(3)]);
+    }
+
+    #[test]
+    fn rtt_is_smoothed_and_resets_the_failures() {
+        let (upstreams, config) = (Upstreams::default(), config(ForwardStrategy::Sequential));
+        let health = |upstreams: &Upstreams| upstreams.snapshot().into_iter().find(|(upstream, _)| *upstream == udp(1)).unwrap().1;
+        upstreams.record_rtt(&udp(1), Duration::from_millis(80));
+        assert_eq!(health(&upstreams).srtt, 80);
+        upstreams.record_rtt(&udp(1), Duration::from_millis(160));
+        assert_eq!(health(&upstreams).srtt, 90);
+
+        upstreams.record_failure(&udp(1), &config);
+        assert_eq!(health(&upstreams), UpstreamHealth { srtt: 1000, failures: 1, down_until: None });
+        upstreams.record_rtt(&udp(1), Duration::from_millis(200));
+        assert_eq!(health(&upstreams), UpstreamHealth { srtt: 900, failures: 0, down_until: None });
+    }
+
+    #[test]
+    fn upstream_is_skipped_once_marked_down() {
+        let upstreams = Upstreams::default();
+        let config = ForwardConfig { down_time: Duration::from_millis(200), ..config(ForwardStrategy::Sequential) };
+        for _ in 0..config.max_failures - 1 {
+            upstreams.record_failure(&udp(1), &config);
+        }
+        assert_eq!(upstreams.order(&config), vec![udp(1), udp(2), udp(3)]);
+        upstreams.record_failure(&udp(1), &config);
+        assert_eq!(upstreams.order(&config), vec![udp(2), udp(3)]);
+
+        // Back once the down time is over, or as soon as it answers
+        thread::sleep(config.down_time);
+        assert_eq!(upstreams.order(&config), vec![udp(1), udp(2), udp(3)]);
+        upstreams.record_failure(&udp(1), &config);
+        assert_eq!(upstreams.order(&config), vec![udp(2), udp(3)]);
+        upstreams.record_rtt(&udp(1), Duration::from_millis(10));
+        assert_eq!(upstreams.order(&config), vec![udp(1), udp(2), udp(3)]);
+    }
+
+    #[test]
+    fn every_upstream_is_tried_when_all_are_down() {
+        let (upstreams, config) = (Upstreams::default(), config(ForwardStrategy::Sequential));
+        for upstream in &config.upstreams {
+            for _ in 0..config.max_failures {
+                upstreams.record_failure(upstream, &config);
+            }
+        }
+        assert!(upstreams.snapshot().iter().all(|(_, stats)| stats.down_until.is_some()));
+        assert_eq!(upstreams.order(&config), vec![udp(1), udp(2), udp(3)]);
+    }
+
+    fn answered(mut query: DnsPacket) -> DnsPacket {
+        let domain = query.questions[0].name.clone();
+        query.answers.push(DnsRecord::A { domain, addr: Ipv4Addr::new(192, 0, 2, 1), ttl: 300 });
+        query
+    }
+
+    fn server_failure(mut query: DnsPacket) -> DnsPacket {
+        query.header.rescode = ResultCode::SERVFAIL;
+        query
+    }
+
+    fn refused(mut query: DnsPacket) -> DnsPacket {
+        query.header.rescode = ResultCode::REFUSED;
+        query
+    }
+
+    fn stub(respond: fn(DnsPacket) -> DnsPacket) -> Upstream {
+        let (ip, port) = udp_server(respond);
+        Upstream::Udp(SocketAddr::new(ip, port))
+    }
+
+    #[test]
+    fn failing_upstreams_are_skipped() {
+        let (failing, refusing, answering) = (stub(server_failure), stub(refused), stub(answered));
+        let config = ForwardConfig::new(vec![failing.clone(), refusing.clone(), answering.clone()], ForwardStrategy::Sequential);
+        let resolver = Resolver::new(ResolverConfig { forward: Some(config.clone()), ..ResolverConfig::default() });
+        let mut resolution = Resolution::new(&resolver.config);
+
+        let response = resolver.forward(&config, "www.example.com", RecordType::A, &mut resolution).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        let health: HashMap<Upstream, UpstreamHealth> = resolver.upstream_health().into_iter().collect();
+        assert_eq!(health[&failing].failures, 1);
+        assert_eq!(health[&refusing].failures, 1);
+        assert_eq!(health[&answering].failures, 0);
+    }
+
+    #[test]
+    fn recursion_is_the_fallback_when_no_upstream_answers() {
+        // The root server does not answer either, it only has to be queried
+        let root = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        for fallback_to_recursion in [false, true] {
+            let config = ForwardConfig { fallback_to_recursion, attempts: 1, ..ForwardConfig::new(vec![stub(server_failure)], ForwardStrategy::Sequential) };
+            let resolver = Resolver::new(ResolverConfig {
+                root_hints: RootHints::parse(". NS a.root.test.\na.root.test. A 127.0.0.2").unwrap(),
+                forward: Some(config),
+                query_timeout: Duration::from_millis(100),
+                attempts: 1,
+                dnssec_validation: false,
+                ..ResolverConfig::default()
+            });
+            let error = resolver.recursive_lookup("www.example.com", RecordType::A).unwrap_err();
+            assert_eq!(error.as_str().contains("No upstream resolver answered"), !fallback_to_recursion);
+            assert_eq!(resolver.infra.lock().unwrap().get(&root).is_some(), fallback_to_recursion);
+        }
+    }
+}
@@ -0,0 +1,767 @@
+//! DNS over HTTPS upstream client (RFC 8484). Each upstream gets a persistent HTTP/2
+//! connection (RFC 9113), every query being sent on a stream of its own, with GET or POST.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use fluke_hpack::{Decoder, Encoder};
+use simple_error::SimpleError;
+use crate::{BytePacketBuffer, DnsPacket, RecordType};
+use super::tls::{connect, TlsStream, IDLE_TIMEOUT};
+use super::{build_query, validate_response, TlsUpstream};
+
+/// Port of HTTPS
+const HTTPS_PORT: u16 = 443;
+/// Media type of DNS messages
+const DNS_MESSAGE: &str = "application/dns-message";
+/// First bytes sent on an HTTP/2 connection
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Initial size of the flow control windows, and largest frame payload accepted (RFC 9113 §6.5.2)
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_FRAME_SIZE: usize = 16_384;
+/// Stream ids are 31 bits long
+const MAX_STREAM_ID: u32 = 0x7fff_ffff;
+
+// Frame types (RFC 9113 §6)
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+// Settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+
+/// Error code of RST_STREAM for a request the client gave up on
+const CANCEL: u32 = 0x8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+/// How the query is carried in the HTTP request
+pub enum HttpMethod {
+    /// In the `dns` parameter of the URL, encoded in base64url
+    Get,
+    /// As the body of the request
+    Post,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+/// Upstream resolver reached over HTTPS
+pub struct HttpsUpstream {
+    /// Address and authentication of the server
+    pub tls: TlsUpstream,
+    /// Path of the DoH endpoint, `/dns-query` usually
+    pub path: String,
+    pub method: HttpMethod,
+}
+
+impl HttpsUpstream {
+    /// Parse `address[:port][/path][#name-or-pin,...]`, the port being 443 and the path
+    /// `/dns-query` unless given. A path ending with the `{?dns}` URI template (RFC 8484 §4.1)
+    /// selects GET, POST being used otherwise. The authentication is the one of `TlsUpstream::parse`.
+    pub fn parse(text: &str) -> Result<HttpsUpstream, SimpleError> {
+        let (url, auth) = text.split_once('#').unwrap_or((text, ""));
+        let (address, path) = match url.find('/') {
+            Some(start) => url.split_at(start),
+            None => (url, "/dns-query"),
+        };
+        let (path, method) = match path.strip_suffix("{?dns}") {
+            Some(path) => (path, HttpMethod::Get),
+            None => (path, HttpMethod::Post),
+        };
+        let tls = TlsUpstream::parse_with_port(&format!("{}#{}", address, auth), HTTPS_PORT)?;
+        Ok(HttpsUpstream { tls, path: path.to_string(), method })
+    }
+
+    /// Value of the `:authority` pseudo-header: the authentication name, or else the address
+    fn authority(&self) -> String {
+        let host = match &self.tls.auth_name {
+            Some(name) => name.clone(),
+            None if self.tls.addr.is_ipv6() => format!("[{}]", self.tls.addr.ip()),
+            None => self.tls.addr.ip().to_string(),
+        };
+        match self.tls.addr.port() {
+            HTTPS_PORT => host,
+            port => format!("{}:{}", host, port),
+        }
+    }
+}
+
+impl fmt::Display for HttpsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "https://{}{}", self.tls.addr, self.path)?;
+        if self.method == HttpMethod::Get {
+            write!(f, "{{?dns}}")?;
+        }
+        match &self.tls.auth_name {
+            Some(name) => write!(f, "#{}", name),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Request handed over to the thread of a connection
+struct Request {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    deadline: Instant,
+    /// Receives the response. Dropped without a response when the request fails.
+    reply: mpsc::Sender<Response>,
+}
+
+/// Response to a request, along with its headers, names in lowercase
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Time the response stays fresh (RFC 9111 §4.2.1): its max-age, less its age
+    fn freshness(&self) -> Option<u32> {
+        let max_age = self
+            .header("cache-control")?
+            .split(',')
+            .find_map(|directive| directive.trim().strip_prefix("max-age="))
+            .and_then(|value| value.trim_matches('"').parse::<u32>().ok())?;
+        let age = self.header("age").and_then(|value| value.trim().parse::<u32>().ok()).unwrap_or(0);
+        Some(max_age.saturating_sub(age))
+    }
+}
+
+#[derive(Clone)]
+/// Persistent connection to an upstream, served by its own thread
+struct HttpsConnection {
+    requests: mpsc::Sender<Request>,
+    closed: Arc<AtomicBool>,
+}
+
+/// Connections to the HTTPS upstreams
+#[derive(Default)]
+pub(super) struct HttpsConnections {
+    connections: Mutex<HashMap<HttpsUpstream, HttpsConnection>>,
+}
+
+impl HttpsConnections {
+    /// Send a query for `qname` to `upstream` and wait at most `timeout` for its response.
+    /// The TTLs of the response are capped by its HTTP freshness lifetime (RFC 8484 §5.1).
+    pub(super) fn lookup(&self, upstream: &HttpsUpstream, qname: &str, qtype: RecordType, timeout: Duration) -> Result<DnsPacket, SimpleError> {
+        let deadline = Instant::now() + timeout;
+        // The id is 0, so that identical queries give identical requests (RFC 8484 §4.1)
+        let mut packet = build_query(qname, qtype)?;
+        packet.header.id = 0;
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer)?;
+        let message = &req_buffer.buf[..req_buffer.pos];
+
+        let mut headers = vec![
+            (":method".to_string(), String::new()),
+            (":scheme".to_string(), "https".to_string()),
+            (":authority".to_string(), upstream.authority()),
+            (":path".to_string(), upstream.path.clone()),
+            ("accept".to_string(), DNS_MESSAGE.to_string()),
+        ];
+        let body = match upstream.method {
+            HttpMethod::Get => {
+                headers[0].1 = "GET".to_string();
+                let separator = if upstream.path.contains('?') { '&' } else { '?' };
+                headers[3].1 = format!("{}{}dns={}", upstream.path, separator, encode_base64url(message));
+                Vec::new()
+            }
+            HttpMethod::Post => {
+                headers[0].1 = "POST".to_string();
+                headers.push(("content-type".to_string(), DNS_MESSAGE.to_string()));
+                headers.push(("content-length".to_string(), message.len().to_string()));
+                message.to_vec()
+            }
+        };
+
+        for reused in [true, false] {
+            let (reply, response) = mpsc::channel();
+            let request = Request { headers: headers.clone(), body: body.clone(), deadline, reply };
+            let connection = self.connection(upstream, deadline)?;
+            let (sent, closed) = (connection.requests.send(request).is_ok(), connection.closed);
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match (sent, response.recv_timeout(remaining)) {
+                (true, Ok(response)) => return read_response(upstream, &packet, response),
+                (_, Err(RecvTimeoutError::Timeout)) => break,
+                // The connection went down before the response came, a new one is tried. It is
+                // marked closed here as its thread may not have finished yet.
+                _ if reused => {
+                    closed.store(true, Ordering::Relaxed);
+                    println!("connection to {} closed, reconnecting", upstream);
+                }
+                _ => break,
+            }
+        }
+        bail!("No response from {}", upstream)
+    }
+
+    /// Usable connection to `upstream`, or else a new one. It is opened without holding
+    /// the lock, so that a slow upstream does not hold up the lookups to the others.
+    fn connection(&self, upstream: &HttpsUpstream, deadline: Instant) -> Result<HttpsConnection, SimpleError> {
+        let usable = |connection: &&HttpsConnection| !connection.closed.load(Ordering::Relaxed);
+        if let Some(connection) = self.connections.lock().unwrap().get(upstream).filter(usable) {
+            return Ok(connection.clone());
+        }
+        let opened = HttpsConnection::open(upstream, deadline)?;
+
+        // Another lookup may have connected meanwhile, the connection opened last is then
+        // dropped and its thread ends
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get(upstream).filter(usable) {
+            Some(connection) => Ok(connection.clone()),
+            None => {
+                connections.insert(upstream.clone(), opened.clone());
+                Ok(opened)
+            }
+        }
+    }
+}
+
+/// Check that `response` carries the answer to `query`, and decode it
+fn read_response(upstream: &HttpsUpstream, query: &DnsPacket, response: Response) -> Result<DnsPacket, SimpleError> {
+    if response.status != 200 {
+        bail!("{} answered with HTTP status {}", upstream, response.status);
+    }
+    let media_type = response.header("content-type").and_then(|value| value.split(';').next()).map(str::trim);
+    if !media_type.is_some_and(|media_type| media_type.eq_ignore_ascii_case(DNS_MESSAGE)) {
+        bail!("{} answered with content type {:?}", upstream, media_type);
+    }
+
+    validate_response(&response.body, upstream.tls.addr, query, upstream.tls.addr)?;
+    let mut packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&response.body))?;
+    if let Some(freshness) = response.freshness() {
+        for record in packet.answers.iter_mut().chain(packet.authorities.iter_mut()).chain(packet.resources.iter_mut()) {
+            record.set_ttl(record.ttl().min(freshness));
+        }
+    }
+    Ok(packet)
+}
+
+impl HttpsConnection {
+    /// Connect to the upstream, negotiating HTTP/2, then hand the connection over to a thread
+    fn open(upstream: &HttpsUpstream, deadline: Instant) -> Result<HttpsConnection, SimpleError> {
+        let mut stream = connect(&upstream.tls, &[b"h2"], deadline)?;
+        if stream.conn.alpn_protocol() != Some(b"h2") {
+            bail!("{} does not support HTTP/2", upstream);
+        }
+        let mut preface = PREFACE.to_vec();
+        write_frame(&mut preface, SETTINGS, 0, 0, &[0, SETTINGS_ENABLE_PUSH as u8, 0, 0, 0, 0]);
+        stream.write_all(&preface).map_err(|e| SimpleError::with("Error sending the connection preface", e))?;
+        stream.flush().map_err(|e| SimpleError::with("Error sending the connection preface", e))?;
+        println!("connected to {}", upstream);
+
+        let (requests, pending) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let thread_closed = closed.clone();
+        let name = upstream.to_string();
+        thread::spawn(move || {
+            if let Err(e) = Http2Connection::new(stream, thread_closed.clone()).serve(pending) {
+                println!("connection to {} failed: {}", name, e);
+            }
+            thread_closed.store(true, Ordering::Relaxed);
+        });
+        Ok(HttpsConnection { requests, closed })
+    }
+}
+
+/// Request sent on a stream, and what was received of its response
+struct Stream {
+    request: Request,
+    status: Option<u16>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// Client side of an HTTP/2 connection
+struct Http2Connection {
+    stream: TlsStream,
+    /// Set once the server sent GOAWAY: no new request is sent, and the connection is closed
+    /// when the streams in progress complete
+    closed: Arc<AtomicBool>,
+    encoder: Encoder<'static>,
+    decoder: Decoder<'static>,
+    streams: HashMap<u32, Stream>,
+    /// Requests waiting for a stream
+    waiting: VecDeque<Request>,
+    next_stream_id: u32,
+    /// Header block being received, split over CONTINUATION frames: its stream, its flags and its fragments
+    header_block: Option<(u32, u8, Vec<u8>)>,
+    /// Flow control window of the connection, and initial window of new streams, for sending
+    send_window: i64,
+    stream_window: i64,
+    max_streams: usize,
+    /// Frames to send
+    output: Vec<u8>,
+}
+
+impl Http2Connection {
+    fn new(stream: TlsStream, closed: Arc<AtomicBool>) -> Http2Connection {
+        Http2Connection {
+            stream,
+            closed,
+            encoder: Encoder::new(),
+            decoder: Decoder::new(),
+            streams: HashMap::new(),
+            waiting: VecDeque::new(),
+            next_stream_id: 1,
+            header_block: None,
+            send_window: DEFAULT_WINDOW,
+            stream_window: DEFAULT_WINDOW,
+            max_streams: usize::MAX,
+            output: Vec::new(),
+        }
+    }
+
+    /// Send the requests received on `pending` as they come, and hand the responses over,
+    /// until the connection fails, is closed by the server, or stays idle for `IDLE_TIMEOUT`
+    fn serve(mut self, pending: mpsc::Receiver<Request>) -> Result<(), SimpleError> {
+        let mut received = Vec::new();
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            let going_away = self.closed.load(Ordering::Relaxed);
+            if going_away && self.streams.is_empty() {
+                return Ok(());
+            }
+
+            // Queue the new requests. With nothing in progress, wait for one until the idle timeout.
+            if self.streams.is_empty() && self.waiting.is_empty() {
+                match pending.recv_timeout(IDLE_TIMEOUT) {
+                    Ok(request) => self.waiting.push_back(request),
+                    Err(_) => return Ok(()),
+                }
+            }
+            loop {
+                match pending.try_recv() {
+                    Ok(request) => self.waiting.push_back(request),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) if self.streams.is_empty() => return Ok(()),
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            }
+            if going_away {
+                // Dropping the requests makes their senders try another connection
+                self.waiting.clear();
+            }
+            self.start_requests();
+            self.flush()?;
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => bail!("closed by the server"),
+                Ok(len) => received.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+                Err(e) => return Err(SimpleError::with("Error receiving", e)),
+            }
+            while received.len() >= 9 {
+                let len = (received[0] as usize) << 16 | (received[1] as usize) << 8 | received[2] as usize;
+                if len > MAX_FRAME_SIZE {
+                    bail!("frame of {} bytes is too large", len);
+                }
+                if received.len() < len + 9 {
+                    break;
+                }
+                let frame: Vec<u8> = received.drain(..len + 9).collect();
+                let stream_id = u32::from_be_bytes([frame[5], frame[6], frame[7], frame[8]]) & MAX_STREAM_ID;
+                self.handle_frame(frame[3], frame[4], stream_id, &frame[9..])?;
+            }
+
+            // Cancel the requests whose sender gave up
+            let now = Instant::now();
+            let expired: Vec<u32> = self.streams.iter().filter(|(_, stream)| stream.request.deadline <= now).map(|(id, _)| *id).collect();
+            for id in expired {
+                self.streams.remove(&id);
+                write_frame(&mut self.output, RST_STREAM, 0, id, &CANCEL.to_be_bytes());
+            }
+            self.waiting.retain(|request| request.deadline > now);
+            self.flush()?;
+        }
+    }
+
+    /// Open streams for the waiting requests, as far as the stream limit and the flow control allow
+    fn start_requests(&mut self) {
+        while self.streams.len() < self.max_streams && self.next_stream_id <= MAX_STREAM_ID {
+            let body_len = match self.waiting.front() {
+                Some(request) => request.body.len() as i64,
+                None => return,
+            };
+            if body_len > self.send_window || body_len > self.stream_window {
+                return;
+            }
+            let request = self.waiting.pop_front().unwrap();
+            let id = self.next_stream_id;
+            self.next_stream_id += 2;
+
+            let headers = request.headers.iter().map(|(name, value)| (name.as_bytes(), value.as_bytes()));
+            let block = self.encoder.encode(headers);
+            let flags = if request.body.is_empty() { END_HEADERS | END_STREAM } else { END_HEADERS };
+            write_frame(&mut self.output, HEADERS, flags, id, &block);
+            if !request.body.is_empty() {
+                write_frame(&mut self.output, DATA, END_STREAM, id, &request.body);
+                self.send_window -= body_len;
+            }
+            self.streams.insert(id, Stream { request, status: None, headers: Vec::new(), body: Vec::new() });
+        }
+        if self.next_stream_id > MAX_STREAM_ID {
+            // Out of stream ids: the requests go to a new connection
+            self.closed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn handle_frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Result<(), SimpleError> {
+        if self.header_block.is_some() && kind != CONTINUATION {
+            bail!("header block interrupted by a frame of type {}", kind);
+        }
+        match kind {
+            DATA => {
+                // Received data is acknowledged right away, for the connection as a whole
+                if !payload.is_empty() {
+                    write_frame(&mut self.output, WINDOW_UPDATE, 0, 0, &(payload.len() as u32).to_be_bytes());
+                }
+                let data = unpad(flags, payload)?;
+                if let Some(stream) = self.streams.get_mut(&stream_id) {
+                    stream.body.extend_from_slice(data);
+                }
+                if flags & END_STREAM != 0 {
+                    self.complete(stream_id);
+                }
+            }
+            HEADERS => {
+                let mut fragment = unpad(flags, payload)?;
+                if flags & PRIORITY != 0 {
+                    fragment = fragment.get(5..).ok_or_else(|| SimpleError::new("truncated HEADERS frame"))?;
+                }
+                self.header_block = Some((stream_id, flags, fragment.to_vec()));
+                if flags & END_HEADERS != 0 {
+                    self.end_headers()?;
+                }
+            }
+            CONTINUATION => {
+                match &mut self.header_block {
+                    Some((block_stream, _, block)) if *block_stream == stream_id => block.extend_from_slice(payload),
+                    _ => bail!("unexpected CONTINUATION frame"),
+                }
+                if flags & END_HEADERS != 0 {
+                    self.end_headers()?;
+                }
+            }
+            RST_STREAM => {
+                self.streams.remove(&stream_id);
+            }
+            SETTINGS if flags & ACK == 0 => {
+                for setting in payload.chunks_exact(6) {
+                    let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                    match u16::from_be_bytes([setting[0], setting[1]]) {
+                        SETTINGS_MAX_CONCURRENT_STREAMS => self.max_streams = value as usize,
+                        SETTINGS_INITIAL_WINDOW_SIZE => self.stream_window = value as i64,
+                        _ => {}
+                    }
+                }
+                write_frame(&mut self.output, SETTINGS, ACK, 0, &[]);
+            }
+            SETTINGS => {}
+            PUSH_PROMISE => bail!("server push was disabled"),
+            PING if flags & ACK == 0 => write_frame(&mut self.output, PING, ACK, 0, payload),
+            GOAWAY => {
+                // The streams above the last one processed by the server are dropped, and
+                // their requests retried on another connection
+                let last = payload.get(..4).map_or(0, |id| u32::from_be_bytes([id[0], id[1], id[2], id[3]]) & MAX_STREAM_ID);
+                self.streams.retain(|id, _| *id <= last);
+                self.closed.store(true, Ordering::Relaxed);
+            }
+            WINDOW_UPDATE if stream_id == 0 => {
+                if let Some(increment) = payload.get(..4) {
+                    self.send_window += (u32::from_be_bytes([increment[0], increment[1], increment[2], increment[3]]) & MAX_STREAM_ID) as i64;
+                }
+            }
+            // Priorities, stream window updates and unknown frames are ignored
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Decode the header block received. Every block has to go through the decoder, to keep
+    /// its table in sync with the server's encoder.
+    fn end_headers(&mut self) -> Result<(), SimpleError> {
+        let (stream_id, flags, block) = self.header_block.take().unwrap();
+        let headers = self.decoder.decode(&block).map_err(|e| SimpleError::new(format!("Invalid header block: {:?}", e)))?;
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            let headers: Vec<(String, String)> = headers
+                .into_iter()
+                .map(|(name, value)| (String::from_utf8_lossy(&name).to_ascii_lowercase(), String::from_utf8_lossy(&value).into_owned()))
+                .collect();
+            let status = headers.iter().find(|(name, _)| name == ":status").and_then(|(_, value)| value.parse::<u16>().ok());
+            // Informational responses come before the final one, and trailers after it
+            match status {
+                Some(status) if stream.status.is_none() && status >= 200 => {
+                    stream.status = Some(status);
+                    stream.headers = headers;
+                }
+                _ => {}
+            }
+        }
+        if flags & END_STREAM != 0 {
+            self.complete(stream_id);
+        }
+        Ok(())
+    }
+
+    /// Hand the response of a stream over to its sender
+    fn complete(&mut self, stream_id: u32) {
+        if let Some(stream) = self.streams.remove(&stream_id) {
+            if let Some(status) = stream.status {
+                let _ = stream.request.reply.send(Response { status, headers: stream.headers, body: stream.body });
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), SimpleError> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+        self.stream.write_all(&self.output).map_err(|e| SimpleError::with("Error sending", e))?;
+        self.stream.flush().map_err(|e| SimpleError::with("Error sending", e))?;
+        self.output.clear();
+        Ok(())
+    }
+}
+
+/// Append a frame to `output`
+fn write_frame(output: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    output.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    output.push(kind);
+    output.push(flags);
+    output.extend_from_slice(&stream_id.to_be_bytes());
+    output.extend_from_slice(payload);
+}
+
+/// Payload of a frame without its padding, when padded
+fn unpad(flags: u8, payload: &[u8]) -> Result<&[u8], SimpleError> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let padding = *payload.first().ok_or_else(|| SimpleError::new("truncated padded frame"))? as usize;
+    match payload.len().checked_sub(padding + 1) {
+        Some(len) => Ok(&payload[1..1 + len]),
+        None => bail!("padding exceeds the frame"),
+    }
+}
+
+/// Encode in base64url without padding (RFC 4648 §5), as in the `dns` parameter of GET requests
+fn encode_base64url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            text.push(ALPHABET[(bits >> (18 - 6 * i)) as usize & 63] as char);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tls::tests::{answer, test_pki, tls_server, ServerStream};
+
+    /// Decoded header block
+    type Headers = Vec<(Vec<u8>, Vec<u8>)>;
+
+    /// Decode base64url without padding
+    fn decode_base64url(text: &str) -> Vec<u8> {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let mut bytes = Vec::new();
+        let (mut buffer, mut bits) = (0u32, 0);
+        for c in text.bytes() {
+            buffer = (buffer << 6) | ALPHABET.iter().position(|a| *a == c).unwrap() as u32;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+                buffer &= (1 << bits) - 1;
+            }
+        }
+        bytes
+    }
+
+    /// Read a frame: its type, flags, stream id and payload
+    fn read_frame(stream: &mut ServerStream) -> Option<(u8, u8, u32, Vec<u8>)> {
+        let mut header = [0u8; 9];
+        stream.read_exact(&mut header).ok()?;
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & MAX_STREAM_ID;
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).ok()?;
+        Some((header[3], header[4], stream_id, payload))
+    }
+
+    /// HTTP/2 server answering DoH requests. The path selects the response: `/dns-query`
+    /// answers with a max-age of 60 seconds and an age of 10, `/missing` with a 404.
+    fn serve_doh(mut stream: ServerStream) {
+        let mut preface = [0u8; PREFACE.len()];
+        if stream.read_exact(&mut preface).is_err() || preface != PREFACE {
+            return;
+        }
+        let mut output = Vec::new();
+        write_frame(&mut output, SETTINGS, 0, 0, &[]);
+        let (mut encoder, mut decoder) = (Encoder::new(), Decoder::new());
+        let mut requests: HashMap<u32, (Headers, Vec<u8>)> = HashMap::new();
+
+        loop {
+            if stream.write_all(&output).and_then(|_| stream.flush()).is_err() {
+                return;
+            }
+            output.clear();
+            let (kind, flags, stream_id, payload) = match read_frame(&mut stream) {
+                Some(frame) => frame,
+                None => return,
+            };
+            match kind {
+                SETTINGS if flags & ACK == 0 => write_frame(&mut output, SETTINGS, ACK, 0, &[]),
+                HEADERS => {
+                    let headers = decoder.decode(&payload).unwrap();
+                    requests.insert(stream_id, (headers, Vec::new()));
+                }
+                DATA => requests.get_mut(&stream_id).unwrap().1.extend_from_slice(&payload),
+                _ => {}
+            }
+            if !matches!(kind, HEADERS | DATA) || flags & END_STREAM == 0 {
+                continue;
+            }
+
+            let (headers, body) = requests.remove(&stream_id).unwrap();
+            let header = |name: &[u8]| headers.iter().find(|(header, _)| header == name).map(|(_, value)| String::from_utf8_lossy(value).to_string());
+            let path = header(b":path").unwrap();
+            let (path, query) = match path.split_once("?dns=") {
+                Some((path, query)) => (path.to_string(), decode_base64url(query)),
+                None => (path, body),
+            };
+            let (status, response) = match path.as_str() {
+                "/dns-query" => ("200", answer(&query)),
+                _ => ("404", b"not found".to_vec()),
+            };
+            let content_type = if status == "200" { DNS_MESSAGE } else { "text/plain" };
+            let response_headers: Vec<(&[u8], &[u8])> = vec![
+                (b":status", status.as_bytes()),
+                (b"content-type", content_type.as_bytes()),
+                (b"cache-control", b"max-age=60"),
+                (b"age", b"10"),
+            ];
+            write_frame(&mut output, HEADERS, END_HEADERS, stream_id, &encoder.encode(response_headers));
+            write_frame(&mut output, DATA, END_STREAM, stream_id, &response);
+        }
+    }
+
+    fn upstream(path: &str, method: HttpMethod) -> HttpsUpstream {
+        let pki = test_pki();
+        let addr = tls_server(&pki, &[b"h2"], serve_doh);
+        let tls = TlsUpstream { addr, auth_name: None, spki_pins: vec![pki.pin()] };
+        HttpsUpstream { tls, path: path.to_string(), method }
+    }
+
+    #[test]
+    fn successful_responses() {
+        for method in [HttpMethod::Post, HttpMethod::Get] {
+            let upstream = upstream("/dns-query", method);
+            let connections = HttpsConnections::default();
+            for name in ["example.com", "example.org"] {
+                let response = connections.lookup(&upstream, name, RecordType::A, Duration::from_secs(5)).unwrap();
+                assert_eq!(response.answers.len(), 1, "{:?}", method);
+                assert!(response.answers[0].domain().eq_ignore_ascii_case(name));
+            }
+        }
+    }
+
+    #[test]
+    fn slow_upstream_does_not_hold_up_the_others() {
+        // The TCP connection is accepted, the TLS handshake never completes
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tls = TlsUpstream { addr: listener.local_addr().unwrap(), auth_name: None, spki_pins: vec![[0; 32]] };
+        let silent = HttpsUpstream { tls, path: "/dns-query".to_string(), method: HttpMethod::Post };
+        let answering = upstream("/dns-query", HttpMethod::Post);
+
+        let connections = Arc::new(HttpsConnections::default());
+        let slow = connections.clone();
+        let slow_lookup = thread::spawn(move || slow.lookup(&silent, "example.com", RecordType::A, Duration::from_secs(2)));
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        assert!(connections.lookup(&answering, "example.com", RecordType::A, Duration::from_secs(5)).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(slow_lookup.join().unwrap().is_err());
+        drop(listener);
+    }
+
+    #[test]
+    fn non_200_status_fails() {
+        let upstream = upstream("/missing", HttpMethod::Post);
+        let result = HttpsConnections::default().lookup(&upstream, "example.com", RecordType::A, Duration::from_secs(5));
+        assert!(result.unwrap_err().as_str().contains("404"));
+    }
+
+    #[test]
+    fn ttls_capped_by_freshness() {
+        let upstream = upstream("/dns-query", HttpMethod::Post);
+        let response = HttpsConnections::default().lookup(&upstream, "example.com", RecordType::A, Duration::from_secs(5)).unwrap();
+        // Answered with a TTL of 300, a max-age of 60 and an age of 10
+        assert_eq!(response.answers[0].ttl(), 50);
+    }
+
+    #[test]
+    fn freshness_is_max_age_less_age() {
+        let response = |headers: &[(&str, &str)]| Response {
+            status: 200,
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            body: Vec::new(),
+        };
+        assert_eq!(response(&[("cache-control", "public, max-age=300"), ("age", "20")]).freshness(), Some(280));
+        assert_eq!(response(&[("cache-control", "max-age=30"), ("age", "60")]).freshness(), Some(0));
+        assert_eq!(response(&[("cache-control", "no-store")]).freshness(), None);
+        assert_eq!(response(&[]).freshness(), None);
+    }
+
+    #[test]
+    fn parse_upstream() {
+        let upstream = HttpsUpstream::parse("192.0.2.1/resolve{?dns}#dns.example").unwrap();
+        assert_eq!(upstream.tls.addr, "192.0.2.1:443".parse().unwrap());
+        assert_eq!(upstream.path, "/resolve");
+        assert_eq!(upstream.method, HttpMethod::Get);
+        assert_eq!(upstream.authority(), "dns.example");
+        let upstream = HttpsUpstream::parse("192.0.2.1:8443#dns.example").unwrap();
+        assert_eq!(upstream.path, "/dns-query");
+        assert_eq!(upstream.method, HttpMethod::Post);
+        assert_eq!(upstream.authority(), "dns.example:8443");
+    }
+
+    #[test]
+    fn base64url_round_trip() {
+        for len in 0..8 {
+            let bytes: Vec<u8> = (0..len).map(|i| (i * 73 + 251) as u8).collect();
+            assert_eq!(decode_base64url(&encode_base64url(&bytes)), bytes);
+        }
+    }
+}
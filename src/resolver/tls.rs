@@ -0,0 +1,595 @@
+//! DNS over TLS upstream client (RFC 7858). Each upstream gets a persistent connection on
+//! which queries are pipelined, framed as over TCP. The server is authenticated by SPKI pins
+//! or by an authentication domain name (RFC 8310).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use ring::digest;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme, StreamOwned};
+use simple_error::SimpleError;
+use crate::tcp::write_message;
+use crate::{BytePacketBuffer, DnsPacket, RecordType};
+use super::{build_query, validate_response};
+
+/// Port of DNS over TLS
+pub const DOT_PORT: u16 = 853;
+/// Time an idle connection is kept open
+pub(super) const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Interval at which a connection waiting for responses looks for new queries to send
+pub(super) const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// TLS connection to an upstream
+pub(super) type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+/// Upstream resolver reached over TLS. At least one of `auth_name` and `spki_pins` is needed.
+pub struct TlsUpstream {
+    pub addr: SocketAddr,
+    /// Authentication domain name: the certificate has to be valid for this name and issued
+    /// by one of the built-in root CAs
+    pub auth_name: Option<String>,
+    /// SHA-256 digests of the SubjectPublicKeyInfo of the accepted keys (RFC 7858 §4.2).
+    /// A certificate of the chain has to match one of them.
+    pub spki_pins: Vec<[u8; 32]>,
+}
+
+impl TlsUpstream {
+    /// Parse `address[:port][#name-or-pin,...]`, the port being 853 unless given. A pin is
+    /// written `sha256/` followed by the base64 digest, anything else is the authentication name.
+    pub fn parse(text: &str) -> Result<TlsUpstream, SimpleError> {
+        TlsUpstream::parse_with_port(text, DOT_PORT)
+    }
+
+    /// Same as `parse`, with another default port
+    pub(super) fn parse_with_port(text: &str, port: u16) -> Result<TlsUpstream, SimpleError> {
+        let (address, auth) = text.split_once('#').unwrap_or((text, ""));
+        let addr = match address.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => SocketAddr::new(address.parse().map_err(|e| SimpleError::with("Invalid upstream address", e))?, port),
+        };
+
+        let mut upstream = TlsUpstream { addr, auth_name: None, spki_pins: Vec::new() };
+        for item in auth.split(',').filter(|item| !item.is_empty()) {
+            match item.strip_prefix("sha256/") {
+                Some(pin) => {
+                    let digest = decode_base64(pin).and_then(|digest| <[u8; 32]>::try_from(digest).ok());
+                    upstream.spki_pins.push(digest.ok_or_else(|| SimpleError::new(format!("Invalid SPKI pin {}", pin)))?);
+                }
+                None => upstream.auth_name = Some(item.trim_end_matches('.').to_string()),
+            }
+        }
+        if upstream.auth_name.is_none() && upstream.spki_pins.is_empty() {
+            bail!("TLS upstream {} needs an authentication name or an SPKI pin", text);
+        }
+        Ok(upstream)
+    }
+}
+
+impl fmt::Display for TlsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.auth_name {
+            Some(name) => write!(f, "tls://{}#{}", self.addr, name),
+            None => write!(f, "tls://{}", self.addr),
+        }
+    }
+}
+
+/// Query handed over to the thread of a connection
+struct PendingQuery {
+    id: u16,
+    message: Vec<u8>,
+    deadline: Instant,
+    /// Receives the response. Dropped without a response when the connection fails.
+    reply: mpsc::Sender<BytePacketBuffer>,
+}
+
+#[derive(Clone)]
+/// Persistent connection to an upstream, served by its own thread
+struct TlsConnection {
+    queries: mpsc::Sender<PendingQuery>,
+    closed: Arc<AtomicBool>,
+}
+
+/// Connections to the TLS upstreams
+#[derive(Default)]
+pub(super) struct TlsConnections {
+    connections: Mutex<HashMap<TlsUpstream, TlsConnection>>,
+}
+
+impl TlsConnections {
+    /// Send a query for `qname` to `upstream` and wait at most `timeout` for its response.
+    /// The connection to the upstream is opened if needed, and opened again once if it was
+    /// closed by the server in the meantime.
+    pub(super) fn lookup(&self, upstream: &TlsUpstream, qname: &str, qtype: RecordType, timeout: Duration) -> Result<DnsPacket, SimpleError> {
+        let deadline = Instant::now() + timeout;
+        let mut packet = build_query(qname, qtype)?;
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer)?;
+        let message = req_buffer.buf[..req_buffer.pos].to_vec();
+
+        for reused in [true, false] {
+            let (reply, response) = mpsc::channel();
+            let query = PendingQuery { id: packet.header.id, message: message.clone(), deadline, reply };
+            let connection = self.connection(upstream, deadline)?;
+            let (sent, closed) = (connection.queries.send(query).is_ok(), connection.closed);
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match (sent, response.recv_timeout(remaining)) {
+                (true, Ok(mut res_buffer)) => {
+                    validate_response(&res_buffer.buf, upstream.addr, &packet, upstream.addr)?;
+                    return DnsPacket::from_buffer(&mut res_buffer);
+                }
+                (_, Err(RecvTimeoutError::Timeout)) => break,
+                // The connection went down before the response came, a new one is tried. It is
+                // marked closed here as its thread may not have finished yet.
+                _ if reused => {
+                    closed.store(true, Ordering::Relaxed);
+                    println!("connection to {} closed, reconnecting", upstream);
+                }
+                _ => break,
+            }
+        }
+        bail!("No response from {}", upstream)
+    }
+
+    /// Usable connection to `upstream`, or else a new one. It is opened without holding
+    /// the lock, so that a slow upstream does not hold up the lookups to the others.
+    fn connection(&self, upstream: &TlsUpstream, deadline: Instant) -> Result<TlsConnection, SimpleError> {
+        let usable = |connection: &&TlsConnection| !connection.closed.load(Ordering::Relaxed);
+        if let Some(connection) = self.connections.lock().unwrap().get(upstream).filter(usable) {
+            return Ok(connection.clone());
+        }
+        let opened = TlsConnection::open(upstream, deadline)?;
+
+        // Another lookup may have connected meanwhile, the connection opened last is then
+        // dropped and its thread ends
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get(upstream).filter(usable) {
+            Some(connection) => Ok(connection.clone()),
+            None => {
+                connections.insert(upstream.clone(), opened.clone());
+                Ok(opened)
+            }
+        }
+    }
+}
+
+impl TlsConnection {
+    /// Connect to the upstream, then hand the connection over to a thread
+    fn open(upstream: &TlsUpstream, deadline: Instant) -> Result<TlsConnection, SimpleError> {
+        let stream = connect(upstream, &[], deadline)?;
+        println!("connected to {}", upstream);
+
+        let (queries, pending) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let thread_closed = closed.clone();
+        let name = upstream.to_string();
+        thread::spawn(move || {
+            if let Err(e) = serve_connection(stream, pending) {
+                println!("connection to {} failed: {}", name, e);
+            }
+            thread_closed.store(true, Ordering::Relaxed);
+        });
+        Ok(TlsConnection { queries, closed })
+    }
+}
+
+/// Connect to `upstream` offering the `alpn` protocols, and complete the TLS handshake
+/// before `deadline`. Reads on the returned stream time out after `POLL_INTERVAL`.
+pub(super) fn connect(upstream: &TlsUpstream, alpn: &[&[u8]], deadline: Instant) -> Result<TlsStream, SimpleError> {
+    let remaining = || match deadline.saturating_duration_since(Instant::now()) {
+        remaining if remaining.is_zero() => Err(SimpleError::new(format!("Timeout connecting to {}", upstream.addr))),
+        remaining => Ok(remaining),
+    };
+
+    let server_name = match &upstream.auth_name {
+        Some(name) => ServerName::try_from(name.clone()).map_err(|e| SimpleError::with("Invalid authentication name", e))?,
+        None => ServerName::IpAddress(upstream.addr.ip().into()),
+    };
+    let conn = ClientConnection::new(client_config(upstream, alpn)?, server_name)
+        .map_err(|e| SimpleError::with("Error creating TLS connection", e))?;
+    let sock = TcpStream::connect_timeout(&upstream.addr, remaining()?)
+        .map_err(|e| SimpleError::with("Error connecting", e))?;
+    sock.set_nodelay(true).map_err(|e| SimpleError::with("Error configuring socket", e))?;
+    let mut stream = StreamOwned::new(conn, sock);
+
+    // The handshake is done right away, so that authentication failures reach the caller
+    while stream.conn.is_handshaking() {
+        stream.sock.set_read_timeout(Some(remaining()?)).map_err(|e| SimpleError::with("Error setting socket timeout", e))?;
+        stream.sock.set_write_timeout(Some(remaining()?)).map_err(|e| SimpleError::with("Error setting socket timeout", e))?;
+        stream.conn.complete_io(&mut stream.sock).map_err(|e| SimpleError::with("TLS handshake failed", e))?;
+    }
+    stream.sock.set_read_timeout(Some(POLL_INTERVAL)).map_err(|e| SimpleError::with("Error setting socket timeout", e))?;
+    Ok(stream)
+}
+
+/// Send the queries received on `pending` as they come, and route the responses to their
+/// senders by message id, until the connection fails or stays idle for `IDLE_TIMEOUT`.
+/// A query with the id of one in flight is held back until the response to the first one.
+fn serve_connection(mut stream: TlsStream, pending: mpsc::Receiver<PendingQuery>) -> Result<(), SimpleError> {
+    let mut in_flight: HashMap<u16, PendingQuery> = HashMap::new();
+    let mut waiting: Vec<PendingQuery> = Vec::new();
+    let mut received = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        // Queue the new queries. With nothing in flight, wait for one until the idle timeout.
+        let mut next = match in_flight.is_empty() && waiting.is_empty() {
+            true => match pending.recv_timeout(IDLE_TIMEOUT) {
+                Ok(query) => Some(query),
+                Err(_) => return Ok(()),
+            },
+            false => None,
+        };
+        loop {
+            let query = match next.take() {
+                Some(query) => query,
+                None => match pending.try_recv() {
+                    Ok(query) => query,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                },
+            };
+            waiting.push(query);
+        }
+        for query in std::mem::take(&mut waiting) {
+            if in_flight.contains_key(&query.id) {
+                waiting.push(query);
+                continue;
+            }
+            write_message(&mut stream, &query.message)?;
+            in_flight.insert(query.id, query);
+        }
+
+        match stream.read(&mut chunk) {
+            Ok(0) => bail!("closed by the server"),
+            Ok(len) => received.extend_from_slice(&chunk[..len]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(SimpleError::with("Error receiving", e)),
+        }
+
+        // Hand the complete messages over, in whatever order they come
+        while received.len() >= 2 {
+            let len = u16::from_be_bytes([received[0], received[1]]) as usize;
+            if received.len() < len + 2 {
+                break;
+            }
+            let message: Vec<u8> = received.drain(..len + 2).skip(2).collect();
+            if message.len() < 2 {
+                continue;
+            }
+            let id = u16::from_be_bytes([message[0], message[1]]);
+            if let Some(query) = in_flight.remove(&id) {
+                let _ = query.reply.send(BytePacketBuffer::from_bytes(&message));
+            }
+        }
+
+        // Forget the queries whose sender gave up
+        let now = Instant::now();
+        in_flight.retain(|_, query| query.deadline > now);
+        waiting.retain(|query| query.deadline > now);
+    }
+}
+
+/// TLS settings authenticating `upstream` with its pins and name, and offering the `alpn` protocols
+fn client_config(upstream: &TlsUpstream, alpn: &[&[u8]]) -> Result<Arc<ClientConfig>, SimpleError> {
+    client_config_with_roots(upstream, alpn, RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() })
+}
+
+/// Same as `client_config`, the certificates of authentication names being issued by one of
+/// the CAs of `roots`
+fn client_config_with_roots(upstream: &TlsUpstream, alpn: &[&[u8]], roots: RootCertStore) -> Result<Arc<ClientConfig>, SimpleError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let webpki = match upstream.auth_name {
+        Some(_) => {
+            let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .map_err(|e| SimpleError::with("Error building the certificate verifier", e))?;
+            Some(verifier)
+        }
+        None => None,
+    };
+    let verifier = Arc::new(UpstreamVerifier { pins: upstream.spki_pins.clone(), webpki, provider: provider.clone() });
+
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| SimpleError::with("Error configuring TLS", e))?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+#[derive(Debug)]
+/// Check the certificate of an upstream against its authentication name, when it has one,
+/// and against its SPKI pins, when it has some
+struct UpstreamVerifier {
+    pins: Vec<[u8; 32]>,
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for UpstreamVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(webpki) = &self.webpki {
+            webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if !self.pins.is_empty() {
+            let pinned = std::iter::once(end_entity)
+                .chain(intermediates)
+                .filter_map(spki_digest)
+                .any(|digest| self.pins.contains(&digest));
+            if !pinned {
+                return Err(rustls::Error::General("no certificate matches the SPKI pins".to_string()));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// SHA-256 digest of the SubjectPublicKeyInfo of a certificate
+fn spki_digest(cert: &CertificateDer<'_>) -> Option<[u8; 32]> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    let spki = cert.subject_public_key_info();
+    digest::digest(&digest::SHA256, spki.as_ref()).as_ref().try_into().ok()
+}
+
+/// Decode standard base64, padding included (RFC 4648 §4)
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{Ipv4Addr, TcpListener};
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair, SanType};
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::{ServerConfig, ServerConnection};
+    use crate::DnsRecord;
+
+    /// TLS connection accepted by a test server
+    pub(in crate::resolver) type ServerStream = StreamOwned<ServerConnection, TcpStream>;
+
+    /// Certificate for dot.test and 127.0.0.1 issued by a test CA, with the key of the certificate
+    pub(in crate::resolver) struct TestPki {
+        pub ca: CertificateDer<'static>,
+        pub cert: CertificateDer<'static>,
+        pub key: Vec<u8>,
+    }
+
+    impl TestPki {
+        /// SPKI pin of the certificate
+        pub(in crate::resolver) fn pin(&self) -> [u8; 32] {
+            spki_digest(&self.cert).unwrap()
+        }
+    }
+
+    pub(in crate::resolver) fn test_pki() -> TestPki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["dot.test".to_string()]).unwrap();
+        params.subject_alt_names.push(SanType::IpAddress(Ipv4Addr::LOCALHOST.into()));
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        TestPki { ca: ca.der().clone(), cert: cert.der().clone(), key: key.serialize_der() }
+    }
+
+    /// Accept TLS connections offering `alpn` on a local port, each one handed to `handle` on
+    /// a thread of its own
+    pub(in crate::resolver) fn tls_server(pki: &TestPki, alpn: &[&[u8]], handle: fn(ServerStream)) -> SocketAddr {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(pki.key.clone()));
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![pki.cert.clone(), pki.ca.clone()], key)
+            .unwrap();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        let config = Arc::new(config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for sock in listener.incoming().flatten() {
+                let conn = ServerConnection::new(config.clone()).unwrap();
+                thread::spawn(move || handle(StreamOwned::new(conn, sock)));
+            }
+        });
+        addr
+    }
+
+    /// Answer a query with an A record for its name
+    pub(in crate::resolver) fn answer(query: &[u8]) -> Vec<u8> {
+        let mut packet = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(query)).unwrap();
+        packet.header.response = true;
+        let name = packet.questions[0].name.clone();
+        packet.answers.push(DnsRecord::A { domain: name, addr: Ipv4Addr::new(192, 0, 2, 1), ttl: 300 });
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.buf[..buffer.pos].to_vec()
+    }
+
+    /// Answer the queries of a DNS over TLS connection until it is closed
+    fn serve_dot(mut stream: ServerStream) {
+        let mut len = [0u8; 2];
+        while stream.read_exact(&mut len).is_ok() {
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            if stream.read_exact(&mut query).is_err() {
+                return;
+            }
+            let response = answer(&query);
+            let mut message = (response.len() as u16).to_be_bytes().to_vec();
+            message.extend_from_slice(&response);
+            if stream.write_all(&message).and_then(|_| stream.flush()).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn upstream(addr: SocketAddr, auth_name: Option<&str>, spki_pins: Vec<[u8; 32]>) -> TlsUpstream {
+        TlsUpstream { addr, auth_name: auth_name.map(str::to_string), spki_pins }
+    }
+
+    /// Complete a handshake with the server, trusting the test CA for the authentication name
+    fn handshake(pki: &TestPki, upstream: &TlsUpstream) -> Result<(), SimpleError> {
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.clone()).unwrap();
+        let config = client_config_with_roots(upstream, &[], roots)?;
+        let name = ServerName::try_from(upstream.auth_name.clone().unwrap()).unwrap();
+        let mut conn = ClientConnection::new(config, name).unwrap();
+        let mut sock = TcpStream::connect(upstream.addr).unwrap();
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock).map_err(|e| SimpleError::with("TLS handshake failed", e))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lookup_with_good_pin() {
+        let pki = test_pki();
+        let addr = tls_server(&pki, &[], serve_dot);
+        let pin = pki.pin();
+        let connections = TlsConnections::default();
+        let upstream = upstream(addr, None, vec![[0; 32], pin]);
+        for name in ["example.com", "example.org"] {
+            let response = connections.lookup(&upstream, name, RecordType::A, Duration::from_secs(5)).unwrap();
+            assert_eq!(response.answers.len(), 1);
+            assert!(response.answers[0].domain().eq_ignore_ascii_case(name));
+        }
+        // Both queries went over the same connection
+        assert_eq!(connections.connections.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn queries_with_the_same_id_are_both_answered() {
+        let pki = test_pki();
+        let addr = tls_server(&pki, &[], serve_dot);
+        let connection = TlsConnection::open(&upstream(addr, None, vec![pki.pin()]), Instant::now() + Duration::from_secs(5)).unwrap();
+
+        let mut responses = Vec::new();
+        for name in ["example.com", "example.org"] {
+            let mut packet = build_query(name, RecordType::A).unwrap();
+            packet.header.id = 1234;
+            let mut buffer = BytePacketBuffer::new();
+            packet.write(&mut buffer).unwrap();
+            let (reply, response) = mpsc::channel();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            connection.queries.send(PendingQuery { id: 1234, message: buffer.buf[..buffer.pos].to_vec(), deadline, reply }).unwrap();
+            responses.push(response);
+        }
+        for (response, name) in responses.iter().zip(["example.com", "example.org"]) {
+            let mut buffer = response.recv_timeout(Duration::from_secs(5)).unwrap();
+            let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+            assert_eq!(packet.questions[0].name, name);
+        }
+    }
+
+    #[test]
+    fn slow_upstream_does_not_hold_up_the_others() {
+        // The TCP connection is accepted, the TLS handshake never completes
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent = upstream(listener.local_addr().unwrap(), None, vec![[0; 32]]);
+        let pki = test_pki();
+        let answering = upstream(tls_server(&pki, &[], serve_dot), None, vec![pki.pin()]);
+
+        let connections = Arc::new(TlsConnections::default());
+        let slow = connections.clone();
+        let slow_lookup = thread::spawn(move || slow.lookup(&silent, "example.com", RecordType::A, Duration::from_secs(2)));
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        assert!(connections.lookup(&answering, "example.com", RecordType::A, Duration::from_secs(5)).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(slow_lookup.join().unwrap().is_err());
+        drop(listener);
+    }
+
+    #[test]
+    fn lookup_with_bad_pin_fails() {
+        let pki = test_pki();
+        let addr = tls_server(&pki, &[], serve_dot);
+        let connections = TlsConnections::default();
+        let upstream = upstream(addr, None, vec![[0; 32]]);
+        assert!(connections.lookup(&upstream, "example.com", RecordType::A, Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn authentication_name_is_checked() {
+        let pki = test_pki();
+        let addr = tls_server(&pki, &[], serve_dot);
+        assert!(handshake(&pki, &upstream(addr, Some("dot.test"), Vec::new())).is_ok());
+        assert!(handshake(&pki, &upstream(addr, Some("other.test"), Vec::new())).is_err());
+        // The name and the pins are both required to match
+        let pin = pki.pin();
+        assert!(handshake(&pki, &upstream(addr, Some("other.test"), vec![pin])).is_err());
+        assert!(handshake(&pki, &upstream(addr, Some("dot.test"), vec![[0; 32]])).is_err());
+        assert!(handshake(&pki, &upstream(addr, Some("dot.test"), vec![pin])).is_ok());
+    }
+
+    #[test]
+    fn parse_upstream() {
+        let upstream = TlsUpstream::parse("192.0.2.1#dns.example,sha256/2/IkIAHns5hHuL7qseJHye8r2TwKSxR2WeaMUwP2UAc=").unwrap();
+        assert_eq!(upstream.addr, "192.0.2.1:853".parse().unwrap());
+        assert_eq!(upstream.auth_name.as_deref(), Some("dns.example"));
+        assert_eq!(upstream.spki_pins.len(), 1);
+        assert!(TlsUpstream::parse("192.0.2.1").is_err());
+        assert!(TlsUpstream::parse("192.0.2.1#sha256/short").is_err());
+    }
+}
@@ -0,0 +1,360 @@
+//! DNSSEC validation of the responses (RFC 4035 §5). The chain of trust is built from the
+//! trust anchors down to the zone of the data, with a DS and a DNSKEY lookup at each zone cut.
+
+use std::time::{Duration, Instant};
+use simple_error::SimpleError;
+use crate::dns_packet::{dname_substitution, is_proper_subdomain, is_subdomain};
+use crate::dnssec::{self, NoDsProof, Security};
+use crate::{DnsPacket, DnsRecord, RRset, RecordType, ResultCode};
+use super::{child_name, Resolution, Resolver};
+
+/// Time a broken link of the chain of trust is remembered, in seconds
+const BOGUS_LINK_TTL: u32 = 60;
+
+#[derive(Clone, Debug)]
+/// What the chain of trust tells about a name
+pub(super) enum Link {
+    /// Apex of a signed zone, with its validated DNSKEY records
+    Zone(Vec<DnsRecord>),
+    /// Not a zone cut: the name belongs to the zone of its parent
+    NoCut,
+    /// Delegation to an unsigned zone, or to a zone signed with unsupported algorithms only
+    Insecure,
+    /// The DS or DNSKEY records of the zone do not check out
+    Bogus,
+}
+
+/// State of the zone a name belongs to
+enum ZoneState {
+    /// Signed zone with a chain of trust: its name and its validated DNSKEY records
+    Secure(String, Vec<DnsRecord>),
+    Insecure,
+    Bogus,
+}
+
+impl Resolver {
+    /// Validate the final response to a query for `qname`: every RRset of the answer, and the
+    /// proof of non-existence when the name or the type is denied.
+    pub(super) fn validate(&self, response: &DnsPacket, qname: &str, qtype: RecordType, resolution: &mut Resolution) -> Result<Security, SimpleError> {
+        let now = dnssec::now();
+        let mut security = Security::Secure;
+
+        // NSEC and NSEC3 records of the authority section that check out, needed to prove
+        // denials and wildcard expansions
+        let mut proofs = Vec::new();
+        let mut proofs_security = Security::Secure;
+        for rrset in response.authority_rrsets() {
+            if matches!(rrset.rtype, RecordType::NSEC | RecordType::NSEC3 | RecordType::SOA) {
+                let (rrset_security, _) = self.rrset_security(&rrset, &response.authorities, now, resolution)?;
+                proofs_security = proofs_security.min(rrset_security);
+                if rrset_security == Security::Secure && rrset.rtype != RecordType::SOA {
+                    proofs.extend(rrset.into_records());
+                }
+            }
+        }
+
+        for rrset in response.answer_rrsets() {
+            if rrset.rtype == RecordType::RRSIG || is_synthesized_cname(response, &rrset) {
+                continue;
+            }
+            let (rrset_security, wildcard_labels) = self.rrset_security(&rrset, &response.answers, now, resolution)?;
+            if let Some(labels) = wildcard_labels {
+                // The query name must not exist for the wildcard to apply (RFC 4035 §5.3.4)
+                if !dnssec::prove_wildcard_expansion(&proofs, &rrset.name, labels) {
+                    println!("no proof for the wildcard expansion of {}", rrset.name);
+                    return Ok(Security::Bogus);
+                }
+            }
+            security = security.min(rrset_security);
+        }
+
+        // Follow the aliases of the answer to the name the response is about
+        let mut end = qname.to_string();
+        while !response.has_answer(&end, qtype) {
+            match response.get_alias(&end) {
+                Some((_, target)) if !target.eq_ignore_ascii_case(qname) => end = target,
+                _ => break,
+            }
+        }
+        let has_soa = response.authorities.iter().any(|record| record.record_type() == RecordType::SOA);
+        let nxdomain = response.header.rescode == ResultCode::NXDOMAIN;
+        let denied = !response.has_answer(&end, qtype) && (nxdomain || has_soa || end.eq_ignore_ascii_case(qname));
+        if !denied {
+            return Ok(security);
+        }
+
+        let denial = match proofs.is_empty() {
+            true if proofs_security == Security::Secure => match self.zone_state(&end, resolution)? {
+                // A signed zone has to prove the denial
+                ZoneState::Secure(zone, _) => {
+                    println!("no proof of non-existence for {:?} {} in signed zone {:?}", qtype, end, zone);
+                    Security::Bogus
+                }
+                ZoneState::Insecure => Security::Insecure,
+                ZoneState::Bogus => Security::Bogus,
+            },
+            true => proofs_security,
+            false => proofs_security.min(dnssec::prove_denial(&proofs, &end, qtype, nxdomain)),
+        };
+        Ok(security.min(denial))
+    }
+
+    /// Validate an RRset with the RRSIG records of `records` (RFC 4035 §5.3). For an RRset
+    /// synthesized from a wildcard, the labels field of the RRSIG is returned as well.
+    fn rrset_security(&self, rrset: &RRset, records: &[DnsRecord], now: u32, resolution: &mut Resolution) -> Result<(Security, Option<u8>), SimpleError> {
+        let rrsigs = dnssec::rrsigs_for(records, rrset);
+        let signer = rrsigs.iter().find_map(|rrsig| match rrsig {
+            DnsRecord::RRSIG { signer_name, .. } if is_subdomain(&rrset.name, signer_name) => Some(signer_name.clone()),
+            _ => None,
+        });
+
+        // Unsigned data is only acceptable from an insecure zone
+        let signer = match signer {
+            Some(signer) => signer,
+            None => {
+                let security = match self.zone_state(&rrset.name, resolution)? {
+                    ZoneState::Secure(zone, _) => {
+                        println!("missing signature for {:?} {} in signed zone {:?}", rrset.rtype, rrset.name, zone);
+                        Security::Bogus
+                    }
+                    ZoneState::Insecure => Security::Insecure,
+                    ZoneState::Bogus => Security::Bogus,
+                };
+                return Ok((security, None));
+            }
+        };
+
+        match self.zone_state(&signer, resolution)? {
+            ZoneState::Secure(zone, keys) if zone.eq_ignore_ascii_case(&signer) => {
+                match dnssec::verify_rrset(rrset, &rrsigs, &keys, &zone, now) {
+                    Some(rrsig @ DnsRecord::RRSIG { labels, .. }) => {
+                        let wildcard = dnssec::is_wildcard_expansion(rrsig).then_some(*labels);
+                        Ok((Security::Secure, wildcard))
+                    }
+                    _ => {
+                        println!("no valid signature for {:?} {} from {:?}", rrset.rtype, rrset.name, zone);
+                        Ok((Security::Bogus, None))
+                    }
+                }
+            }
+            ZoneState::Secure(zone, _) => {
+                println!("{:?} {} signed by {:?}, which is not a zone apex under {:?}", rrset.rtype, rrset.name, signer, zone);
+                Ok((Security::Bogus, None))
+            }
+            ZoneState::Insecure => Ok((Security::Insecure, None)),
+            ZoneState::Bogus => Ok((Security::Bogus, None)),
+        }
+    }
+
+    /// Follow the chain of trust from the closest trust anchor down to `name`, returning the
+    /// state of the zone `name` belongs to
+    fn zone_state(&self, name: &str, resolution: &mut Resolution) -> Result<ZoneState, SimpleError> {
+        // The deepest trust anchor above the name is where the chain of trust starts
+        let anchor = self.config.trust_anchors
+            .iter()
+            .map(|ds| ds.domain())
+            .filter(|owner| is_subdomain(name, owner))
+            .max_by_key(|owner| owner.len());
+        let anchor = match anchor {
+            Some(anchor) => anchor.to_string(),
+            None => return Ok(ZoneState::Insecure),
+        };
+
+        let mut zone = anchor.clone();
+        let mut keys = match self.link(&anchor, None, resolution)? {
+            Link::Zone(keys) => keys,
+            Link::Insecure => return Ok(ZoneState::Insecure),
+            Link::NoCut | Link::Bogus => return Ok(ZoneState::Bogus),
+        };
+
+        let mut current = anchor;
+        while !current.eq_ignore_ascii_case(name) {
+            current = child_name(name, &current);
+            match self.link(&current, Some((&zone, &keys)), resolution)? {
+                Link::Zone(child_keys) => {
+                    zone = current.clone();
+                    keys = child_keys;
+                }
+                Link::NoCut => {}
+                Link::Insecure => return Ok(ZoneState::Insecure),
+                Link::Bogus => return Ok(ZoneState::Bogus),
+            }
+        }
+        Ok(ZoneState::Secure(zone, keys))
+    }
+
+    /// What the chain of trust tells about `name`, from the link cache or else from its DS and
+    /// DNSKEY records. `parent` is the closest enclosing signed zone with its keys, `None` for a
+    /// trust anchor.
+    fn link(&self, name: &str, parent: Option<(&str, &[DnsRecord])>, resolution: &mut Resolution) -> Result<Link, SimpleError> {
+        let key = name.to_ascii_lowercase();
+        if let Some((link, expires)) = self.links.lock().unwrap().get(&key) {
+            if *expires > Instant::now() {
+                return Ok(link.clone());
+            }
+        }
+
+        let (link, ttl) = match parent {
+            None => {
+                let anchors: Vec<DnsRecord> = self.config.trust_anchors
+                    .iter()
+                    .filter(|ds| ds.domain().eq_ignore_ascii_case(name))
+                    .cloned()
+                    .collect();
+                self.zone_keys(name, &anchors, resolution)?
+            }
+            Some((zone, keys)) => self.delegation_link(name, zone, keys, resolution)?,
+        };
+        if let Link::Bogus = link {
+            println!("chain of trust broken at {:?}", name);
+        }
+
+        let ttl = match link {
+            Link::Bogus => BOGUS_LINK_TTL,
+            _ => ttl.min(self.config.max_cache_ttl),
+        };
+        let mut links = self.links.lock().unwrap();
+        if links.len() >= self.config.cache_size {
+            let now = Instant::now();
+            links.retain(|_, (_, expires)| *expires > now);
+        }
+        if links.len() < self.config.cache_size {
+            links.insert(key, (link.clone(), Instant::now() + Duration::from_secs(ttl as u64)));
+        }
+        Ok(link)
+    }
+
+    /// Find out whether `name` is a zone cut below `zone`, signed or not, from its DS RRset
+    /// (RFC 4035 §5.2). Returns the link along with its TTL.
+    fn delegation_link(&self, name: &str, zone: &str, keys: &[DnsRecord], resolution: &mut Resolution) -> Result<(Link, u32), SimpleError> {
+        let now = dnssec::now();
+        let response = self.resolve(name, RecordType::DS, false, resolution)?;
+
+        // A name that does not exist, or an alias, cannot be a zone cut
+        if response.header.rescode == ResultCode::NXDOMAIN || response.get_alias(name).is_some() {
+            return Ok((Link::NoCut, min_ttl(&response.authorities)));
+        }
+
+        let ds_rrset = response.answer_rrsets()
+            .into_iter()
+            .find(|rrset| rrset.rtype == RecordType::DS && rrset.name.eq_ignore_ascii_case(name));
+        if let Some(ds_rrset) = ds_rrset {
+            let rrsigs = dnssec::rrsigs_for(&response.answers, &ds_rrset);
+            if dnssec::verify_rrset(&ds_rrset, &rrsigs, keys, zone, now).is_none() {
+                return Ok((Link::Bogus, 0));
+            }
+            return self.zone_keys(name, ds_rrset.records(), resolution);
+        }
+
+        // No DS record: the NSEC or NSEC3 records tell whether there is an unsigned delegation
+        let mut proofs = Vec::new();
+        for rrset in response.authority_rrsets() {
+            if rrset.rtype == RecordType::NSEC || rrset.rtype == RecordType::NSEC3 {
+                let rrsigs = dnssec::rrsigs_for(&response.authorities, &rrset);
+                if dnssec::verify_rrset(&rrset, &rrsigs, keys, zone, now).is_none() {
+                    return Ok((Link::Bogus, 0));
+                }
+                proofs.extend(rrset.into_records());
+            }
+        }
+        let ttl = min_ttl(&proofs);
+        match dnssec::prove_no_ds(&proofs, name) {
+            Some(NoDsProof::NoZoneCut) => Ok((Link::NoCut, ttl)),
+            Some(NoDsProof::InsecureDelegation) => Ok((Link::Insecure, ttl)),
+            None => Ok((Link::Bogus, 0)),
+        }
+    }
+
+    /// Fetch and validate the DNSKEY RRset of `zone` with its DS records (RFC 4035 §5.2).
+    /// Returns the link along with its TTL.
+    fn zone_keys(&self, zone: &str, ds_records: &[DnsRecord], resolution: &mut Resolution) -> Result<(Link, u32), SimpleError> {
+        let supported: Vec<&DnsRecord> = ds_records
+            .iter()
+            .filter(|ds| matches!(ds, DnsRecord::DS { algorithm, digest_type, .. }
+                if dnssec::is_supported_algorithm(*algorithm) && dnssec::is_supported_digest(*digest_type)))
+            .collect();
+        // A zone signed with algorithms we do not implement is treated as unsigned (RFC 4035 §5.2)
+        if supported.is_empty() {
+            return Ok((Link::Insecure, min_ttl(ds_records)));
+        }
+
+        let response = self.resolve(zone, RecordType::DNSKEY, false, resolution)?;
+        let dnskey_rrset = response.answer_rrsets()
+            .into_iter()
+            .find(|rrset| rrset.rtype == RecordType::DNSKEY && rrset.name.eq_ignore_ascii_case(zone));
+        let dnskey_rrset = match dnskey_rrset {
+            Some(rrset) => rrset,
+            None => return Ok((Link::Bogus, 0)),
+        };
+
+        // The DNSKEY RRset has to be signed by one of the keys the DS records designate
+        let entry_keys: Vec<DnsRecord> = dnskey_rrset.records()
+            .iter()
+            .filter(|key| supported.iter().any(|ds| dnssec::ds_matches(ds, key)))
+            .cloned()
+            .collect();
+        let rrsigs = dnssec::rrsigs_for(&response.answers, &dnskey_rrset);
+        if dnssec::verify_rrset(&dnskey_rrset, &rrsigs, &entry_keys, zone, dnssec::now()).is_none() {
+            return Ok((Link::Bogus, 0));
+        }
+        let ttl = dnskey_rrset.ttl().min(min_ttl(ds_records));
+        Ok((Link::Zone(dnskey_rrset.into_records()), ttl))
+    }
+}
+
+/// Lowest TTL of some records, used as the lifetime of the links derived from them
+fn min_ttl(records: &[DnsRecord]) -> u32 {
+    records.iter().map(|record| record.ttl()).min().unwrap_or(0)
+}
+
+/// Check whether an unsigned CNAME was synthesized from a DNAME of the response (RFC 6672 §5.3.1).
+/// Its target has to be the one the DNAME substitution gives, or it is forged.
+fn is_synthesized_cname(response: &DnsPacket, rrset: &RRset) -> bool {
+    let target = match rrset.records() {
+        [DnsRecord::CNAME { host, .. }] => host,
+        _ => return false,
+    };
+    dnssec::rrsigs_for(&response.answers, rrset).is_empty()
+        && response.answers.iter().any(|record| match record {
+            DnsRecord::DNAME { domain, host, .. } if is_proper_subdomain(&rrset.name, domain) => {
+                dname_substitution(&rrset.name, domain, host).eq_ignore_ascii_case(target)
+            }
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dname_response(cname_target: &str) -> (DnsPacket, RRset) {
+        let mut response = DnsPacket::new();
+        response.answers.push(DnsRecord::DNAME { domain: "example.com".to_string(), host: "example.net".to_string(), ttl: 300 });
+        response.answers.push(DnsRecord::CNAME { domain: "www.example.com".to_string(), host: cname_target.to_string(), ttl: 300 });
+        let cname = RRset::group(&response.answers).remove(1);
+        (response, cname)
+    }
+
+    #[test]
+    fn synthesized_cname_matches_the_dname_substitution() {
+        let (response, cname) = dname_response("www.example.net");
+        assert!(is_synthesized_cname(&response, &cname));
+        let (response, cname) = dname_response("WWW.Example.NET");
+        assert!(is_synthesized_cname(&response, &cname));
+    }
+
+    #[test]
+    fn forged_cname_under_a_dname_is_not_synthesized() {
+        for target in ["evil.example.org", "example.net", "www.example.com", "mail.example.net"] {
+            let (response, cname) = dname_response(target);
+            assert!(!is_synthesized_cname(&response, &cname), "{}", target);
+        }
+    }
+
+    #[test]
+    fn dname_substitution_replaces_the_owner_suffix() {
+        assert_eq!(dname_substitution("a.b.example.com", "example.com", "example.net"), "a.b.example.net");
+        assert_eq!(dname_substitution("www.example.com", "example.com", ""), "www");
+        assert_eq!(dname_substitution("www.example", "", "alias.test"), "www.example.alias.test");
+    }
+}
@@ -0,0 +1,256 @@
+//! Root hints: the name servers of the root zone used to start the resolution
+//!
+//! The hints use the format of the named.root file published by InterNIC
+//! (https://www.internic.net/domain/named.root), a copy of which is built in.
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use simple_error::SimpleError;
+use crate::{DnsPacket, DnsRecord};
+
+/// Copy of the named.root file shipped with the crate
+const BUILTIN_ROOT_HINTS: &str = include_str!("../resources/named.root");
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Name server of the root zone along with its addresses
+pub struct RootServer {
+    pub name: String,
+    pub ipv4: Vec<Ipv4Addr>,
+    pub ipv6: Vec<Ipv6Addr>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// List of the root name servers
+pub struct RootHints {
+    pub servers: Vec<RootServer>,
+}
+
+impl Default for RootHints {
+    fn default() -> Self {
+        RootHints::builtin()
+    }
+}
+
+impl RootHints {
+    /// Root hints built into the crate
+    pub fn builtin() -> RootHints {
+        RootHints::parse(BUILTIN_ROOT_HINTS).expect("Built-in root hints are valid")
+    }
+
+    /// Load root hints from a file in the named.root format
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<RootHints, SimpleError> {
+        let content = fs::read_to_string(path).map_err(|e| SimpleError::with("Error reading root hints", e))?;
+        RootHints::parse(&content)
+    }
+
+    /// Parse root hints in the named.root format: one record per line, as
+    /// `<owner> [<ttl>] [IN] <type> <data>`, comments starting with ';'
+    pub fn parse(content: &str) -> Result<RootHints, SimpleError> {
+        let mut hints = RootHints { servers: Vec::new() };
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            // The TTL and the class are optional, in this order
+            let mut fields = line.split_whitespace().peekable();
+            let owner = normalize_name(fields.next().unwrap_or_default());
+            if fields.peek().is_some_and(|field| field.parse::<u32>().is_ok()) {
+                fields.next();
+            }
+            if fields.peek().is_some_and(|field| field.eq_ignore_ascii_case("IN")) {
+                fields.next();
+            }
+            let (rtype, data) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(rtype), Some(data), None) => (rtype.to_ascii_uppercase(), data),
+                _ => bail!("Invalid root hints at line {}", number + 1),
+            };
+
+            match rtype.as_str() {
+                "NS" if owner.is_empty() => {
+                    hints.server_mut(&normalize_name(data));
+                }
+                "A" => {
+                    let addr = data.parse().map_err(|e| SimpleError::with("Invalid address in root hints", e))?;
+                    hints.server_mut(&owner).ipv4.push(addr);
+                }
+                "AAAA" => {
+                    let addr = data.parse().map_err(|e| SimpleError::with("Invalid address in root hints", e))?;
+                    hints.server_mut(&owner).ipv6.push(addr);
+                }
+                _ => bail!("Unexpected record in root hints at line {}", number + 1),
+            }
+        }
+
+        hints.servers.retain(|server| !server.ipv4.is_empty() || !server.ipv6.is_empty());
+        if hints.servers.is_empty() {
+            bail!("Root hints do not hold any address");
+        }
+        Ok(hints)
+    }
+
+    /// Merge the response to a priming query (RFC 8109) into the hints: the NS records of the
+    /// root zone in the answer give the root servers, and their addresses in the additional
+    /// section replace the ones of the hints. The addresses of a family missing from the
+    /// response are taken from the hints.
+    pub fn with_priming_response(&self, response: &DnsPacket) -> Result<RootHints, SimpleError> {
+        let mut primed = RootHints { servers: Vec::new() };
+        for record in &response.answers {
+            if let DnsRecord::NS { domain, host, .. } = record {
+                if normalize_name(domain).is_empty() {
+                    primed.server_mut(&normalize_name(host));
+                }
+            }
+        }
+        for record in &response.resources {
+            match record {
+                DnsRecord::A { domain, addr, .. } => {
+                    if let Some(server) = primed.server(&normalize_name(domain)) {
+                        server.ipv4.push(*addr);
+                    }
+                }
+                DnsRecord::AAAA { domain, addr, .. } => {
+                    if let Some(server) = primed.server(&normalize_name(domain)) {
+                        server.ipv6.push(*addr);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for server in primed.servers.iter_mut() {
+            if let Some(hint) = self.servers.iter().find(|hint| hint.name == server.name) {
+                if server.ipv4.is_empty() {
+                    server.ipv4 = hint.ipv4.clone();
+                }
+                if server.ipv6.is_empty() {
+                    server.ipv6 = hint.ipv6.clone();
+                }
+            }
+        }
+        primed.servers.retain(|server| !server.ipv4.is_empty() || !server.ipv6.is_empty());
+        if primed.servers.is_empty() {
+            bail!("Priming response does not hold any root server address");
+        }
+        Ok(primed)
+    }
+
+    /// TTL of the NS records of the root zone in the response to a priming query: the root
+    /// servers are primed again once it expires (RFC 8109 §4.1)
+    pub fn priming_ttl(response: &DnsPacket) -> Option<u32> {
+        response
+            .answers
+            .iter()
+            .filter(|record| matches!(record, DnsRecord::NS { domain, .. } if normalize_name(domain).is_empty()))
+            .map(|record| record.ttl())
+            .min()
+    }
+
+    /// Every address of the root servers
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        self.servers
+            .iter()
+            .flat_map(|server| {
+                server.ipv4.iter().map(|addr| IpAddr::V4(*addr))
+                    .chain(server.ipv6.iter().map(|addr| IpAddr::V6(*addr)))
+            })
+            .collect()
+    }
+
+    fn server(&mut self, name: &str) -> Option<&mut RootServer> {
+        self.servers.iter_mut().find(|server| server.name == name)
+    }
+
+    /// Return the server named `name`, adding it if it is not known yet
+    fn server_mut(&mut self, name: &str) -> &mut RootServer {
+        match self.servers.iter().position(|server| server.name == name) {
+            Some(index) => &mut self.servers[index],
+            None => {
+                self.servers.push(RootServer {
+                    name: name.to_string(),
+                    ipv4: Vec::new(),
+                    ipv6: Vec::new(),
+                });
+                self.servers.last_mut().unwrap()
+            }
+        }
+    }
+}
+
+/// Lowercase a name and drop its trailing dot, as names are stored in the rest of the crate
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ns(host: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::NS { domain: String::new(), host: host.to_string(), ttl }
+    }
+
+    #[test]
+    fn builtin_hints() {
+        let hints = RootHints::builtin();
+        assert_eq!(hints.servers.len(), 13);
+        assert!(hints.servers.iter().all(|server| server.ipv4.len() == 1 && server.ipv6.len() == 1));
+        assert_eq!(hints.servers[0].name, "a.root-servers.net");
+        assert_eq!(hints.servers[0].ipv4, vec![Ipv4Addr::new(198, 41, 0, 4)]);
+    }
+
+    #[test]
+    fn parse_optional_ttl_and_class() {
+        let hints = RootHints::parse(
+            ". 3600000 IN NS in.\n\
+             . NS 1.example.\n\
+             in. 3600000 A 192.0.2.1\n\
+             1.example. IN AAAA 2001:db8::1\n",
+        )
+        .unwrap();
+        // Names looking like a TTL or a class are not mistaken for one
+        assert_eq!(hints.servers[0].name, "in");
+        assert_eq!(hints.servers[0].ipv4, vec![Ipv4Addr::new(192, 0, 2, 1)]);
+        assert_eq!(hints.servers[1].name, "1.example");
+        assert_eq!(hints.servers[1].ipv6, vec!["2001:db8::1".parse::<Ipv6Addr>().unwrap()]);
+    }
+
+    #[test]
+    fn parse_rejects_other_layouts() {
+        assert!(RootHints::parse("a.root-servers.net. A 3600000 198.41.0.4").is_err());
+        assert!(RootHints::parse("a.root-servers.net. 3600000 CH A 198.41.0.4").is_err());
+        assert!(RootHints::parse("a.root-servers.net. 3600000 A 198.41.0.4 extra").is_err());
+        assert!(RootHints::parse("a.root-servers.net. 3600000 A").is_err());
+    }
+
+    #[test]
+    fn priming_response_is_merged() {
+        let hints = RootHints::parse(
+            ". NS a.root-servers.net.\n\
+             . NS b.root-servers.net.\n\
+             a.root-servers.net. A 192.0.2.1\n\
+             a.root-servers.net. AAAA 2001:db8::1\n\
+             b.root-servers.net. A 192.0.2.2\n",
+        )
+        .unwrap();
+        let mut response = DnsPacket::new();
+        response.answers.push(ns("a.root-servers.net", 518400));
+        response.answers.push(ns("c.root-servers.net", 86400));
+        response.resources.push(DnsRecord::A { domain: "a.root-servers.net".to_string(), addr: Ipv4Addr::new(192, 0, 2, 10), ttl: 518400 });
+        response.resources.push(DnsRecord::A { domain: "c.root-servers.net".to_string(), addr: Ipv4Addr::new(192, 0, 2, 3), ttl: 518400 });
+
+        let primed = hints.with_priming_response(&response).unwrap();
+        assert_eq!(primed.servers.len(), 2);
+        // The primed IPv4 address replaces the hint, the IPv6 one comes from the hints
+        assert_eq!(primed.servers[0].ipv4, vec![Ipv4Addr::new(192, 0, 2, 10)]);
+        assert_eq!(primed.servers[0].ipv6, vec!["2001:db8::1".parse::<Ipv6Addr>().unwrap()]);
+        assert_eq!(primed.servers[1].name, "c.root-servers.net");
+        assert_eq!(RootHints::priming_ttl(&response), Some(86400));
+
+        assert!(hints.with_priming_response(&DnsPacket::new()).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! Handling of the queries received by the server
+
+use std::net::UdpSocket;
+use simple_error::SimpleError;
+use crate::{BytePacketBuffer, DnsPacket, DnsRecord, EdnsOption, RecordType, Resolver, ResultCode, EDE_STALE_ANSWER, EDNS_MESSAGE_SIZE, UDP_MESSAGE_SIZE};
+
+/// Handle query received on the socket, resolving it with `resolver`
+pub fn handle_query(socket: &UdpSocket, resolver: &Resolver) -> Result<(), SimpleError> {
+    // Read a packet. Block until one is received
+    let mut req_buffer = BytePacketBuffer::new();
+
+    // Write the data into the buffer, and keep track of the source
+    // in order to send our reply later on
+    let (_, src_addr) = socket.recv_from(&mut req_buffer.buf)
+        .map_err(|e| SimpleError::with("Did not receive the data", e))?;
+    
+    // Parse the raw bytes into a "DnsPacket"
+    let mut request = DnsPacket::from_buffer(&mut req_buffer)?;
+
+    // A client sending an OPT record gets one back, and a response as large as both sides
+    // can take. DNSSEC records are only sent to the clients setting the DO bit.
+    let client_size = match request.edns() {
+        Some(DnsRecord::OPT { packet_len, .. }) => Some((*packet_len as usize).clamp(UDP_MESSAGE_SIZE, EDNS_MESSAGE_SIZE)),
+        _ => None,
+    };
+    let dnssec_ok = request.dnssec_ok();
+
+    // Create and initialize the response packet
+    let mut res_packet = DnsPacket::new();
+    res_packet.header.id = request.header.id;
+    res_packet.header.recursion_desired = true;
+    res_packet.header.recursion_available = true;
+    res_packet.header.response = true;
+    res_packet.header.checking_disabled = request.header.checking_disabled;
+    let mut extended_errors = Vec::new();
+
+    // In the normal case, one question is present
+    if let Some(question) = request.questions.pop() {
+        println!("Received query: {:?}", question);
+
+        // Query is forwarded to the target server. If query fails, 'SERVFAIL' response
+        // code is set to indicate it to the client. Otherwise question and response records are
+        // copied into our response packet
+        if let Ok(mut result) = resolver.recursive_lookup_with_cd(&question.name, question.qtype, request.header.checking_disabled) {
+            res_packet.header.rescode = result.header.rescode;
+            // The AD bit is only set for clients showing they understand it (RFC 6840 §5.8)
+            res_packet.header.authed_data = result.header.authed_data && (dnssec_ok || request.header.authed_data);
+            // Answers served from expired data say so (RFC 8767 §7)
+            if result.extended_errors().contains(&EDE_STALE_ANSWER) {
+                extended_errors.push(EdnsOption::extended_error(EDE_STALE_ANSWER, ""));
+            }
+            let qtype = question.qtype;
+            let keep = |record: &DnsRecord| match record.record_type() {
+                RecordType::OPT => false,
+                rtype @ (RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3) => dnssec_ok || rtype == qtype,
+                _ => true,
+            };
+            result.answers.retain(keep);
+            result.authorities.retain(keep);
+            result.resources.retain(keep);
+            res_packet.questions.push(question);
+            for rec in result.answers {
+                println!("Answer: {:?}", rec);
+                res_packet.answers.push(rec);
+            }
+            for rec in result.authorities {
+                println!("Authorities: {:?}", rec);
+                res_packet.authorities.push(rec);
+            }
+            for rec in result.resources {
+                println!("Resources: {:?}", rec);
+                res_packet.resources.push(rec);
+            }
+        } else {
+            res_packet.header.rescode = ResultCode::SERVFAIL;
+        }
+    } else {
+        // No question, indicate that the sender made something wrong
+        res_packet.header.rescode = ResultCode::FORMERR;
+    }
+
+    if client_size.is_some() {
+        res_packet.set_edns(EDNS_MESSAGE_SIZE as u16, dnssec_ok, extended_errors);
+    }
+
+    // Encode the response and send it off
+    let res_buffer = encode_response(&mut res_packet, client_size.unwrap_or(UDP_MESSAGE_SIZE))?;
+    socket.send_to(&res_buffer.buf[0..res_buffer.pos], src_addr)
+        .map_err(|e| SimpleError::with("Error sending response packet to user", e))?;
+    Ok(())
+}
+
+/// Encode a response in at most `size` bytes. A response too large for the client is sent
+/// truncated, with the header, the question and the OPT record only, so the client retries
+/// over TCP (RFC 2181 §9).
+fn encode_response(packet: &mut DnsPacket, size: usize) -> Result<BytePacketBuffer, SimpleError> {
+    let mut buffer = BytePacketBuffer::with_size(size);
+    if packet.write(&mut buffer).is_ok() {
+        return Ok(buffer);
+    }
+    packet.header.truncated_message = true;
+    packet.answers.clear();
+    packet.authorities.clear();
+    packet.resources.retain(|record| record.record_type() == RecordType::OPT);
+    let mut buffer = BytePacketBuffer::with_size(size);
+    packet.write(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DnsQuestions;
+    use std::net::Ipv4Addr;
+
+    fn response(answers: u8) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.questions.push(DnsQuestions::new("example.com".to_string(), RecordType::A));
+        for i in 0..answers {
+            packet.answers.push(DnsRecord::A { domain: "example.com".to_string(), addr: Ipv4Addr::new(192, 0, 2, i), ttl: 300 });
+        }
+        packet
+    }
+
+    #[test]
+    fn response_fitting_the_buffer_is_sent_whole() {
+        let mut buffer = encode_response(&mut response(10), UDP_MESSAGE_SIZE).unwrap();
+        buffer.pos = 0;
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert!(!packet.header.truncated_message);
+        assert_eq!(packet.answers.len(), 10);
+    }
+
+    #[test]
+    fn oversized_response_is_truncated() {
+        let mut packet = response(40);
+        packet.set_edns(EDNS_MESSAGE_SIZE as u16, false, Vec::new());
+        let mut buffer = encode_response(&mut packet, UDP_MESSAGE_SIZE).unwrap();
+        buffer.pos = 0;
+        let packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert!(packet.header.truncated_message);
+        assert_eq!(packet.questions.len(), 1);
+        assert!(packet.answers.is_empty());
+        assert!(packet.edns().is_some());
+    }
+}
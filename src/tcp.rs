@@ -0,0 +1,28 @@
+//! Framing of DNS messages over stream transports: each message is prefixed with its
+//! length on two bytes (RFC 1035 §4.2.2, RFC 7766 §8)
+
+use std::io::{Read, Write};
+use simple_error::SimpleError;
+use crate::BytePacketBuffer;
+
+/// Write a message with its length prefix, in a single write so that both go out in the same segment
+pub fn write_message<W: Write>(stream: &mut W, message: &[u8]) -> Result<(), SimpleError> {
+    if message.len() > u16::MAX as usize {
+        bail!("Message of {} bytes is too large for TCP", message.len());
+    }
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).map_err(|e| SimpleError::with("Error sending message", e))?;
+    stream.flush().map_err(|e| SimpleError::with("Error sending message", e))
+}
+
+/// Read a length prefixed message into a buffer of its exact size
+pub fn read_message<R: Read>(stream: &mut R) -> Result<BytePacketBuffer, SimpleError> {
+    let mut prefix = [0u8; 2];
+    stream.read_exact(&mut prefix).map_err(|e| SimpleError::with("Error receiving message length", e))?;
+    let len = u16::from_be_bytes(prefix) as usize;
+    let mut buffer = BytePacketBuffer::with_size(len);
+    stream.read_exact(&mut buffer.buf).map_err(|e| SimpleError::with("Error receiving message", e))?;
+    Ok(buffer)
+}